@@ -0,0 +1,511 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Drivers the fans are controlled through. A backend finds its fans and
+//! knows which of their files take the speed and switch between the daemon
+//! and the firmware
+
+use crate::config::Config;
+use crate::config::FanTemplate;
+use crate::curve::FanLimits;
+use crate::sysfs;
+use glob::glob;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// hwmon devices, searched for the driver of each backend
+const HWMON_GLOB: &str = "/sys/class/hwmon/hwmon*";
+
+/// ACPI device of the SMC behind the T2 chip
+const T2_DEVICE: &str = "APP0001";
+
+pub trait Backend: Sync {
+    /// Short name for messages
+    fn name(&self) -> &'static str;
+
+    /// Name in `fan_backends`
+    fn id(&self) -> &'static str;
+
+    /// Whether the backend is only used when no other one finds fans, unless
+    /// it is picked in `fan_backends`
+    fn fallback(&self) -> bool {
+        false
+    }
+
+    /// Every fan of this backend, as the path its files are named after,
+    /// like `.../fan1` for `fan1_input`
+    fn fan_paths(&self, config: &Config) -> Vec<PathBuf>;
+
+    /// Speed range the hardware allows
+    fn limits(&self, path: &Path) -> io::Result<FanLimits>;
+
+    /// Contents of the label file of the fan, if it has one
+    fn label(&self, path: &Path) -> Option<String>;
+
+    /// File the speed is written to
+    fn output(&self, path: &Path) -> PathBuf;
+
+    /// File the speed is read back from
+    fn input(&self, path: &Path) -> PathBuf;
+
+    /// Whether `input` reads back in the units written to `output`, so the
+    /// two can be compared
+    fn reads_back_output(&self) -> bool {
+        true
+    }
+
+    /// Hands the fan to the daemon, or back to the firmware
+    fn set_manual(&self, path: &Path, manual: bool) -> io::Result<()>;
+
+    /// File telling whether the daemon controls the fan, if there is one
+    fn mode(&self, path: &Path) -> Option<PathBuf>;
+
+    /// Whether `mode`, read from the `mode` file, means the daemon controls
+    /// the fan
+    fn is_manual(&self, mode: &str) -> bool;
+
+    /// Index of the fan in its device, 1 for `fan1`
+    fn index(&self, path: &Path) -> u32 {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| {
+                name.trim_start_matches(|c: char| !c.is_ascii_digit())
+                    .parse()
+                    .ok()
+            })
+            .unwrap_or(0)
+    }
+}
+
+/// Every backend, in the order their fans are listed
+pub const BACKENDS: &[&dyn Backend] = &[&T2, &APPLESMC, &Pwm];
+
+/// Ids of every backend, for `fan_backends`
+pub fn ids() -> Vec<&'static str> {
+    BACKENDS.iter().map(|backend| backend.id()).collect()
+}
+
+/// The SMC of T2 Macs, found as the `APP0001` ACPI device
+pub const T2: AppleSmc = AppleSmc { t2: true };
+
+/// The SMC of older Macs, found as the `applesmc.768` platform device. Its
+/// files are the same as on T2 Macs
+pub const APPLESMC: AppleSmc = AppleSmc { t2: false };
+
+/// Fans of the `applesmc` driver, with `fan*_input`, `fan*_min`, `fan*_max`,
+/// `fan*_output` and `fan*_manual` files
+pub struct AppleSmc {
+    /// Whether this takes the fans behind a T2 chip, or the others
+    t2: bool,
+}
+
+impl AppleSmc {
+    /// Whether the fan at `path` belongs to this variant
+    fn owns(&self, path: &Path) -> bool {
+        let device = fs::canonicalize(path.parent().unwrap_or(path)).unwrap_or_default();
+        device.to_string_lossy().contains(T2_DEVICE) == self.t2
+    }
+
+    /// The `fan*_input` files of every `applesmc` hwmon device
+    fn inputs(&self) -> Vec<PathBuf> {
+        let mut inputs = Vec::new();
+        for device in hwmon_devices("applesmc") {
+            // applesmc keeps its files on the platform device, not the hwmon one
+            for dir in [device.join("device"), device] {
+                let found = glob_paths(&format!("{}/fan*_input", dir.display()));
+                if !found.is_empty() {
+                    inputs.extend(found);
+                    break;
+                }
+            }
+        }
+        inputs
+    }
+}
+
+impl Backend for AppleSmc {
+    fn name(&self) -> &'static str {
+        if self.t2 {
+            "T2 SMC"
+        } else {
+            "applesmc"
+        }
+    }
+
+    fn id(&self) -> &'static str {
+        if self.t2 {
+            "t2"
+        } else {
+            "applesmc"
+        }
+    }
+
+    /// Fans are left out unless they report their speed range. `fan_glob`
+    /// replaces the hwmon search when it is set
+    fn fan_paths(&self, config: &Config) -> Vec<PathBuf> {
+        let inputs = match &config.fan_glob {
+            Some(pattern) => glob_paths(&sysfs::path(pattern)),
+            None => self.inputs(),
+        };
+        inputs
+            .iter()
+            .filter_map(|input| {
+                let path = PathBuf::from(input.to_str()?.strip_suffix("_input")?);
+                let complete = fan_file(&path, "_min").exists() && fan_file(&path, "_max").exists();
+                (complete && self.owns(&path)).then_some(path)
+            })
+            .collect()
+    }
+
+    fn limits(&self, path: &Path) -> io::Result<FanLimits> {
+        let read = |suffix: &str| {
+            fs::read_to_string(fan_file(path, suffix))?
+                .trim()
+                .parse::<u32>()
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+        };
+        Ok(FanLimits {
+            min_speed: read("_min")?,
+            max_speed: read("_max")?,
+        })
+    }
+
+    fn label(&self, path: &Path) -> Option<String> {
+        fs::read_to_string(fan_file(path, "_label"))
+            .ok()
+            .map(|label| label.trim().to_string())
+    }
+
+    fn output(&self, path: &Path) -> PathBuf {
+        fan_file(path, "_output")
+    }
+
+    fn input(&self, path: &Path) -> PathBuf {
+        fan_file(path, "_input")
+    }
+
+    fn set_manual(&self, path: &Path, manual: bool) -> io::Result<()> {
+        fs::write(fan_file(path, "_manual"), if manual { "1" } else { "0" })
+    }
+
+    fn mode(&self, path: &Path) -> Option<PathBuf> {
+        Some(fan_file(path, "_manual"))
+    }
+
+    fn is_manual(&self, mode: &str) -> bool {
+        mode.trim() != "0"
+    }
+}
+
+/// Standard hwmon fans of any other driver, driven through `pwm*` with a
+/// duty cycle from 0 to 255 and switched to manual with `pwm*_enable`
+pub struct Pwm;
+
+/// `pwm*_enable` value that hands the fan to the firmware or driver, when
+/// the one from before the daemon took it is not known
+const PWM_AUTO: &str = "2";
+
+/// `pwm*_enable` value that lets the daemon set the duty cycle
+const PWM_MANUAL: &str = "1";
+
+/// `pwm*_enable` of every fan from before the daemon took it, written back
+/// when the fan is handed back
+static PWM_MODES: Mutex<Vec<(PathBuf, String)>> = Mutex::new(Vec::new());
+
+/// Top of the `pwm*` duty cycle
+const PWM_MAX: u32 = 255;
+
+impl Backend for Pwm {
+    fn name(&self) -> &'static str {
+        "hwmon PWM"
+    }
+
+    fn id(&self) -> &'static str {
+        "pwm"
+    }
+
+    fn fallback(&self) -> bool {
+        true
+    }
+
+    /// Every `pwm*` with an `_enable` file, leaving out the SMC
+    fn fan_paths(&self, _config: &Config) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for device in glob_paths(&sysfs::path(HWMON_GLOB)) {
+            if fs::read_to_string(device.join("name")).is_ok_and(|name| name.trim() == "applesmc") {
+                continue;
+            }
+            for enable in glob_paths(&format!("{}/pwm[0-9]*_enable", device.display())) {
+                if let Some(path) = enable
+                    .to_str()
+                    .and_then(|path| path.strip_suffix("_enable"))
+                {
+                    paths.push(PathBuf::from(path));
+                }
+            }
+        }
+        paths.sort();
+        paths
+    }
+
+    fn limits(&self, _path: &Path) -> io::Result<FanLimits> {
+        Ok(FanLimits {
+            min_speed: 0,
+            max_speed: PWM_MAX,
+        })
+    }
+
+    fn label(&self, path: &Path) -> Option<String> {
+        let label = path.with_file_name(format!("fan{}_label", self.index(path)));
+        fs::read_to_string(label)
+            .ok()
+            .map(|label| label.trim().to_string())
+    }
+
+    fn output(&self, path: &Path) -> PathBuf {
+        path.to_path_buf()
+    }
+
+    /// The `fan*_input` of the same number, in RPM
+    fn input(&self, path: &Path) -> PathBuf {
+        path.with_file_name(format!("fan{}_input", self.index(path)))
+    }
+
+    fn reads_back_output(&self) -> bool {
+        false
+    }
+
+    /// Keeps the mode the fan was in when taking it, and writes it back when
+    /// handing it back
+    fn set_manual(&self, path: &Path, manual: bool) -> io::Result<()> {
+        let enable = fan_file(path, "_enable");
+        let mut modes = PWM_MODES.lock().unwrap_or_else(|error| error.into_inner());
+        let saved = modes.iter().position(|(saved, _)| saved == path);
+        if manual {
+            let mode = fs::read_to_string(&enable)?.trim().to_string();
+            // Already manual when a daemon before this one did not hand it back
+            if saved.is_none() && mode != PWM_MANUAL {
+                modes.push((path.to_path_buf(), mode));
+            }
+            return fs::write(enable, PWM_MANUAL);
+        }
+        let mode = saved.map(|saved| modes.remove(saved).1);
+        fs::write(enable, mode.as_deref().unwrap_or(PWM_AUTO))
+    }
+
+    fn mode(&self, path: &Path) -> Option<PathBuf> {
+        Some(fan_file(path, "_enable"))
+    }
+
+    fn is_manual(&self, mode: &str) -> bool {
+        mode.trim() == PWM_MANUAL
+    }
+}
+
+/// Fans described by `fan_template`, for drivers the other backends do not
+/// know
+pub struct Template {
+    template: FanTemplate,
+}
+
+/// Every `Template` handed out, fans keep a `'static` reference to theirs
+static TEMPLATES: Mutex<Vec<&'static Template>> = Mutex::new(Vec::new());
+
+/// The backend for `template`, made once for every template the config ever
+/// had so reloading the same one does not leak
+pub fn template(template: &FanTemplate) -> &'static Template {
+    let mut templates = TEMPLATES.lock().unwrap_or_else(|error| error.into_inner());
+    if let Some(found) = templates.iter().find(|found| found.template == *template) {
+        return found;
+    }
+    let made: &'static Template = Box::leak(Box::new(Template {
+        template: template.clone(),
+    }));
+    templates.push(made);
+    made
+}
+
+impl Template {
+    /// The `{n}` of the fan whose input is `path`
+    fn number<'a>(&self, path: &'a Path) -> Option<&'a str> {
+        let name = self.template.input.rsplit('/').next()?;
+        let (prefix, suffix) = name.split_once("{n}")?;
+        let number = path
+            .file_name()?
+            .to_str()?
+            .strip_prefix(prefix)?
+            .strip_suffix(suffix)?;
+        (!number.is_empty() && number.bytes().all(|byte| byte.is_ascii_digit())).then_some(number)
+    }
+
+    /// The file named by `name` next to the input of the fan at `path`
+    fn file(&self, path: &Path, name: &str) -> PathBuf {
+        path.with_file_name(name.replace("{n}", self.number(path).unwrap_or_default()))
+    }
+}
+
+impl Backend for Template {
+    fn name(&self) -> &'static str {
+        "fan_template"
+    }
+
+    fn id(&self) -> &'static str {
+        "template"
+    }
+
+    /// The input files matching the template, with a speed range
+    fn fan_paths(&self, _config: &Config) -> Vec<PathBuf> {
+        let pattern = sysfs::path(&self.template.input.replace("{n}", "[0-9]*"));
+        let mut paths: Vec<PathBuf> = glob_paths(&pattern)
+            .into_iter()
+            .filter(|path| {
+                self.number(path).is_some()
+                    && self.file(path, &self.template.min).exists()
+                    && self.file(path, &self.template.max).exists()
+            })
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn limits(&self, path: &Path) -> io::Result<FanLimits> {
+        let read = |name: &str| {
+            fs::read_to_string(self.file(path, name))?
+                .trim()
+                .parse::<u32>()
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+        };
+        Ok(FanLimits {
+            min_speed: read(&self.template.min)?,
+            max_speed: read(&self.template.max)?,
+        })
+    }
+
+    fn label(&self, path: &Path) -> Option<String> {
+        let label = self.template.label.as_ref()?;
+        fs::read_to_string(self.file(path, label))
+            .ok()
+            .map(|label| label.trim().to_string())
+    }
+
+    fn output(&self, path: &Path) -> PathBuf {
+        self.file(path, &self.template.output)
+    }
+
+    fn input(&self, path: &Path) -> PathBuf {
+        path.to_path_buf()
+    }
+
+    /// Without a `manual` file the driver is taken to always follow the
+    /// speed written
+    fn set_manual(&self, path: &Path, manual: bool) -> io::Result<()> {
+        match &self.template.manual {
+            Some(file) => fs::write(self.file(path, file), if manual { "1" } else { "0" }),
+            None => Ok(()),
+        }
+    }
+
+    fn mode(&self, path: &Path) -> Option<PathBuf> {
+        Some(self.file(path, self.template.manual.as_ref()?))
+    }
+
+    fn is_manual(&self, mode: &str) -> bool {
+        mode.trim() != "0"
+    }
+
+    fn index(&self, path: &Path) -> u32 {
+        self.number(path)
+            .and_then(|number| number.parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+/// The hwmon devices whose `name` is `driver`, in order
+fn hwmon_devices(driver: &str) -> Vec<PathBuf> {
+    let mut devices = glob_paths(&sysfs::path(HWMON_GLOB));
+    devices.sort();
+    devices.retain(|device| {
+        fs::read_to_string(device.join("name")).is_ok_and(|name| name.trim() == driver)
+    });
+    devices
+}
+
+/// Paths matching `pattern`, none when it is not a valid glob
+fn glob_paths(pattern: &str) -> Vec<PathBuf> {
+    glob(pattern)
+        .map(|paths| paths.filter_map(Result::ok).collect())
+        .unwrap_or_default()
+}
+
+/// The file of the fan at `path` with `suffix`, like `fan1_output`
+fn fan_file(path: &Path, suffix: &str) -> PathBuf {
+    let mut file = path.as_os_str().to_owned();
+    file.push(suffix);
+    PathBuf::from(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `pwm1` in a fresh directory under the temporary directory, with
+    /// `pwm1_enable` at `mode`
+    fn pwm(name: &str, mode: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("t2macd-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("pwm1_enable"), mode).unwrap();
+        dir.join("pwm1")
+    }
+
+    fn mode(path: &Path) -> String {
+        fs::read_to_string(fan_file(path, "_enable")).unwrap()
+    }
+
+    #[test]
+    fn pwm_is_handed_back_in_its_old_mode() {
+        let path = pwm("pwm-mode", "5\n");
+        Pwm.set_manual(&path, true).unwrap();
+        assert_eq!(mode(&path), PWM_MANUAL);
+        assert!(Pwm.is_manual(&mode(&path)));
+        // Taking it again, as after resume, keeps the mode from before
+        Pwm.set_manual(&path, true).unwrap();
+        Pwm.set_manual(&path, false).unwrap();
+        assert_eq!(mode(&path), "5");
+        assert!(!Pwm.is_manual(&mode(&path)));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn pwm_left_in_manual_is_handed_back_to_auto() {
+        let path = pwm("pwm-manual", PWM_MANUAL);
+        Pwm.set_manual(&path, true).unwrap();
+        Pwm.set_manual(&path, false).unwrap();
+        assert_eq!(mode(&path), PWM_AUTO);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn pwm_reads_back_its_tachometer() {
+        let path = Path::new("/sys/class/hwmon/hwmon3/pwm2");
+        assert_eq!(
+            Pwm.input(path),
+            Path::new("/sys/class/hwmon/hwmon3/fan2_input")
+        );
+    }
+}
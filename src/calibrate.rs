@@ -0,0 +1,149 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! `t2macd calibrate` runs the fans at a few fixed speeds under load, and
+//! builds a curve from the temperatures they settle at.
+
+use crate::config;
+use crate::config::Config;
+use crate::config::Format;
+use crate::curve::CurvePoint;
+use crate::curve::FanCurve;
+use crate::fan::Fan;
+use crate::sensor::get_current_temp;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Fan speeds that are tested, as a percentage of the fans range
+const STEPS: [u32; 4] = [25, 50, 75, 100];
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// Number of samples that must stay within `STABLE_RANGE` degrees
+const STABLE_SAMPLES: usize = 6;
+const STABLE_RANGE: f64 = 0.5;
+const STEP_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Keeps every CPU busy until dropped
+struct Load {
+    stop: Arc<AtomicBool>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl Load {
+    fn start() -> Load {
+        let stop = Arc::new(AtomicBool::new(false));
+        let count = thread::available_parallelism().map_or(1, |count| count.get());
+        let threads = (0..count)
+            .map(|_| {
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    let mut x: u64 = 1;
+                    while !stop.load(Ordering::Relaxed) {
+                        x = std::hint::black_box(
+                            x.wrapping_mul(6364136223846793005).wrapping_add(1),
+                        );
+                    }
+                })
+            })
+            .collect();
+        Load { stop, threads }
+    }
+}
+
+impl Drop for Load {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Waits until the temperature stops changing, returning where it settled
+fn steady_temp(config: &Config) -> Result<f64, io::Error> {
+    let start = Instant::now();
+    let mut samples = Vec::new();
+    loop {
+        thread::sleep(SAMPLE_INTERVAL);
+        samples.push(get_current_temp(config)?);
+        if samples.len() > STABLE_SAMPLES {
+            samples.remove(0);
+        }
+        let max = samples.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let min = samples.iter().cloned().fold(f64::INFINITY, f64::min);
+        let stable = samples.len() == STABLE_SAMPLES && max - min <= STABLE_RANGE;
+        if stable || start.elapsed() > STEP_TIMEOUT {
+            return Ok(samples.iter().sum::<f64>() / samples.len() as f64);
+        }
+    }
+}
+
+/// Measures the steady state temperature at every step, and writes a
+/// `FanCurve::POINTS` curve spanning them to the config at `path`. The
+/// coolest temperature reached gets the lowest speed tested and the hottest
+/// gets full speed
+pub fn calibrate(fans: &[Fan], config: &Config, path: &Path) -> Result<(), io::Error> {
+    println!(
+        "Calibrating, this loads every CPU and takes up to {} minutes",
+        STEPS.len() * 5
+    );
+    let load = Load::start();
+    let mut temps = Vec::new();
+    for percent in STEPS {
+        for fan in fans {
+            fan.set_speed(fan.limits.speed_at(percent as f64 / 100.0))?;
+        }
+        let temp = steady_temp(config)?;
+        println!("  {:>3}% fan speed settles at {:.1}°C", percent, temp);
+        temps.push(temp);
+    }
+    drop(load);
+    for fan in fans {
+        fan.release_control()?;
+    }
+
+    temps.sort_by(|a, b| a.total_cmp(b));
+    let unit = config.temperature_unit;
+    let mut points: Vec<CurvePoint> = Vec::new();
+    for (temp, percent) in temps.iter().zip(STEPS) {
+        let temp = unit.celsius_to(*temp).round() as u32;
+        // Points must strictly increase, merge steps that settled together
+        match points.last_mut() {
+            Some(last) if last.temp >= temp => last.percent = percent,
+            _ => points.push(CurvePoint { temp, percent }),
+        }
+    }
+
+    // Only replace the curve, so the rest of the file stays as the user wrote it
+    let mut file: serde_json::Value = match fs::read_to_string(path) {
+        Ok(file) => Format::of(path)
+            .parse(&file)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?,
+        Err(..) => serde_json::to_value(config).unwrap(),
+    };
+    file["fan_curve"] = serde_json::to_value(FanCurve::POINTS).unwrap();
+    file["curve_points"] = serde_json::to_value(points).unwrap();
+    file["preset"] = serde_json::Value::Null;
+    config::write_value(path, file)?;
+    println!("Wrote the calibrated curve to {}", path.display());
+    Ok(())
+}
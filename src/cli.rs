@@ -0,0 +1,193 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::curve::Preset;
+use crate::curve::Speed;
+use crate::manual;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::Level;
+
+/// Environment variable that sets the config path when `--config` is not given
+pub const CONFIG_ENV: &str = "T2MACD_CONFIG";
+
+pub enum Command {
+    /// Run the fan control daemon
+    Daemon,
+    /// Print the temperature of every sensor
+    Status,
+    /// List every temperature input and whether it is read
+    Sensors,
+    /// Print the temperature to speed table of every fan
+    CurveShow { plot: bool },
+    /// Build a curve from the thermals measured at fixed fan speeds
+    Calibrate,
+    /// Step every fan through its range and check that it follows
+    TestFans,
+    /// Rewrite the legacy JSON config as TOML
+    ConfigConvert,
+    /// Report every problem in the config and exit
+    ConfigValidate,
+    /// Print the default config with every option explained
+    PrintDefaultConfig,
+    /// Write the default config
+    Init,
+    /// Write the systemd unit, and enable and start it with `enable`
+    Install { enable: bool },
+    /// List the profiles, or switch the running daemon to one
+    Profile { name: Option<String> },
+    /// Pin a fan, or every fan when `fan` is `None`, at `speed` for
+    /// `duration`. No speed hands it back to the daemon
+    Set {
+        fan: Option<String>,
+        speed: Option<Speed>,
+        duration: Duration,
+    },
+    /// Hand every fan to the firmware, keeping the daemon away from them
+    /// for `duration` or until `resume`
+    Auto { duration: Option<Duration> },
+    /// Let the daemon take the fans back after `auto`
+    Resume,
+    /// Run every fan at full speed for `duration`
+    Boost { duration: Duration },
+}
+
+pub struct Args {
+    pub command: Command,
+    pub preset: Option<Preset>,
+    pub config: Option<PathBuf>,
+    /// Run with the default config if the config can not be parsed
+    pub force_defaults: bool,
+    /// Scenario to run against simulated hardware instead of sysfs
+    pub simulate: Option<PathBuf>,
+    /// Log the speeds the fans would be set to instead of writing them
+    pub dry_run: bool,
+    /// Set the fans once and exit instead of running the daemon
+    pub once: bool,
+    /// Least severe messages that are logged
+    pub log_level: Level,
+}
+
+impl Args {
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Args, String> {
+        let mut args = args;
+        let mut words = Vec::new();
+        let mut preset = None;
+        let mut plot = false;
+        let mut enable = false;
+        let mut force_defaults = false;
+        let mut dry_run = false;
+        let mut once = false;
+        let mut log_level = Level::INFO;
+        let mut print_default_config = false;
+        let mut simulate = None;
+        let mut duration = None;
+        let mut config = std::env::var_os(CONFIG_ENV).map(PathBuf::from);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--preset" => {
+                    let name = args.next().unwrap_or_default();
+                    match Preset::from_name(&name) {
+                        Some(found) => preset = Some(found),
+                        None => {
+                            return Err(format!(
+                                "Unknown preset \"{}\", expected quiet, balanced or performance",
+                                name
+                            ))
+                        }
+                    }
+                }
+                "--plot" => plot = true,
+                "--enable" => enable = true,
+                "--force-defaults" => force_defaults = true,
+                "--dry-run" => dry_run = true,
+                "--once" => once = true,
+                "--print-default-config" => print_default_config = true,
+                "--log-level" => {
+                    let name = args.next().unwrap_or_default();
+                    match Level::from_str(&name) {
+                        Ok(level) => log_level = level,
+                        Err(..) => {
+                            return Err(format!(
+                            "Unknown log level \"{}\", expected error, warn, info, debug or trace",
+                            name
+                        ))
+                        }
+                    }
+                }
+                "--simulate" => match args.next() {
+                    Some(path) => simulate = Some(PathBuf::from(path)),
+                    None => return Err(String::from("--simulate needs a scenario file")),
+                },
+                "--for" => {
+                    duration = Some(manual::parse_duration(&args.next().unwrap_or_default())?)
+                }
+                "--config" => match args.next() {
+                    Some(path) => config = Some(PathBuf::from(path)),
+                    None => return Err(String::from("--config needs a path")),
+                },
+                _ if arg.starts_with('-') => return Err(format!("Unknown argument \"{}\"", arg)),
+                _ => words.push(arg),
+            }
+        }
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        let command = match words.as_slice() {
+            [] if print_default_config => Command::PrintDefaultConfig,
+            [] => Command::Daemon,
+            ["status"] => Command::Status,
+            ["sensors"] => Command::Sensors,
+            ["curve", "show"] => Command::CurveShow { plot },
+            ["calibrate"] => Command::Calibrate,
+            ["test-fans"] => Command::TestFans,
+            ["init"] => Command::Init,
+            ["install"] => Command::Install { enable },
+            ["config", "convert"] => Command::ConfigConvert,
+            ["config", "validate"] => Command::ConfigValidate,
+            ["profile"] => Command::Profile { name: None },
+            ["profile", name] => Command::Profile {
+                name: Some(name.to_string()),
+            },
+            ["set", fan, speed] => Command::Set {
+                fan: (*fan != "all").then(|| fan.to_string()),
+                speed: match *speed {
+                    "auto" => None,
+                    speed => Some(Speed::parse(speed)?),
+                },
+                duration: duration.unwrap_or(manual::DEFAULT_DURATION),
+            },
+            ["auto"] => Command::Auto { duration },
+            ["resume"] => Command::Resume,
+            ["boost"] => Command::Boost {
+                duration: duration.unwrap_or(manual::DEFAULT_DURATION),
+            },
+            ["boost", time] => Command::Boost {
+                duration: manual::parse_duration(time)?,
+            },
+            _ => return Err(format!("Unknown command \"{}\"", words.join(" "))),
+        };
+        Ok(Args {
+            command,
+            preset,
+            config,
+            force_defaults,
+            simulate,
+            dry_run,
+            once,
+            log_level,
+        })
+    }
+}
@@ -0,0 +1,2867 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::backend;
+use crate::controller::DeadBandConfig;
+use crate::controller::NoiseCapConfig;
+use crate::controller::PidConfig;
+use crate::controller::PredictiveConfig;
+use crate::controller::ZeroRpmConfig;
+use crate::curve::CurvePoint;
+use crate::curve::FanCurve;
+use crate::curve::FanLimits;
+use crate::curve::Points;
+use crate::curve::Preset;
+use crate::curve::Speed;
+use crate::curve::SpeedCurve;
+use crate::curve::EXPRESSION_VARIABLES;
+use crate::expression::Expression;
+use crate::migrate;
+use crate::model;
+use crate::model::Model;
+use crate::sensor;
+use crate::sensor::Reading;
+use crate::sensor::SensorKind;
+use crate::sysfs;
+use serde::de;
+use serde::de::value::MapAccessDeserializer;
+use serde::de::DeserializeOwned;
+use serde::de::DeserializeSeed;
+use serde::de::MapAccess;
+use serde::de::SeqAccess;
+use serde::de::Visitor;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+pub const DEFAULT_PATH: &str = "/etc/t2macd/config.toml";
+/// Drop-in files applied over `DEFAULT_PATH`, in file name order
+pub const DROP_IN_GLOB: &str = "/etc/t2macd/conf.d/*.toml";
+/// Where the config lived before TOML support, still read when there is no
+/// config at `DEFAULT_PATH`
+pub const LEGACY_PATH: &str = "/etc/t2macd.json";
+/// Environment variables starting with this override config keys, like
+/// `T2MACD_MIN_TEMP=70`. Nested keys are separated by two underscores, as in
+/// `T2MACD_PID__TARGET_TEMP`
+pub const ENV_PREFIX: &str = "T2MACD_";
+/// Layer written by `t2macd profile` to switch profiles until the next boot
+pub const PROFILE_PATH: &str = "/run/t2macd/profile.toml";
+
+/// `PROFILE_PATH`, under the generated tree with `--simulate`
+pub fn profile_path() -> PathBuf {
+    PathBuf::from(sysfs::path(PROFILE_PATH))
+}
+
+/// Returns the config file to use when none was given. A legacy config is
+/// converted to `DEFAULT_PATH` first, keeping the original as a backup
+pub fn default_path() -> PathBuf {
+    let path = PathBuf::from(DEFAULT_PATH);
+    if !path.exists() && Path::new(LEGACY_PATH).exists() {
+        let backup = format!("{}.v1.bak", LEGACY_PATH);
+        let converted = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| convert(Path::new(LEGACY_PATH), &path))
+            .and_then(|_| fs::rename(LEGACY_PATH, &backup));
+        match converted {
+            Ok(..) => info!(
+                "Converted legacy config {} to {}, the original is at {}",
+                LEGACY_PATH, DEFAULT_PATH, backup
+            ),
+            Err(error) => {
+                warn!(
+                    "Using legacy config {}, converting it to {} failed: {}",
+                    LEGACY_PATH, DEFAULT_PATH, error
+                );
+                return PathBuf::from(LEGACY_PATH);
+            }
+        }
+    }
+    path
+}
+
+/// Returns every file the config is layered from, the base config followed by
+/// the drop-ins and the user config in `$XDG_CONFIG_HOME`
+pub fn layers() -> Vec<PathBuf> {
+    let mut paths = vec![default_path()];
+    let mut drop_ins: Vec<PathBuf> = glob::glob(DROP_IN_GLOB)
+        .unwrap()
+        .filter_map(Result::ok)
+        .collect();
+    drop_ins.sort();
+    paths.append(&mut drop_ins);
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    if let Some(config_home) = config_home {
+        let user_config = config_home.join("t2macd/config.toml");
+        if user_config.exists() {
+            paths.push(user_config);
+        }
+    }
+    paths
+}
+
+/// Adds the files pulled in by `include` to `paths`, right after the file
+/// that includes them. Patterns are relative to the including file
+pub fn with_includes(paths: &[PathBuf]) -> Vec<PathBuf> {
+    /// `chain` holds the files that included this one, to catch loops
+    fn add(path: &Path, chain: &mut Vec<PathBuf>, paths: &mut Vec<PathBuf>) {
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if chain.contains(&canonical) {
+            warn!("Not including {} in itself", path.display());
+            return;
+        }
+        paths.push(path.to_path_buf());
+        let layer = fs::read_to_string(path)
+            .ok()
+            .and_then(|text| Format::of(path).parse::<serde_json::Value>(&text).ok());
+        let patterns = match layer.as_ref().and_then(|layer| layer.get("include")) {
+            Some(serde_json::Value::Array(patterns)) => patterns.clone(),
+            _ => return,
+        };
+        chain.push(canonical);
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        for pattern in patterns.iter().filter_map(|pattern| pattern.as_str()) {
+            let pattern = dir.join(pattern);
+            let mut included: Vec<PathBuf> = match glob::glob(&pattern.to_string_lossy()) {
+                Ok(included) => included.filter_map(Result::ok).collect(),
+                Err(error) => {
+                    warn!("Bad include pattern in {}: {}", path.display(), error);
+                    continue;
+                }
+            };
+            included.sort();
+            for included in included {
+                add(&included, chain, paths);
+            }
+        }
+        chain.pop();
+    }
+    let mut expanded = Vec::new();
+    for path in paths {
+        add(path, &mut Vec::new(), &mut expanded);
+    }
+    expanded
+}
+
+/// Writes the default config to `path`, with comments when it is TOML
+pub fn init(path: &Path) -> Result<(), io::Error> {
+    if path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", path.display()),
+        ));
+    }
+    let text = match Format::of(path) {
+        Format::TOML => commented_default(),
+        format => format
+            .serialize(&Config::for_model(model::detect()))
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?,
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, text)
+}
+
+/// Description written above each option by `commented_default`, keyed by
+/// its path
+const OPTION_DOCS: &[(&str, &str)] = &[
+    (
+        "version",
+        "Version of the config format, older configs are upgraded automatically",
+    ),
+    (
+        "control_mode",
+        "How fan speeds are picked: CURVE, PID, PREDICTIVE or SCRIPT",
+    ),
+    (
+        "fan_curve",
+        "Shape of the curve: LINEAR, POINTS, EXPONENTIAL, SIGMOID or EXPRESSION",
+    ),
+    (
+        "preset",
+        "Built-in curve for this Mac model, used instead of fan_curve: quiet, balanced or performance",
+    ),
+    (
+        "min_temp",
+        "Temperature the fans start speeding up at, below it they run at their minimum",
+    ),
+    ("max_temp", "Temperature the fans reach their maximum speed at"),
+    (
+        "curve_points",
+        "Breakpoints of a POINTS curve, e.g. [{ temp = 60, percent = 0 }, { temp = 90, percent = 100 }]",
+    ),
+    (
+        "curve_exponent",
+        "Power of an EXPONENTIAL curve, higher values stay quiet for longer",
+    ),
+    (
+        "sigmoid_midpoint",
+        "Temperature a SIGMOID curve reaches half speed at",
+    ),
+    (
+        "sigmoid_steepness",
+        "How sharply a SIGMOID curve rises around its midpoint",
+    ),
+    (
+        "hysteresis",
+        "Degrees the temperature has to fall before the fans slow down",
+    ),
+    (
+        "curve_blend_time",
+        "Seconds to fade between curves when the config changes while running",
+    ),
+    (
+        "temperature_unit",
+        "Unit of every temperature in this file: CELSIUS or FAHRENHEIT",
+    ),
+    (
+        "include",
+        "Files applied over this one, as glob patterns relative to it, e.g. [\"curves/*.toml\"]",
+    ),
+    (
+        "profile",
+        "Named sets of options applied over the rest of the config, see the example at the end",
+    ),
+    (
+        "sensor_failure",
+        "What the fans do while the sensors can not be read: HOLD the last speed, run at a FIXED speed or at MAX",
+    ),
+    (
+        "sensor_failure_speed",
+        "Speed in RPM or percent used by sensor_failure = \"FIXED\"",
+    ),
+    (
+        "poll_interval_ms",
+        "Milliseconds between reading the sensors and updating the fans",
+    ),
+    (
+        "ramp_up_time",
+        "Seconds a fan takes to speed up over its whole range, 0 changes speed at once",
+    ),
+    (
+        "ramp_down_time",
+        "Seconds a fan takes to slow down over its whole range",
+    ),
+    (
+        "transition_time",
+        "Seconds a fan takes to move to a new target speed, 0 jumps straight to it",
+    ),
+    (
+        "fan_backends",
+        "Drivers the fans are controlled through: t2, applesmc or pwm. Empty uses the Apple SMC, or pwm when it has no fans",
+    ),
+    (
+        "fan_wait_time",
+        "Seconds to wait at startup for the fan drivers to load before giving up, 0 waits forever",
+    ),
+    (
+        "stall_timeout",
+        "Seconds an update may take before the daemon counts itself as hung, hands the fans back and aborts, 0 never does",
+    ),
+    (
+        "exit_action",
+        "What the fans are left at when the daemon stops or crashes: AUTO hands them back to the firmware, FIXED runs them at exit_speed and MAX at full speed",
+    ),
+    (
+        "exit_speed",
+        "Speed in RPM or percent used by exit_action = \"FIXED\"",
+    ),
+    (
+        "strict_config",
+        "Refuse to start when the config can not be parsed, instead of running with the defaults",
+    ),
+    ("pid", "Used by the PID control mode"),
+    ("pid.target_temp", "Temperature to hold the fans at"),
+    (
+        "pid.kp",
+        "Gain on the difference to target_temp, in share of the speed range per degree",
+    ),
+    ("pid.ki", "Gain on the accumulated difference"),
+    ("pid.kd", "Gain on how fast the difference changes"),
+    (
+        "predictive",
+        "Used by the PREDICTIVE control mode, which feeds the curve with where the temperature is heading",
+    ),
+    (
+        "predictive.lookahead",
+        "How many seconds ahead to extrapolate a rising temperature",
+    ),
+    (
+        "predictive.history",
+        "Number of samples the rate of change is measured over",
+    ),
+    (
+        "predictive.max_fall_rate",
+        "Fastest the predicted temperature may fall, in degrees per second",
+    ),
+    (
+        "sensors",
+        "Temperature sensors, and the settings for single ones keyed by their hwmon name",
+    ),
+    (
+        "sensors.include",
+        "hwmon devices to read, by the contents of their name file or by kind: cpu, gpu, nvme, drive, smc or battery. Empty reads every device",
+    ),
+    ("sensors.exclude", "hwmon devices to skip even when included"),
+    (
+        "sensors.smc",
+        "applesmc temperatures to read, by key like TA0P or by label like ambient",
+    ),
+    (
+        "sensors.thermal_zones",
+        "Thermal zones to read alongside hwmon, by their type like x86_pkg_temp or acpitz",
+    ),
+    (
+        "sensors.aggregation",
+        "How readings are combined: MAX takes the hottest, AVG the average and WEIGHTED the average by weight",
+    ),
+    (
+        "sensors.input",
+        "Sensor that alone feeds the curve, like a virtual one, instead of combining them all",
+    ),
+    (
+        "fan_failure.time",
+        "Seconds a fan can report 0 RPM while told to spin before it counts as failed and the others run at full speed. A command = \"...\" here is run when that happens",
+    ),
+    (
+        "sensors.history_time",
+        "Seconds of readings kept for the trend and maximum shown by t2macd status",
+    ),
+    (
+        "sensors.failed_reads",
+        "Reads in a row a sensor can fail while its last reading is used, before it is left out",
+    ),
+    (
+        "sensors.median_window",
+        "Number of readings each sensor is the median of, to drop single bogus samples. 1 turns it off",
+    ),
+    (
+        "sensors.smoothing",
+        "Share of the previous reading kept when smoothing each sensor, from 0 (off) to below 1",
+    ),
+    (
+        "sensors.core_aggregation",
+        "How the package and core temperatures of the CPU are combined into its reading: MAX or AVG",
+    ),
+];
+
+/// Options without a default, written commented out by `commented_default`.
+/// The first part goes with the top level keys, the second after the tables
+const OPTIONAL_EXAMPLES: (&str, &str) = (
+    r#"# Use a built-in curve instead of fan_curve: quiet, balanced or performance
+# preset = "balanced"
+
+# Speed in RPM as a function of t, min and max, used by the EXPRESSION curve
+# curve_expression = "min + (max - min) * ((t - 60) / 40) ^ 2"
+
+# Lowest speed any fan runs at while spinning, in RPM or percent of its range,
+# even when the hardware allows slower
+# min_speed = 2000
+
+# Rhai script used by the SCRIPT control mode
+# script = "/etc/t2macd/fans.rhai"
+
+# Profile applied at startup, switch with `t2macd profile <name>`
+# default_profile = "quiet"
+
+# Fan files to use instead of the fans of the applesmc hwmon device
+# fan_glob = "/sys/devices/*/*/*/*/APP0001:00/fan*_input"
+"#,
+    r#"
+# Sensors worked out from the others, usable like any other sensor
+# [sensors.virtual]
+# chassis = "max(coretemp, amdgpu) + 0.5 * nvme"
+
+# [sensors.coretemp]
+# Above this every fan runs at full speed, regardless of the curve
+# critical_temp = 100
+# Share of the curve input taken from this sensor with WEIGHTED aggregation
+# weight = 1.0
+# Degrees added to every reading, for sensors that read high or low
+# offset = -3.0
+# Read the sensor this often instead of every poll_interval_ms, for sensors
+# that change slowly like the battery
+# poll_interval_ms = 10000
+
+# Stop the fans below idle_temp, and only spin them up again at spin_up_temp
+# [zero_rpm]
+# idle_temp = 45
+# spin_up_temp = 55
+
+# Cap the fans at max_speed to keep them quiet until critical_temp is reached
+# [noise_cap]
+# max_speed = "60%"
+# critical_temp = 95
+
+# Files of the fans of a driver t2macd does not know, instead of its own
+# search. {n} is the fan number, the files other than input sit next to it
+# [fan_template]
+# input = "/sys/devices/platform/myfans/hwmon/hwmon*/fan{n}_rpm"
+# output = "fan{n}_target"
+# min = "fan{n}_min"
+# max = "fan{n}_max"
+# manual = "fan{n}_manual"
+# label = "fan{n}_label"
+
+# Tick every calm_interval_ms instead of poll_interval_ms while every sensor
+# is below calm_temp and changing by less than calm_trend degrees a minute
+# [adaptive_polling]
+# calm_interval_ms = 5000
+# calm_temp = 60
+# calm_trend = 3.0
+
+# Skip writing a new speed while it is within speed RPM of the last one and
+# the temperature has moved less than temp degrees
+# [dead_band]
+# speed = 100
+# temp = 2
+
+# Options applied over the rest of the config when this profile is selected
+# [profile.quiet]
+# min_temp = 85
+# max_temp = 105
+# noise_cap = { max_speed = "50%", critical_temp = 95 }
+
+# Overrides for the fan with this index (fan1 is 1) or label, like "left"
+# [[fans]]
+# index = 1
+# fan_curve = "POINTS"
+# min_temp = 70
+# max_temp = 95
+# min_speed = "20%"
+# noise_cap = 4000
+# Percentage of the speed range added to (or removed from) the curve output
+# speed_offset = -5.0
+# RPM added to (or removed from) the curve output
+# offset_rpm = -200
+# Factor the curve output is multiplied by, below 1.0 for a louder fan
+# scale = 0.9
+# Leave this fan to the firmware instead of controlling it
+# enabled = false
+# Follow only these sensors, by name or kind, instead of every sensor
+# sensors = ["drive"]
+"#,
+);
+
+/// Returns the default config as TOML, with a comment describing every option
+pub fn commented_default() -> String {
+    let defaults = toml::to_string_pretty(&Config::for_model(model::detect())).unwrap();
+    let mut text = String::new();
+    let mut table = String::new();
+    let mut examples = Some(OPTIONAL_EXAMPLES.0);
+    for line in defaults.lines() {
+        let path = match line.strip_prefix('[') {
+            Some(header) => {
+                if let Some(examples) = examples.take() {
+                    text.push_str(examples);
+                    text.push('\n');
+                }
+                table = header.trim_end_matches(']').to_string();
+                table.clone()
+            }
+            None => match line.split_once(" = ") {
+                Some((key, _)) if table.is_empty() => key.to_string(),
+                Some((key, _)) => format!("{}.{}", table, key),
+                None => String::new(),
+            },
+        };
+        // Left out so the [[fans]] example can be uncommented
+        if path == "fans" {
+            continue;
+        }
+        if let Some((_, doc)) = OPTION_DOCS.iter().find(|(key, _)| *key == path) {
+            text.push_str(&format!("# {}\n", doc));
+        }
+        text.push_str(line);
+        text.push('\n');
+    }
+    text.push_str(examples.unwrap_or_default());
+    text.push_str(OPTIONAL_EXAMPLES.1);
+    text
+}
+
+/// Why a config could not be loaded
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(String),
+    Invalid(Vec<String>),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, error) => {
+                write!(f, "Could not read {}: {}", path.display(), error)
+            }
+            ConfigError::Parse(error) => write!(f, "Could not parse config: {}", error),
+            ConfigError::Invalid(errors) => write!(f, "Invalid config:\n  {}", errors.join("\n  ")),
+        }
+    }
+}
+
+/// Reads `strict_config` from the files of a config that could not be
+/// parsed as a whole, falling back to strict
+fn strict_config(paths: &[PathBuf]) -> bool {
+    paths
+        .iter()
+        .rev()
+        .find_map(|path| {
+            let text = fs::read_to_string(path).ok()?;
+            let layer: serde_json::Value = Format::of(path).parse(&text).ok()?;
+            layer.get("strict_config")?.as_bool()
+        })
+        .unwrap_or(true)
+}
+
+/// Applies the `ENV_PREFIX` environment variables over `tree`. Values are
+/// read as TOML, falling back to a plain string, and `T2MACD_PROFILE` is
+/// short for `T2MACD_DEFAULT_PROFILE`
+fn apply_env(tree: &mut serde_json::Value) {
+    apply_vars(tree, std::env::vars());
+}
+
+/// `apply_env` with `vars` in place of the environment
+fn apply_vars(tree: &mut serde_json::Value, vars: impl Iterator<Item = (String, String)>) {
+    let options = serde_json::to_value(Config::default().in_unit(unit_of(tree))).unwrap();
+    for (name, value) in vars {
+        let key = match name.strip_prefix(ENV_PREFIX) {
+            Some(key) if name != crate::cli::CONFIG_ENV => key.to_lowercase(),
+            _ => continue,
+        };
+        let key = if key == "profile" {
+            String::from("default_profile")
+        } else {
+            key
+        };
+        let segments: Vec<&str> = key.split("__").collect();
+        if options.get(segments[0]).is_none() {
+            warn!(
+                "Ignoring {}, there is no option called {}",
+                name, segments[0]
+            );
+            continue;
+        }
+        let value = toml::from_str::<toml::Table>(&format!("value = {}", value))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .and_then(|value| serde_json::to_value(value).ok())
+            .unwrap_or(serde_json::Value::String(value));
+        // A nested key on a table the files leave out starts from its defaults
+        if segments.len() > 1 && tree.get(segments[0]).is_none() {
+            merge(
+                tree,
+                serde_json::json!({ segments[0]: options[segments[0]] }),
+            );
+        }
+        let layer = segments.iter().rev().fold(
+            value,
+            |value, segment| serde_json::json!({ *segment: value }),
+        );
+        merge(tree, layer);
+    }
+}
+
+/// The `temperature_unit` set in `tree`, Celsius when it is not set
+fn unit_of(tree: &serde_json::Value) -> TemperatureUnit {
+    tree.get("temperature_unit")
+        .and_then(|unit| serde_json::from_value(unit.clone()).ok())
+        .unwrap_or(default_temperature_unit())
+}
+
+/// Applies the profile named by `default_profile` over the rest of `tree`
+fn select_profile(tree: &mut serde_json::Value) -> Result<(), String> {
+    let name = match tree.get("default_profile").and_then(|name| name.as_str()) {
+        Some(name) => name.to_string(),
+        None => return Ok(()),
+    };
+    let mut profile = match tree.pointer(&format!("/profile/{}", name)) {
+        Some(profile) => profile.clone(),
+        None => {
+            return Err(format!(
+                "default_profile: there is no profile called {}",
+                name
+            ))
+        }
+    };
+    if let Some(profile) = profile.as_object_mut() {
+        profile.remove("profile");
+        profile.remove("default_profile");
+    }
+    merge(tree, profile);
+    Ok(())
+}
+
+/// Makes `name` the active profile until the next boot, by writing it to
+/// `PROFILE_PATH`
+pub fn switch_profile(name: &str) -> Result<(), io::Error> {
+    let path = profile_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_value(&path, serde_json::json!({ "default_profile": name }))
+}
+
+/// A key the config does not know about, taken from the error serde gives
+/// for `deny_unknown_fields`
+struct UnknownKey {
+    /// Map keys and sequence indices leading to the key, ending with it
+    segments: Vec<String>,
+    /// Known key closest to the unknown one
+    suggestion: Option<String>,
+}
+
+impl UnknownKey {
+    fn from_error(error: &serde_path_to_error::Error<serde_json::Error>) -> Option<UnknownKey> {
+        // Looks like: unknown field `max_tmep`, expected one of `min_temp`, `max_temp`
+        let message = error.inner().to_string();
+        let rest = message.strip_prefix("unknown field `")?;
+        let (key, expected) = rest.split_once('`')?;
+        let mut segments: Vec<String> = error
+            .path()
+            .iter()
+            .filter_map(|segment| match segment {
+                serde_path_to_error::Segment::Seq { index } => Some(index.to_string()),
+                serde_path_to_error::Segment::Map { key } => Some(key.clone()),
+                _ => None,
+            })
+            .collect();
+        if segments.last().map(String::as_str) != Some(key) {
+            segments.push(key.to_string());
+        }
+        let suggestion = expected
+            .split('`')
+            .skip(1)
+            .step_by(2)
+            .map(|known| (edit_distance(key, known), known))
+            .min()
+            .filter(|(distance, _)| *distance <= (key.len() / 3).max(1))
+            .map(|(_, known)| known.to_string());
+        Some(UnknownKey {
+            segments,
+            suggestion,
+        })
+    }
+
+    /// The full key, as in `fans[0].min_speed`
+    fn name(&self) -> String {
+        let mut name = String::new();
+        for segment in &self.segments {
+            match segment.parse::<usize>() {
+                Ok(index) => name.push_str(&format!("[{}]", index)),
+                Err(..) if name.is_empty() => name.push_str(segment),
+                Err(..) => name.push_str(&format!(".{}", segment)),
+            }
+        }
+        name
+    }
+
+    fn pointer(&self) -> String {
+        self.segments
+            .iter()
+            .map(|segment| format!("/{}", segment))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "unknown key {}", self.name())?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, ", did you mean {}?", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of single character edits between `a` and `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Turns a deserialization error into a message naming the key it is about
+fn describe(error: serde_path_to_error::Error<serde_json::Error>) -> String {
+    match UnknownKey::from_error(&error) {
+        Some(unknown) => unknown.to_string(),
+        None => error.to_string(),
+    }
+}
+
+/// Finds the first line of a config file that sets `key`, counting from 1
+fn line_of(text: &str, key: &str) -> Option<usize> {
+    let quoted = format!("\"{}\"", key);
+    text.lines()
+        .position(|line| {
+            let line = line
+                .trim_start()
+                .trim_start_matches('[')
+                .trim_start_matches("- ");
+            [key, quoted.as_str()].iter().any(|name| {
+                line.strip_prefix(name).is_some_and(|rest| {
+                    let rest = rest.trim_start();
+                    rest.starts_with('=') || rest.starts_with(':') || rest.starts_with(']')
+                })
+            })
+        })
+        .map(|line| line + 1)
+}
+
+/// Recursively merges `layer` into `tree`. Tables are merged key by key, any
+/// other value in `layer` replaces the one in `tree`
+fn merge(tree: &mut serde_json::Value, layer: serde_json::Value) {
+    match (tree, layer) {
+        (serde_json::Value::Object(tree), serde_json::Value::Object(layer)) => {
+            for (key, value) in layer {
+                match tree.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        tree.insert(key, value);
+                    }
+                }
+            }
+        }
+        (tree, layer) => *tree = layer,
+    }
+}
+
+/// File format of a config, picked from the file extension
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    JSON,
+    TOML,
+    /// Only available with the "yaml" feature
+    YAML,
+}
+
+impl Format {
+    pub fn of(path: &Path) -> Format {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Format::JSON,
+            Some("yaml" | "yml") => Format::YAML,
+            _ => Format::TOML,
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(&self, text: &str) -> Result<T, String> {
+        match self {
+            Format::JSON => serde_json::from_str(text).map_err(|error| error.to_string()),
+            Format::TOML => toml::from_str(text).map_err(|error| error.to_string()),
+            #[cfg(feature = "yaml")]
+            Format::YAML => serde_yaml::from_str(text).map_err(|error| error.to_string()),
+            #[cfg(not(feature = "yaml"))]
+            Format::YAML => Err(Format::no_yaml()),
+        }
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<String, String> {
+        match self {
+            Format::JSON => serde_json::to_string_pretty(value).map_err(|error| error.to_string()),
+            Format::TOML => toml::to_string_pretty(value).map_err(|error| error.to_string()),
+            #[cfg(feature = "yaml")]
+            Format::YAML => serde_yaml::to_string(value).map_err(|error| error.to_string()),
+            #[cfg(not(feature = "yaml"))]
+            Format::YAML => Err(Format::no_yaml()),
+        }
+    }
+
+    #[cfg(not(feature = "yaml"))]
+    fn no_yaml() -> String {
+        String::from("t2macd was built without the \"yaml\" feature")
+    }
+}
+
+/// Writes a config tree to `path` in the format matching its extension. TOML
+/// has no null, so null values are left out
+pub fn write_value(path: &Path, mut value: serde_json::Value) -> Result<(), io::Error> {
+    fn strip_nulls(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => {
+                map.retain(|_, value| !value.is_null());
+                map.values_mut().for_each(strip_nulls);
+            }
+            serde_json::Value::Array(array) => array.iter_mut().for_each(strip_nulls),
+            _ => {}
+        }
+    }
+    let format = Format::of(path);
+    if format == Format::TOML {
+        strip_nulls(&mut value);
+    }
+    let text = format
+        .serialize(&value)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    fs::write(path, text)
+}
+
+/// Rewrites the JSON config at `from` as TOML at `to`, upgraded to the
+/// current version
+pub fn convert(from: &Path, to: &Path) -> Result<(), io::Error> {
+    if to.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", to.display()),
+        ));
+    }
+    let mut value: serde_json::Value = Format::JSON
+        .parse(&fs::read_to_string(from)?)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    migrate::upgrade(&mut value)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    if let Some(config) = value.as_object_mut() {
+        config.insert(String::from("version"), migrate::CONFIG_VERSION.into());
+    }
+    write_value(to, value)
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ControlMode {
+    CURVE,
+    PID,
+    PREDICTIVE,
+    SCRIPT,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum TemperatureUnit {
+    CELSIUS,
+    FAHRENHEIT,
+}
+
+impl TemperatureUnit {
+    /// Converts a temperature in this unit to Celsius
+    pub fn celsius_from(&self, temp: f64) -> f64 {
+        match self {
+            TemperatureUnit::CELSIUS => temp,
+            TemperatureUnit::FAHRENHEIT => (temp - 32.0) * 5.0 / 9.0,
+        }
+    }
+
+    /// Converts a temperature in Celsius to this unit
+    pub fn celsius_to(&self, temp: f64) -> f64 {
+        match self {
+            TemperatureUnit::CELSIUS => temp,
+            TemperatureUnit::FAHRENHEIT => temp * 9.0 / 5.0 + 32.0,
+        }
+    }
+
+    /// Scale of a temperature difference relative to Celsius
+    pub fn delta_scale(&self) -> f64 {
+        match self {
+            TemperatureUnit::CELSIUS => 1.0,
+            TemperatureUnit::FAHRENHEIT => 5.0 / 9.0,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            TemperatureUnit::CELSIUS => "°C",
+            TemperatureUnit::FAHRENHEIT => "°F",
+        }
+    }
+
+    /// Formats `temp`, in Celsius, in this unit like `92.5°C`
+    pub fn format(&self, temp: f64) -> String {
+        format!("{:.1}{}", self.celsius_to(temp), self.symbol())
+    }
+}
+
+/// Which hwmon devices are read as temperature sensors, and the settings for
+/// each of them
+#[derive(Serialize, Clone)]
+pub struct SensorsConfig {
+    /// Devices to read, matched against their hwmon `name` or their kind
+    /// (`cpu`, `gpu`, `nvme`, `drive`, `smc` or `battery`). When empty every
+    /// device is read
+    pub include: Vec<String>,
+    /// Devices to skip even when included
+    pub exclude: Vec<String>,
+    /// applesmc temperatures read as sensors of their own, by SMC key or by
+    /// label
+    pub smc: Vec<String>,
+    /// Thermal zones read as sensors, by their `type`
+    pub thermal_zones: Vec<String>,
+    /// How the readings are combined into the temperature fed to the curve
+    pub aggregation: Aggregation,
+    /// Sensor fed to the curve on its own, instead of the readings combined
+    /// by `aggregation`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<String>,
+    /// Sensors calculated from the others, by name
+    #[serde(rename = "virtual", skip_serializing_if = "BTreeMap::is_empty")]
+    pub virtual_sensors: BTreeMap<String, String>,
+    /// Seconds of readings kept to work out trends
+    pub history_time: u64,
+    /// Reads in a row a sensor can fail before it is left out, its last
+    /// reading is used until then
+    pub failed_reads: u32,
+    /// Number of readings of each sensor the median is taken of
+    pub median_window: usize,
+    /// Weight of the previous value in the moving average every reading is
+    /// smoothed with before it reaches the curve, 0 turns smoothing off
+    pub smoothing: f64,
+    /// How every temperature of the CPU is combined into its reading
+    pub core_aggregation: Aggregation,
+    /// Settings for single sensors, keyed by their hwmon `name`
+    #[serde(flatten)]
+    pub devices: BTreeMap<String, SensorConfig>,
+}
+
+/// Keys of `[sensors]` that are settings rather than devices
+const SENSORS_KEYS: &[&str] = &[
+    "include",
+    "exclude",
+    "smc",
+    "thermal_zones",
+    "aggregation",
+    "input",
+    "virtual",
+    "history_time",
+    "failed_reads",
+    "median_window",
+    "smoothing",
+    "core_aggregation",
+];
+
+// Written by hand as `flatten` buffers the device tables, which loses the path
+// to a bad key in them. Every key that is not a setting is a device, and as a
+// device has to be a table, a mistyped setting is reported as an unknown key
+impl<'de> Deserialize<'de> for SensorsConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SensorsConfig, D::Error> {
+        deserializer.deserialize_map(SensorsVisitor)
+    }
+}
+
+struct SensorsVisitor;
+
+impl<'de> Visitor<'de> for SensorsVisitor {
+    type Value = SensorsConfig;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a table of sensor settings")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<SensorsConfig, A::Error> {
+        let mut sensors = SensorsConfig::default();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "include" => sensors.include = map.next_value()?,
+                "exclude" => sensors.exclude = map.next_value()?,
+                "smc" => sensors.smc = map.next_value()?,
+                "thermal_zones" => sensors.thermal_zones = map.next_value()?,
+                "aggregation" => sensors.aggregation = map.next_value()?,
+                "input" => sensors.input = map.next_value()?,
+                "virtual" => sensors.virtual_sensors = map.next_value()?,
+                "history_time" => sensors.history_time = map.next_value()?,
+                "failed_reads" => sensors.failed_reads = map.next_value()?,
+                "median_window" => sensors.median_window = map.next_value()?,
+                "smoothing" => sensors.smoothing = map.next_value()?,
+                "core_aggregation" => sensors.core_aggregation = map.next_value()?,
+                _ => {
+                    let device = map.next_value_seed(Device { key: &key })?;
+                    sensors.devices.insert(key, device);
+                }
+            }
+        }
+        Ok(sensors)
+    }
+}
+
+/// The table of a single device in `[sensors]`
+struct Device<'a> {
+    key: &'a str,
+}
+
+impl Device<'_> {
+    fn unknown<E: de::Error>(self) -> Result<SensorConfig, E> {
+        Err(E::unknown_field(self.key, SENSORS_KEYS))
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for Device<'_> {
+    type Value = SensorConfig;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<SensorConfig, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Device<'_> {
+    type Value = SensorConfig;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a table of settings for the {} sensor", self.key)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<SensorConfig, A::Error> {
+        SensorConfig::deserialize(MapAccessDeserializer::new(map))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, _: A) -> Result<SensorConfig, A::Error> {
+        self.unknown()
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<SensorConfig, E> {
+        self.unknown()
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<SensorConfig, E> {
+        self.unknown()
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<SensorConfig, E> {
+        self.unknown()
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<SensorConfig, E> {
+        self.unknown()
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<SensorConfig, E> {
+        self.unknown()
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<SensorConfig, E> {
+        self.unknown()
+    }
+}
+
+/// How sensor readings are combined into a single temperature
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    /// The hottest sensor
+    MAX,
+    /// The average of every sensor
+    AVG,
+    /// The average weighted by `weight`, sensors without one are left out
+    WEIGHTED,
+}
+
+fn default_aggregation() -> Aggregation {
+    Aggregation::MAX
+}
+
+fn default_history_time() -> u64 {
+    600
+}
+
+fn default_failed_reads() -> u32 {
+    3
+}
+
+fn default_median_window() -> usize {
+    1
+}
+
+fn default_include() -> Vec<String> {
+    vec![
+        String::from("cpu"),
+        String::from("gpu"),
+        String::from("nvme"),
+        String::from("battery"),
+    ]
+}
+
+impl Default for SensorsConfig {
+    fn default() -> SensorsConfig {
+        SensorsConfig {
+            include: default_include(),
+            exclude: Vec::new(),
+            smc: Vec::new(),
+            thermal_zones: Vec::new(),
+            aggregation: default_aggregation(),
+            input: None,
+            virtual_sensors: BTreeMap::new(),
+            history_time: default_history_time(),
+            failed_reads: default_failed_reads(),
+            median_window: default_median_window(),
+            smoothing: 0.0,
+            core_aggregation: default_aggregation(),
+            devices: BTreeMap::new(),
+        }
+    }
+}
+
+impl SensorsConfig {
+    /// Whether a hwmon device going by any of `names` is read
+    pub fn selects(&self, names: &[&str]) -> bool {
+        let listed = |list: &[String]| list.iter().any(|device| names.contains(&device.as_str()));
+        (self.include.is_empty() || listed(&self.include)) && !listed(&self.exclude)
+    }
+}
+
+/// What the fans do while the sensors can not be read
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SensorFailure {
+    /// Keep the last speed written
+    HOLD,
+    /// Run at `sensor_failure_speed`
+    FIXED,
+    /// Run at full speed
+    MAX,
+}
+
+/// What the fans are left at when the daemon stops or crashes
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ExitAction {
+    /// Hand the fans back to the firmware
+    AUTO,
+    /// Keep control and run them at `exit_speed`
+    FIXED,
+    /// Keep control and run them at full speed
+    MAX,
+}
+
+/// Files of the fans of a driver the built-in backends do not know. `input`
+/// is a glob of the file the speed is read from, with `{n}` for the fan
+/// number in its file name. The others are file names next to it, with the
+/// same `{n}`
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FanTemplate {
+    pub input: String,
+    pub output: String,
+    pub min: String,
+    pub max: String,
+    /// Takes `1` to hand the fan to the daemon and `0` to hand it back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manual: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// Ticks less often while every sensor is cool and steady
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct AdaptivePollingConfig {
+    /// Milliseconds between ticks while calm, instead of `poll_interval_ms`
+    pub calm_interval_ms: u64,
+    /// Hottest a sensor can be for it to count as calm
+    pub calm_temp: u32,
+    /// Fastest a sensor can change, in degrees per minute, for it to count
+    /// as calm
+    pub calm_trend: f64,
+}
+
+/// When a fan counts as failed, and what to run when it does
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FanFailureConfig {
+    /// Seconds a fan can report 0 RPM while told to spin
+    #[serde(default = "default_fan_failure_time")]
+    pub time: f64,
+    /// Run through `sh -c` when a fan fails, with its name in `T2MACD_FAN`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+}
+
+fn default_fan_failure_time() -> f64 {
+    10.0
+}
+
+impl Default for FanFailureConfig {
+    fn default() -> FanFailureConfig {
+        FanFailureConfig {
+            time: default_fan_failure_time(),
+            command: None,
+        }
+    }
+}
+
+/// Settings for a single temperature sensor, keyed by its name in `sensors`
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SensorConfig {
+    /// Above this every fan runs at full speed, regardless of the curve
+    #[serde(default)]
+    pub critical_temp: Option<u32>,
+    /// Share of the curve input taken from this sensor with
+    /// `Aggregation::WEIGHTED`
+    #[serde(default)]
+    pub weight: Option<f64>,
+    /// Degrees added to every reading, for sensors that read high or low
+    #[serde(default)]
+    pub offset: f64,
+    /// How often the sensor is read, when it changes slower than
+    /// `poll_interval_ms`. The last reading is used in between
+    #[serde(default)]
+    pub poll_interval_ms: Option<u64>,
+}
+
+/// Whether the `fan*_label` contents `label` are picked by `wanted`, either
+/// in full or by the first word, ignoring case. This lets `left` stand for
+/// the `Left side` fan
+pub fn label_matches(label: &str, wanted: &str) -> bool {
+    let label = label.trim();
+    label.eq_ignore_ascii_case(wanted)
+        || label
+            .split_whitespace()
+            .next()
+            .is_some_and(|word| word.eq_ignore_ascii_case(wanted))
+}
+
+/// Settings that replace the global ones for a single fan, matched by the
+/// hwmon index (`fan1` is 1) or its label, see `label_matches`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FanOverride {
+    #[serde(default)]
+    pub index: Option<u32>,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub fan_curve: Option<FanCurve>,
+    #[serde(default)]
+    pub min_temp: Option<u32>,
+    #[serde(default)]
+    pub max_temp: Option<u32>,
+    #[serde(default)]
+    pub min_speed: Option<Speed>,
+    /// Replaces `noise_cap.max_speed` for this fan
+    #[serde(default)]
+    pub noise_cap: Option<Speed>,
+    /// Percentage of the speed range added to (or with a negative value,
+    /// removed from) the curve output, to balance intake and exhaust fans
+    #[serde(default)]
+    pub speed_offset: f64,
+    /// RPM added to (or removed from) the curve output, after `speed_offset`
+    #[serde(default)]
+    pub offset_rpm: i32,
+    /// Factor the curve output is multiplied by before the offsets, to move
+    /// work from a louder fan to a quieter one
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    /// Whether the daemon controls this fan, or leaves it to the firmware
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Sensors, by name or kind, this fan follows instead of every sensor
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sensors: Vec<String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+impl FanOverride {
+    pub fn matches(&self, index: u32, label: Option<&str>) -> bool {
+        self.index == Some(index)
+            || matches!((&self.label, label), (Some(wanted), Some(label)) if label_matches(label, wanted))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Schema version the config was written for, see `migrate`
+    #[serde(default = "default_version")]
+    pub version: u32,
+    #[serde(default = "default_control_mode")]
+    pub control_mode: ControlMode,
+    pub fan_curve: FanCurve,
+    pub min_temp: u32,
+    pub max_temp: u32,
+    #[serde(default)]
+    pub curve_points: Vec<CurvePoint>,
+    #[serde(default = "default_curve_exponent")]
+    pub curve_exponent: f64,
+    #[serde(default = "default_sigmoid_midpoint")]
+    pub sigmoid_midpoint: f64,
+    #[serde(default = "default_sigmoid_steepness")]
+    pub sigmoid_steepness: f64,
+    #[serde(default)]
+    pub pid: PidConfig,
+    #[serde(default)]
+    pub hysteresis: u32,
+    /// Seconds to fade between curves when the curve changes at runtime
+    #[serde(default = "default_curve_blend_time")]
+    pub curve_blend_time: f64,
+    /// Overrides `fan_curve` with one of the built-in curves
+    #[serde(default)]
+    pub preset: Option<Preset>,
+    #[serde(default)]
+    pub zero_rpm: Option<ZeroRpmConfig>,
+    #[serde(default)]
+    pub predictive: PredictiveConfig,
+    #[serde(default)]
+    pub fans: Vec<FanOverride>,
+    /// Speed in RPM as a function of `t`, `min` and `max`, used by
+    /// `FanCurve::EXPRESSION`
+    #[serde(default)]
+    pub curve_expression: Option<String>,
+    /// Rhai script used by `ControlMode::SCRIPT`
+    #[serde(default)]
+    pub script: Option<PathBuf>,
+    #[serde(default)]
+    pub noise_cap: Option<NoiseCapConfig>,
+    /// Lowest speed any fan runs at while spinning, in RPM or percent of its
+    /// hardware range. `fans[].min_speed` overrides it for a single fan
+    #[serde(default)]
+    pub min_speed: Option<Speed>,
+    /// Unit the temperatures in this file are written in. Once loaded every
+    /// temperature is converted to Celsius, this only affects output
+    #[serde(default = "default_temperature_unit")]
+    pub temperature_unit: TemperatureUnit,
+    #[serde(default)]
+    pub sensors: SensorsConfig,
+    #[serde(default)]
+    pub dead_band: Option<DeadBandConfig>,
+    /// Refuse to start when the config can not be parsed, instead of running
+    /// with the defaults
+    #[serde(default = "default_strict_config")]
+    pub strict_config: bool,
+    /// Named sets of options applied over the rest of the config when
+    /// selected, like `[profile.quiet]`
+    #[serde(default)]
+    pub profile: BTreeMap<String, serde_json::Value>,
+    /// Files applied over this one, as glob patterns relative to it
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Profile applied at startup, unless another is picked with
+    /// `t2macd profile`
+    #[serde(default)]
+    pub default_profile: Option<String>,
+    #[serde(default = "default_sensor_failure")]
+    pub sensor_failure: SensorFailure,
+    /// Speed used by `SensorFailure::FIXED`
+    #[serde(default = "default_sensor_failure_speed")]
+    pub sensor_failure_speed: Speed,
+    /// Milliseconds between reading the sensors and updating the fans
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    #[serde(default)]
+    pub adaptive_polling: Option<AdaptivePollingConfig>,
+    /// Seconds a fan takes to speed up over its whole range, 0 jumps straight
+    /// to the new speed
+    #[serde(default)]
+    pub ramp_up_time: f64,
+    /// Seconds a fan takes to slow down over its whole range
+    #[serde(default)]
+    pub ramp_down_time: f64,
+    /// Seconds a fan takes to move to a new target speed, stepping towards
+    /// it on every tick. Changes within `dead_band.speed` are not spread out
+    #[serde(default)]
+    pub transition_time: f64,
+    #[serde(default = "default_exit_action")]
+    pub exit_action: ExitAction,
+    #[serde(default = "default_exit_speed")]
+    pub exit_speed: Speed,
+    /// Glob of the `fan*_input` files to use instead of the hwmon devices
+    /// of the fan drivers
+    #[serde(default)]
+    pub fan_glob: Option<String>,
+    /// Files of the fans to use instead of every built-in backend
+    #[serde(default)]
+    pub fan_template: Option<FanTemplate>,
+    #[serde(default)]
+    pub fan_failure: FanFailureConfig,
+    /// Backends the fans are looked for in, by `Backend::id`. Empty picks
+    /// them automatically
+    #[serde(default)]
+    pub fan_backends: Vec<String>,
+    /// Seconds to wait for the fans at startup before giving up, 0 waits
+    /// forever
+    #[serde(default = "default_fan_wait_time")]
+    pub fan_wait_time: u64,
+    /// Seconds a tick may take before the daemon counts itself as hung, hands
+    /// the fans back and aborts, 0 never does
+    #[serde(default = "default_stall_timeout")]
+    pub stall_timeout: u64,
+}
+
+fn default_sensor_failure() -> SensorFailure {
+    SensorFailure::MAX
+}
+
+fn default_fan_wait_time() -> u64 {
+    300
+}
+
+fn default_stall_timeout() -> u64 {
+    30
+}
+
+fn default_exit_action() -> ExitAction {
+    ExitAction::AUTO
+}
+
+fn default_exit_speed() -> Speed {
+    Speed::Percent(60.0)
+}
+
+fn default_sensor_failure_speed() -> Speed {
+    Speed::Percent(60.0)
+}
+
+fn default_poll_interval_ms() -> u64 {
+    1000
+}
+
+fn default_strict_config() -> bool {
+    true
+}
+
+fn default_version() -> u32 {
+    migrate::CONFIG_VERSION
+}
+
+fn default_curve_blend_time() -> f64 {
+    5.0
+}
+
+fn default_temperature_unit() -> TemperatureUnit {
+    TemperatureUnit::CELSIUS
+}
+
+fn default_control_mode() -> ControlMode {
+    ControlMode::CURVE
+}
+
+fn default_curve_exponent() -> f64 {
+    2.0
+}
+
+fn default_sigmoid_midpoint() -> f64 {
+    90.0
+}
+
+fn default_sigmoid_steepness() -> f64 {
+    0.5
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            version: default_version(),
+            control_mode: default_control_mode(),
+            fan_curve: FanCurve::LINEAR,
+            min_temp: 80,
+            max_temp: 100,
+            curve_points: Vec::new(),
+            curve_exponent: default_curve_exponent(),
+            sigmoid_midpoint: default_sigmoid_midpoint(),
+            sigmoid_steepness: default_sigmoid_steepness(),
+            pid: PidConfig::default(),
+            hysteresis: 0,
+            curve_blend_time: default_curve_blend_time(),
+            preset: None,
+            zero_rpm: None,
+            predictive: PredictiveConfig::default(),
+            fans: Vec::new(),
+            curve_expression: None,
+            script: None,
+            noise_cap: None,
+            min_speed: None,
+            temperature_unit: default_temperature_unit(),
+            sensors: SensorsConfig::default(),
+            dead_band: None,
+            strict_config: default_strict_config(),
+            profile: BTreeMap::new(),
+            include: Vec::new(),
+            default_profile: None,
+            sensor_failure: default_sensor_failure(),
+            sensor_failure_speed: default_sensor_failure_speed(),
+            poll_interval_ms: default_poll_interval_ms(),
+            adaptive_polling: None,
+            ramp_up_time: 0.0,
+            ramp_down_time: 0.0,
+            transition_time: 0.0,
+            exit_action: default_exit_action(),
+            exit_speed: default_exit_speed(),
+            fan_glob: None,
+            fan_template: None,
+            fan_failure: FanFailureConfig::default(),
+            fan_backends: Vec::new(),
+            fan_wait_time: default_fan_wait_time(),
+            stall_timeout: default_stall_timeout(),
+        }
+    }
+}
+
+impl Config {
+    /// The defaults, with the sensors and curve of `model` when it is known
+    pub fn for_model(model: Option<&Model>) -> Config {
+        let mut config = Config::default();
+        if let Some(model) = model {
+            config.sensors.include = model.include.iter().map(|kind| kind.to_string()).collect();
+            // As points rather than `preset`, which would win over any curve
+            // set on top of these defaults
+            config.fan_curve = FanCurve::POINTS;
+            config.curve_points = model.preset.points();
+            config.fans = model
+                .fans
+                .iter()
+                .map(|(label, sensors)| FanOverride {
+                    index: None,
+                    label: Some(label.to_string()),
+                    fan_curve: None,
+                    min_temp: None,
+                    max_temp: None,
+                    min_speed: None,
+                    noise_cap: None,
+                    speed_offset: 0.0,
+                    offset_rpm: 0,
+                    scale: default_scale(),
+                    enabled: default_enabled(),
+                    sensors: sensors.iter().map(|sensor| sensor.to_string()).collect(),
+                })
+                .collect();
+        }
+        config
+    }
+
+    /// Loads the config from `paths`, with keys in later files overriding
+    /// earlier ones
+    pub fn load(paths: &[PathBuf]) -> Result<Config, ConfigError> {
+        Config::load_over(serde_json::Value::Object(Default::default()), paths)
+    }
+
+    /// Like `load`, with the files applied over `tree`
+    fn load_over(tree: serde_json::Value, paths: &[PathBuf]) -> Result<Config, ConfigError> {
+        Config::from_tree(Config::layered(tree, paths)?)
+    }
+
+    /// `tree` with the files in `paths`, the environment and the selected
+    /// profile applied over it
+    fn layered(
+        mut tree: serde_json::Value,
+        paths: &[PathBuf],
+    ) -> Result<serde_json::Value, ConfigError> {
+        for path in paths {
+            let layer =
+                fs::read_to_string(path).map_err(|error| ConfigError::Io(path.clone(), error))?;
+            let mut layer = Format::of(path)
+                .parse::<serde_json::Value>(&layer)
+                .map_err(|error| ConfigError::Parse(format!("{}: {}", path.display(), error)))?;
+            let upgraded = migrate::upgrade(&mut layer).map_err(|error| {
+                ConfigError::Invalid(vec![format!("{}: {}", path.display(), error)])
+            })?;
+            if let Some(from) = upgraded {
+                match migrate::migrate_file(path, &layer, from) {
+                    Ok(backup) => info!(
+                        "Upgraded {} from config version {}, the original is at {}",
+                        path.display(),
+                        from,
+                        backup.display()
+                    ),
+                    Err(error) => warn!(
+                        "Failed to write the upgraded config to {}: {}",
+                        path.display(),
+                        error
+                    ),
+                }
+            }
+            merge(&mut tree, layer);
+        }
+        apply_env(&mut tree);
+        select_profile(&mut tree).map_err(|error| ConfigError::Invalid(vec![error]))?;
+        Ok(tree)
+    }
+
+    /// Reads the config from `tree`, checking it and converting it to Celsius
+    fn from_tree(tree: serde_json::Value) -> Result<Config, ConfigError> {
+        let mut config: Config = serde_path_to_error::deserialize(tree)
+            .map_err(|error| ConfigError::Parse(describe(error)))?;
+        let errors = config.validate();
+        if !errors.is_empty() {
+            return Err(ConfigError::Invalid(errors));
+        }
+        config.convert_to_celsius();
+        Ok(config)
+    }
+
+    /// Like `load`, with the defaults for this Mac in place of the base file.
+    /// The defaults are in Celsius, so they are converted to the unit the
+    /// files pick before the files are applied over them
+    fn over_defaults(paths: &[PathBuf]) -> Result<Config, ConfigError> {
+        let layers = Config::layered(serde_json::Value::Object(Default::default()), paths)?;
+        let defaults = Config::for_model(model::detect()).in_unit(unit_of(&layers));
+        let mut tree = serde_json::to_value(defaults).unwrap();
+        merge(&mut tree, layers);
+        Config::from_tree(tree)
+    }
+
+    /// Like `load`, but the defaults stand in for the first file if it is
+    /// missing. A config that can not be parsed is an error, unless
+    /// `strict_config` is turned off or `force_defaults` is set, then the
+    /// defaults are used instead
+    pub fn get(paths: &[PathBuf], force_defaults: bool) -> Result<Config, std::io::Error> {
+        let loaded = match Config::load(paths) {
+            Err(ConfigError::Io(path, error))
+                if error.kind() == io::ErrorKind::NotFound && paths.first() == Some(&path) =>
+            {
+                warn!(
+                    "No config at {}, using the defaults. Run `t2macd init` to create one",
+                    path.display()
+                );
+                Config::over_defaults(&paths[1..])
+            }
+            loaded => loaded,
+        };
+        match loaded {
+            Ok(config) => Ok(config),
+            Err(ConfigError::Io(_, error)) => Err(error),
+            Err(ConfigError::Parse(error)) if force_defaults || !strict_config(paths) => {
+                error!("Could not parse config: {}", error);
+                warn!("Using default config");
+                Ok(Config::for_model(model::detect()))
+            }
+            Err(ConfigError::Parse(error)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Could not parse config: {}\nFix the config, or start with --force-defaults to run with the defaults",
+                    error
+                ),
+            )),
+            Err(error) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                error.to_string(),
+            )),
+        }
+    }
+
+    /// Reads the config from `paths` like `load`, but keeps going past the
+    /// first problem to report all of them
+    pub fn check(paths: &[PathBuf]) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut layers = Vec::new();
+        let mut tree = serde_json::Value::Object(Default::default());
+        for path in paths {
+            let text = match fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(error) => {
+                    problems.push(format!("{}: {}", path.display(), error));
+                    continue;
+                }
+            };
+            match Format::of(path).parse::<serde_json::Value>(&text) {
+                Ok(mut layer) => {
+                    if let Err(error) = migrate::upgrade(&mut layer) {
+                        problems.push(format!("{}: {}", path.display(), error));
+                    }
+                    merge(&mut tree, layer.clone());
+                    layers.push((path, text, layer));
+                }
+                Err(error) => problems.push(format!("{}: {}", path.display(), error)),
+            }
+        }
+        apply_env(&mut tree);
+        if let Err(error) = select_profile(&mut tree) {
+            problems.push(error);
+        }
+        // Serde stops at the first unknown key, so drop each one found and
+        // try again to find the rest
+        let config = loop {
+            let error = match serde_path_to_error::deserialize::<_, Config>(tree.clone()) {
+                Ok(config) => break Ok(config),
+                Err(error) => error,
+            };
+            let unknown = match UnknownKey::from_error(&error) {
+                Some(unknown) => unknown,
+                None => break Err(describe(error)),
+            };
+            let pointer = unknown.pointer();
+            let (parent, key) = pointer.rsplit_once('/').unwrap();
+            let removed = tree
+                .pointer_mut(parent)
+                .and_then(|parent| parent.as_object_mut())
+                .and_then(|parent| parent.remove(key));
+            if removed.is_none() {
+                break Err(unknown.to_string());
+            }
+            // Report the key in the last file that sets it, as that one wins
+            let found = layers
+                .iter()
+                .rev()
+                .find(|(_, _, layer)| layer.pointer(&pointer).is_some());
+            match found {
+                Some((path, text, _)) => match line_of(text, key) {
+                    Some(line) => {
+                        problems.push(format!("{}:{}: {}", path.display(), line, unknown))
+                    }
+                    None => problems.push(format!("{}: {}", path.display(), unknown)),
+                },
+                None => problems.push(unknown.to_string()),
+            }
+        };
+        match config {
+            Ok(config) => {
+                problems.extend(config.validate());
+                // Left out on a machine without any of the sensors, where
+                // every name would be reported
+                let found = sensor::discover_sensors(&config);
+                if !found.is_empty() {
+                    let names: Vec<&str> =
+                        found.iter().map(|sensor| sensor.name.as_str()).collect();
+                    problems.extend(config.validate_sensors(&names));
+                }
+            }
+            Err(error) => problems.push(error),
+        }
+        problems
+    }
+
+    /// Checks the config for values that would make the daemon misbehave,
+    /// returning a description of every problem found
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let backends = backend::ids();
+        for id in &self.fan_backends {
+            if !backends.contains(&id.as_str()) {
+                errors.push(format!(
+                    "fan_backends: unknown backend \"{}\", expected one of {}",
+                    id,
+                    backends.join(", ")
+                ));
+            }
+        }
+        if let Some(pattern) = &self.fan_glob {
+            if let Err(error) = glob::Pattern::new(pattern) {
+                errors.push(format!(
+                    "fan_glob (\"{}\") is not a valid glob: {}",
+                    pattern, error
+                ));
+            } else if !pattern.ends_with("_input") {
+                errors.push(format!(
+                    "fan_glob (\"{}\") must match fan*_input files",
+                    pattern
+                ));
+            }
+        }
+        if let Some(template) = &self.fan_template {
+            let input_name = template.input.rsplit('/').next().unwrap_or_default();
+            if !template.input.starts_with('/') || !input_name.contains("{n}") {
+                errors.push(format!(
+                    "fan_template.input (\"{}\") must be an absolute path with {{n}} in its file name",
+                    template.input
+                ));
+            } else if let Err(error) = glob::Pattern::new(&template.input.replace("{n}", "1")) {
+                errors.push(format!(
+                    "fan_template.input (\"{}\") is not a valid glob: {}",
+                    template.input, error
+                ));
+            }
+            for (name, file) in [
+                ("output", Some(&template.output)),
+                ("min", Some(&template.min)),
+                ("max", Some(&template.max)),
+                ("manual", template.manual.as_ref()),
+                ("label", template.label.as_ref()),
+            ] {
+                if file.is_some_and(|file| file.is_empty() || file.contains('/')) {
+                    errors.push(format!(
+                        "fan_template.{} must be a file name next to fan_template.input",
+                        name
+                    ));
+                }
+            }
+            if self.fan_glob.is_some() || !self.fan_backends.is_empty() {
+                errors.push(String::from(
+                    "fan_template replaces the built-in backends, fan_glob and fan_backends cannot be used with it",
+                ));
+            }
+        }
+        // Whole temperatures are kept as unsigned Celsius, which has no room
+        // for them below freezing
+        if self.temperature_unit == TemperatureUnit::FAHRENHEIT {
+            for (name, temp) in self.whole_temps() {
+                if temp < 32 {
+                    errors.push(format!(
+                        "{} ({}°F) must be at least 32°F, temperatures below 0°C can not be set",
+                        name, temp
+                    ));
+                }
+            }
+        }
+        if self.min_temp >= self.max_temp {
+            errors.push(format!(
+                "min_temp ({}) must be below max_temp ({})",
+                self.min_temp, self.max_temp
+            ));
+        }
+        if matches!(self.fan_curve, FanCurve::POINTS) && self.curve_points.is_empty() {
+            errors.push(String::from(
+                "curve_points: POINTS curve needs at least one point",
+            ));
+        }
+        for (i, pair) in self.curve_points.windows(2).enumerate() {
+            if pair[1].temp <= pair[0].temp {
+                errors.push(format!(
+                    "curve_points[{}].temp ({}) must be above the previous point ({})",
+                    i + 1,
+                    pair[1].temp,
+                    pair[0].temp
+                ));
+            }
+        }
+        for (i, point) in self.curve_points.iter().enumerate() {
+            if point.percent > 100 {
+                errors.push(format!(
+                    "curve_points[{}].percent ({}) must be at most 100",
+                    i, point.percent
+                ));
+            }
+        }
+        if self.curve_exponent <= 0.0 {
+            errors.push(format!(
+                "curve_exponent ({}) must be above 0",
+                self.curve_exponent
+            ));
+        }
+        if self.sigmoid_steepness <= 0.0 {
+            errors.push(format!(
+                "sigmoid_steepness ({}) must be above 0",
+                self.sigmoid_steepness
+            ));
+        }
+        if let Some(adaptive_polling) = &self.adaptive_polling {
+            if adaptive_polling.calm_interval_ms < self.poll_interval_ms {
+                errors.push(format!(
+                    "adaptive_polling.calm_interval_ms ({}) must not be below poll_interval_ms ({})",
+                    adaptive_polling.calm_interval_ms, self.poll_interval_ms
+                ));
+            }
+            if adaptive_polling.calm_trend < 0.0 {
+                errors.push(format!(
+                    "adaptive_polling.calm_trend ({}) must not be negative",
+                    adaptive_polling.calm_trend
+                ));
+            }
+        }
+        if self.poll_interval_ms == 0 {
+            errors.push(String::from("poll_interval_ms must be above 0"));
+        }
+        for (name, ramp_time) in [
+            ("ramp_up_time", self.ramp_up_time),
+            ("ramp_down_time", self.ramp_down_time),
+            ("transition_time", self.transition_time),
+        ] {
+            if ramp_time < 0.0 {
+                errors.push(format!("{} ({}) must not be negative", name, ramp_time));
+            }
+        }
+        if self.fan_failure.time < 0.0 {
+            errors.push(format!(
+                "fan_failure.time ({}) must not be negative",
+                self.fan_failure.time
+            ));
+        }
+        if self.curve_blend_time < 0.0 {
+            errors.push(format!(
+                "curve_blend_time ({}) must not be negative",
+                self.curve_blend_time
+            ));
+        }
+        if let Err(error) = self.check_expression() {
+            errors.push(format!("curve_expression: {}", error));
+        }
+        if let Some(zero_rpm) = self.zero_rpm {
+            if zero_rpm.idle_temp > zero_rpm.spin_up_temp {
+                errors.push(format!(
+                    "zero_rpm.idle_temp ({}) must not be above zero_rpm.spin_up_temp ({})",
+                    zero_rpm.idle_temp, zero_rpm.spin_up_temp
+                ));
+            }
+        }
+        if matches!(self.control_mode, ControlMode::SCRIPT) && self.script.is_none() {
+            errors.push(String::from(
+                "script: SCRIPT control mode needs a script path",
+            ));
+        }
+        for (name, source) in &self.sensors.virtual_sensors {
+            if let Err(error) = Expression::parse(source) {
+                errors.push(format!("sensors.virtual.{}: {}", name, error));
+            }
+        }
+        if self.sensors.median_window == 0 {
+            errors.push(String::from("sensors.median_window must be at least 1"));
+        }
+        if !(0.0..1.0).contains(&self.sensors.smoothing) {
+            errors.push(format!(
+                "sensors.smoothing ({}) must be at least 0 and below 1",
+                self.sensors.smoothing
+            ));
+        }
+        if self.sensors.core_aggregation == Aggregation::WEIGHTED {
+            errors.push(String::from(
+                "sensors.core_aggregation must be MAX or AVG, CPU cores have no weight",
+            ));
+        }
+        for (name, sensor) in &self.sensors.devices {
+            if let Some(weight) = sensor.weight {
+                if weight < 0.0 {
+                    errors.push(format!(
+                        "sensors.{}.weight ({}) must not be negative",
+                        name, weight
+                    ));
+                }
+            }
+        }
+        for (i, fan) in self.fans.iter().enumerate() {
+            if fan.index.is_none() && fan.label.is_none() {
+                errors.push(format!("fans[{}]: needs an index or a label", i));
+            }
+            let min_temp = fan.min_temp.unwrap_or(self.min_temp);
+            let max_temp = fan.max_temp.unwrap_or(self.max_temp);
+            if (fan.min_temp.is_some() || fan.max_temp.is_some()) && min_temp >= max_temp {
+                errors.push(format!(
+                    "fans[{}].min_temp ({}) must be below fans[{}].max_temp ({})",
+                    i, min_temp, i, max_temp
+                ));
+            }
+            if !(-100.0..=100.0).contains(&fan.speed_offset) {
+                errors.push(format!(
+                    "fans[{}].speed_offset ({}) must be between -100 and 100",
+                    i, fan.speed_offset
+                ));
+            }
+            if !(fan.scale > 0.0 && fan.scale.is_finite()) {
+                errors.push(format!("fans[{}].scale ({}) must be above 0", i, fan.scale));
+            }
+            if let (Some(Speed::Rpm(min_speed)), Some(Speed::Rpm(noise_cap))) =
+                (fan.min_speed, fan.noise_cap)
+            {
+                if min_speed > noise_cap {
+                    errors.push(format!(
+                        "fans[{}].min_speed ({}) must not be above fans[{}].noise_cap ({})",
+                        i, min_speed, i, noise_cap
+                    ));
+                }
+            }
+        }
+        errors
+    }
+
+    /// Every temperature kept as a whole number, by its key
+    fn whole_temps(&self) -> Vec<(String, u32)> {
+        let mut temps = vec![
+            (String::from("min_temp"), self.min_temp),
+            (String::from("max_temp"), self.max_temp),
+        ];
+        for (i, point) in self.curve_points.iter().enumerate() {
+            temps.push((format!("curve_points[{}].temp", i), point.temp));
+        }
+        if let Some(zero_rpm) = &self.zero_rpm {
+            temps.push((String::from("zero_rpm.idle_temp"), zero_rpm.idle_temp));
+            temps.push((String::from("zero_rpm.spin_up_temp"), zero_rpm.spin_up_temp));
+        }
+        if let Some(noise_cap) = &self.noise_cap {
+            temps.push((
+                String::from("noise_cap.critical_temp"),
+                noise_cap.critical_temp,
+            ));
+        }
+        if let Some(adaptive_polling) = &self.adaptive_polling {
+            temps.push((
+                String::from("adaptive_polling.calm_temp"),
+                adaptive_polling.calm_temp,
+            ));
+        }
+        for (i, fan) in self.fans.iter().enumerate() {
+            temps.extend(
+                fan.min_temp
+                    .map(|temp| (format!("fans[{}].min_temp", i), temp)),
+            );
+            temps.extend(
+                fan.max_temp
+                    .map(|temp| (format!("fans[{}].max_temp", i), temp)),
+            );
+        }
+        for (name, sensor) in &self.sensors.devices {
+            temps.extend(
+                sensor
+                    .critical_temp
+                    .map(|temp| (format!("sensors.{}.critical_temp", name), temp)),
+            );
+        }
+        temps
+    }
+
+    /// Rewrites every temperature from `temperature_unit` to Celsius
+    fn convert_to_celsius(&mut self) {
+        let unit = self.temperature_unit;
+        self.convert(|temp| unit.celsius_from(temp), unit.delta_scale());
+    }
+
+    /// The config with every temperature, given in Celsius, rewritten to
+    /// `unit`, the reverse of `convert_to_celsius`
+    fn in_unit(mut self, unit: TemperatureUnit) -> Config {
+        self.convert(|temp| unit.celsius_to(temp), 1.0 / unit.delta_scale());
+        self.temperature_unit = unit;
+        self
+    }
+
+    /// Rewrites every temperature through `convert`, and every difference of
+    /// temperatures by `delta_scale`
+    fn convert(&mut self, convert: impl Fn(f64) -> f64, delta_scale: f64) {
+        let temp = |temp: u32| convert(temp as f64).round() as u32;
+        let delta = |delta: u32| (delta as f64 * delta_scale).round() as u32;
+        self.min_temp = temp(self.min_temp);
+        self.max_temp = temp(self.max_temp);
+        for point in &mut self.curve_points {
+            point.temp = temp(point.temp);
+        }
+        self.sigmoid_midpoint = convert(self.sigmoid_midpoint);
+        self.sigmoid_steepness /= delta_scale;
+        self.pid.target_temp = convert(self.pid.target_temp);
+        self.pid.kp /= delta_scale;
+        self.pid.ki /= delta_scale;
+        self.pid.kd /= delta_scale;
+        self.hysteresis = delta(self.hysteresis);
+        if let Some(dead_band) = &mut self.dead_band {
+            dead_band.temp = delta(dead_band.temp);
+        }
+        self.predictive.max_fall_rate *= delta_scale;
+        if let Some(zero_rpm) = &mut self.zero_rpm {
+            zero_rpm.idle_temp = temp(zero_rpm.idle_temp);
+            zero_rpm.spin_up_temp = temp(zero_rpm.spin_up_temp);
+        }
+        if let Some(noise_cap) = &mut self.noise_cap {
+            noise_cap.critical_temp = temp(noise_cap.critical_temp);
+        }
+        if let Some(adaptive_polling) = &mut self.adaptive_polling {
+            adaptive_polling.calm_temp = temp(adaptive_polling.calm_temp);
+            adaptive_polling.calm_trend *= delta_scale;
+        }
+        for fan in &mut self.fans {
+            fan.min_temp = fan.min_temp.map(temp);
+            fan.max_temp = fan.max_temp.map(temp);
+        }
+        for sensor in self.sensors.devices.values_mut() {
+            sensor.critical_temp = sensor.critical_temp.map(temp);
+            sensor.offset *= delta_scale;
+        }
+    }
+
+    /// Finds a sensor above its `critical_temp`, returning its reading and
+    /// limit
+    pub fn critical_sensor<'a>(&self, readings: &'a [Reading]) -> Option<(&'a Reading, u32)> {
+        readings.iter().find_map(|reading| {
+            let configured = self
+                .sensors
+                .devices
+                .get(&reading.name)
+                .and_then(|sensor| sensor.critical_temp);
+            let critical_temp = match configured {
+                Some(critical_temp) => critical_temp,
+                None if reading.name == SensorKind::BATTERY.name() => sensor::BATTERY_CRITICAL_TEMP,
+                None => return None,
+            };
+            (reading.temp >= critical_temp as f64).then_some((reading, critical_temp))
+        })
+    }
+
+    fn check_expression(&self) -> Result<(), String> {
+        let uses_expression = matches!(self.fan_curve, FanCurve::EXPRESSION)
+            || self
+                .fans
+                .iter()
+                .any(|fan| matches!(fan.fan_curve, Some(FanCurve::EXPRESSION)));
+        if !uses_expression {
+            return Ok(());
+        }
+        let source = match &self.curve_expression {
+            Some(source) => source,
+            None => return Err(String::from("EXPRESSION curve needs a curve_expression")),
+        };
+        let expression = Expression::parse(source)?;
+        for variable in expression.variables() {
+            if !EXPRESSION_VARIABLES.contains(&variable) {
+                return Err(format!(
+                    "unknown variable {}, expected one of t, min or max",
+                    variable
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the sensors named by `sensors.virtual` and `sensors.input`
+    /// against `found`, the names of the sensors found on the machine
+    pub fn validate_sensors(&self, found: &[&str]) -> Vec<String> {
+        let virtuals: Vec<(&String, Expression)> = self
+            .sensors
+            .virtual_sensors
+            .iter()
+            .filter_map(|(name, source)| Some((name, Expression::parse(source).ok()?)))
+            .collect();
+        let known =
+            |name: &str| found.contains(&name) || self.sensors.virtual_sensors.contains_key(name);
+        let mut errors = Vec::new();
+        for (name, expression) in &virtuals {
+            for variable in expression.variables() {
+                if !known(variable) {
+                    errors.push(format!(
+                        "sensors.virtual.{}: there is no sensor called {}",
+                        name, variable
+                    ));
+                }
+            }
+        }
+        // Virtual sensors that use each other, and can never be worked out
+        let mut resolved: Vec<&str> = found.to_vec();
+        loop {
+            let before = resolved.len();
+            for (name, expression) in &virtuals {
+                if !resolved.contains(&name.as_str())
+                    && expression
+                        .variables()
+                        .iter()
+                        .all(|variable| resolved.contains(variable))
+                {
+                    resolved.push(name);
+                }
+            }
+            if resolved.len() == before {
+                break;
+            }
+        }
+        for (name, expression) in &virtuals {
+            let variables = expression.variables();
+            let unresolved: Vec<&str> = variables
+                .iter()
+                .copied()
+                .filter(|variable| {
+                    self.sensors.virtual_sensors.contains_key(*variable)
+                        && !resolved.contains(variable)
+                })
+                .collect();
+            if !unresolved.is_empty() {
+                errors.push(format!(
+                    "sensors.virtual.{}: uses {}, which can not be worked out",
+                    name,
+                    unresolved.join(", ")
+                ));
+            }
+        }
+        if let Some(input) = &self.sensors.input {
+            if !known(input) {
+                errors.push(format!(
+                    "sensors.input: there is no sensor called {}",
+                    input
+                ));
+            }
+        }
+        errors
+    }
+
+    /// Checks a fans overrides against the limits reported by the hardware
+    pub fn validate_fan(&self, index: u32, label: Option<&str>, limits: &FanLimits) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Some(min_speed) = self.min_speed_for_fan(index, label, limits) {
+            if min_speed > limits.max_speed {
+                errors.push(format!(
+                    "min_speed ({}) for fan{} is above its hardware maximum of {}",
+                    min_speed, index, limits.max_speed
+                ));
+            }
+        }
+        if let Some(noise_cap) = self.noise_cap_for_fan(index, label, limits) {
+            if noise_cap < limits.min_speed {
+                errors.push(format!(
+                    "noise_cap ({}) for fan{} is below its hardware minimum of {}",
+                    noise_cap, index, limits.min_speed
+                ));
+            }
+        }
+        errors
+    }
+
+    /// Returns the config with the overrides for this fan applied
+    pub fn for_fan(&self, index: u32, label: Option<&str>) -> Config {
+        let mut config = self.clone();
+        if let Some(fan) = self.fans.iter().find(|fan| fan.matches(index, label)) {
+            if let Some(fan_curve) = fan.fan_curve {
+                config.fan_curve = fan_curve;
+                config.preset = None;
+            }
+            config.min_temp = fan.min_temp.unwrap_or(config.min_temp);
+            config.max_temp = fan.max_temp.unwrap_or(config.max_temp);
+        }
+        config
+    }
+
+    pub fn min_speed_for_fan(
+        &self,
+        index: u32,
+        label: Option<&str>,
+        limits: &FanLimits,
+    ) -> Option<u32> {
+        self.fans
+            .iter()
+            .find(|fan| fan.matches(index, label))
+            .and_then(|fan| fan.min_speed)
+            .or(self.min_speed)
+            .map(|speed| speed.resolve(limits))
+    }
+
+    pub fn fan_enabled(&self, index: u32, label: Option<&str>) -> bool {
+        self.fans
+            .iter()
+            .find(|fan| fan.matches(index, label))
+            .is_none_or(|fan| fan.enabled)
+    }
+
+    pub fn speed_offset_for_fan(&self, index: u32, label: Option<&str>) -> f64 {
+        self.fans
+            .iter()
+            .find(|fan| fan.matches(index, label))
+            .map_or(0.0, |fan| fan.speed_offset)
+    }
+
+    pub fn sensors_for_fan(&self, index: u32, label: Option<&str>) -> Vec<String> {
+        self.fans
+            .iter()
+            .find(|fan| fan.matches(index, label))
+            .map_or(Vec::new(), |fan| fan.sensors.clone())
+    }
+
+    pub fn offset_rpm_for_fan(&self, index: u32, label: Option<&str>) -> i32 {
+        self.fans
+            .iter()
+            .find(|fan| fan.matches(index, label))
+            .map_or(0, |fan| fan.offset_rpm)
+    }
+
+    pub fn scale_for_fan(&self, index: u32, label: Option<&str>) -> f64 {
+        self.fans
+            .iter()
+            .find(|fan| fan.matches(index, label))
+            .map_or(1.0, |fan| fan.scale)
+    }
+
+    pub fn noise_cap_for_fan(
+        &self,
+        index: u32,
+        label: Option<&str>,
+        limits: &FanLimits,
+    ) -> Option<u32> {
+        let noise_cap = self.noise_cap?;
+        let fan_cap = self
+            .fans
+            .iter()
+            .find(|fan| fan.matches(index, label))
+            .and_then(|fan| fan.noise_cap);
+        Some(fan_cap.unwrap_or(noise_cap.max_speed).resolve(limits))
+    }
+
+    pub fn speed_curve(&self) -> Box<dyn SpeedCurve> {
+        match self.preset {
+            Some(preset) => Box::new(Points {
+                points: preset.points(),
+            }),
+            None => self.fan_curve.build(self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::Linear;
+
+    const FAN: FanLimits = FanLimits {
+        min_speed: 1000,
+        max_speed: 5000,
+    };
+
+    /// Writes `text` to `name` in a directory of its own under the temporary
+    /// directory, named after `test`
+    fn write(test: &str, name: &str, text: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("t2macd-{}-{}", test, std::process::id()));
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn remove(path: &Path) {
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    /// `Config::load_over`, panicking with the message of an error
+    fn load(tree: serde_json::Value, paths: &[PathBuf]) -> Config {
+        Config::load_over(tree, paths).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    #[test]
+    fn a_known_model_keeps_the_curve_set_over_its_defaults() {
+        let model = model::find("MacBookPro16,1");
+        let defaults = serde_json::to_value(Config::for_model(model)).unwrap();
+        let path = write("model-curve", "t2macd.toml", "fan_curve = \"LINEAR\"\n");
+        let config = load(defaults, std::slice::from_ref(&path));
+        remove(&path);
+        let linear = Linear {
+            min_temp: config.min_temp as f64,
+            max_temp: config.max_temp as f64,
+        };
+        for temp in [40.0, 60.0, 70.0, 85.0] {
+            assert_eq!(
+                config.speed_curve().speed(temp, &FAN),
+                linear.speed(temp, &FAN)
+            );
+        }
+    }
+
+    #[test]
+    fn a_known_model_defaults_to_its_curve_points() {
+        let config = Config::for_model(model::find("MacBookAir9,1"));
+        let points = Points {
+            points: Preset::PERFORMANCE.points(),
+        };
+        assert_eq!(
+            config.speed_curve().speed(60.0, &FAN),
+            points.speed(60.0, &FAN)
+        );
+    }
+
+    /// The defaults with `sensors.virtual` set to `virtuals` and
+    /// `sensors.input` to `input`
+    fn with_sensors(virtuals: &[(&str, &str)], input: Option<&str>) -> Config {
+        let mut config = Config::default();
+        for (name, source) in virtuals {
+            config
+                .sensors
+                .virtual_sensors
+                .insert(name.to_string(), source.to_string());
+        }
+        config.sensors.input = input.map(String::from);
+        config
+    }
+
+    #[test]
+    fn sensors_that_exist_are_accepted() {
+        let config = with_sensors(&[("b", "max(coretemp, a)"), ("a", "nvme + 1")], Some("b"));
+        assert!(config.validate_sensors(&["coretemp", "nvme"]).is_empty());
+    }
+
+    #[test]
+    fn virtual_sensors_need_their_sensors() {
+        let config = with_sensors(&[("chassis", "max(coretemp, amdgpu)")], None);
+        assert_eq!(
+            config.validate_sensors(&["coretemp"]),
+            ["sensors.virtual.chassis: there is no sensor called amdgpu"]
+        );
+    }
+
+    #[test]
+    fn virtual_sensors_can_not_use_themselves() {
+        let config = with_sensors(&[("a", "b + 1"), ("b", "a - 1")], None);
+        assert_eq!(
+            config.validate_sensors(&["coretemp"]),
+            [
+                "sensors.virtual.a: uses b, which can not be worked out",
+                "sensors.virtual.b: uses a, which can not be worked out",
+            ]
+        );
+    }
+
+    #[test]
+    fn input_needs_its_sensor() {
+        let config = with_sensors(&[], Some("amdgpu"));
+        assert_eq!(
+            config.validate_sensors(&["coretemp"]),
+            ["sensors.input: there is no sensor called amdgpu"]
+        );
+    }
+
+    #[test]
+    fn legacy_json_converts_to_the_current_version() {
+        let from = write(
+            "convert",
+            "t2macd.json",
+            r#"{ "fan_curve": "EXPONENTIAL", "min_temp": 70, "max_temp": 95 }"#,
+        );
+        let to = from.with_extension("toml");
+        convert(&from, &to).unwrap();
+        let config = load(serde_json::json!({}), std::slice::from_ref(&to));
+        remove(&from);
+        assert_eq!(config.version, migrate::CONFIG_VERSION);
+        assert!(matches!(config.fan_curve, FanCurve::EXPONENTIAL));
+        assert_eq!((config.min_temp, config.max_temp), (70, 95));
+    }
+
+    /// The defaults as TOML, after `top`, which can only hold top level keys
+    fn defaults_after(top: &str) -> String {
+        format!(
+            "{}\n{}",
+            top,
+            toml::to_string_pretty(&Config::default()).unwrap()
+        )
+    }
+
+    #[test]
+    fn edit_distance_counts_single_edits() {
+        assert_eq!(edit_distance("max_temp", "max_temp"), 0);
+        assert_eq!(edit_distance("max_tmep", "max_temp"), 2);
+        assert_eq!(edit_distance("min_tem", "min_temp"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn unknown_keys_suggest_the_closest_known_one() {
+        let path = write(
+            "unknown-close",
+            "t2macd.toml",
+            &defaults_after("# Fans\n\nmax_tmep = 95"),
+        );
+        let problems = Config::check(std::slice::from_ref(&path));
+        remove(&path);
+        assert_eq!(
+            problems,
+            [format!(
+                "{}:3: unknown key max_tmep, did you mean max_temp?",
+                path.display()
+            )]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_without_a_close_one_suggest_nothing() {
+        let path = write(
+            "unknown-far",
+            "t2macd.toml",
+            &defaults_after("flux_capacitor = 1"),
+        );
+        let problems = Config::check(std::slice::from_ref(&path));
+        remove(&path);
+        assert_eq!(
+            problems,
+            [format!("{}:1: unknown key flux_capacitor", path.display())]
+        );
+    }
+
+    #[test]
+    fn every_unknown_key_is_reported_on_its_line() {
+        let path = write(
+            "unknown-lines",
+            "t2macd.toml",
+            &defaults_after("min_tem = 60\n\nmax_tmep = 95"),
+        );
+        let problems = Config::check(std::slice::from_ref(&path));
+        remove(&path);
+        assert_eq!(
+            problems,
+            [
+                format!(
+                    "{}:3: unknown key max_tmep, did you mean max_temp?",
+                    path.display()
+                ),
+                format!(
+                    "{}:1: unknown key min_tem, did you mean min_temp?",
+                    path.display()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn drop_ins_override_the_base_file_and_each_other_in_order() {
+        let base = write("layers", "t2macd.toml", &defaults_after(""));
+        let first = write(
+            "layers",
+            "10-first.toml",
+            "min_temp = 60\nmax_temp = 90\n[pid]\nkp = 0.5",
+        );
+        let second = write(
+            "layers",
+            "20-second.toml",
+            "max_temp = 95\n[pid]\nki = 0.25",
+        );
+        let empty = serde_json::Value::Object(Default::default());
+        let config = load(
+            empty.clone(),
+            &[base.clone(), first.clone(), second.clone()],
+        );
+        let reversed = load(empty, &[base.clone(), second, first]);
+        remove(&base);
+        assert_eq!((config.min_temp, config.max_temp), (60, 95));
+        assert_eq!(reversed.max_temp, 90);
+        // Tables are merged key by key, keeping what the base file set
+        assert_eq!((config.pid.kp, config.pid.ki), (0.5, 0.25));
+        assert_eq!(config.pid.target_temp, Config::default().pid.target_temp);
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn environment_variables_override_the_files() {
+        let mut tree = serde_json::to_value(Config {
+            min_temp: 60,
+            max_temp: 90,
+            ..Config::default()
+        })
+        .unwrap();
+        apply_vars(
+            &mut tree,
+            vars(&[
+                ("T2MACD_MAX_TEMP", "95"),
+                ("T2MACD_PID__KP", "0.5"),
+                ("T2MACD_FAN_CURVE", "EXPONENTIAL"),
+                ("T2MACD_NO_SUCH_OPTION", "1"),
+                ("OTHER_MAX_TEMP", "70"),
+            ]),
+        );
+        let config: Config = serde_json::from_value(tree).unwrap();
+        assert_eq!((config.min_temp, config.max_temp), (60, 95));
+        assert_eq!(config.pid.kp, 0.5);
+        assert!(matches!(config.fan_curve, FanCurve::EXPONENTIAL));
+    }
+
+    #[test]
+    fn nested_environment_variables_start_from_the_defaults() {
+        let mut tree = serde_json::json!({ "max_temp": 90 });
+        apply_vars(&mut tree, vars(&[("T2MACD_PID__KI", "0.25")]));
+        let mut pid = serde_json::to_value(Config::default().pid).unwrap();
+        pid["ki"] = serde_json::json!(0.25);
+        assert_eq!(tree, serde_json::json!({ "max_temp": 90, "pid": pid }));
+    }
+
+    #[test]
+    fn nested_environment_variables_start_from_defaults_in_the_unit_of_the_files() {
+        let mut tree = serde_json::json!({ "temperature_unit": "FAHRENHEIT" });
+        apply_vars(&mut tree, vars(&[("T2MACD_PID__KI", "0.25")]));
+        assert_eq!(tree["pid"]["target_temp"], serde_json::json!(185.0));
+    }
+
+    #[test]
+    fn the_default_profile_is_applied_over_the_rest() {
+        let text = format!(
+            "{}\n{}",
+            defaults_after("default_profile = \"quiet\""),
+            "[profile.quiet]\nmin_temp = 70\nfan_curve = \"EXPONENTIAL\"\n\n\
+             [profile.quiet.pid]\nkp = 0.5\n\n[profile.loud]\nmin_temp = 50"
+        );
+        let path = write("profiles", "t2macd.toml", &text);
+        let config = load(
+            serde_json::Value::Object(Default::default()),
+            std::slice::from_ref(&path),
+        );
+        remove(&path);
+        assert_eq!(config.min_temp, 70);
+        assert!(matches!(config.fan_curve, FanCurve::EXPONENTIAL));
+        assert_eq!(config.pid.kp, 0.5);
+        assert_eq!(config.pid.ki, Config::default().pid.ki);
+    }
+
+    #[test]
+    fn without_a_default_profile_none_is_applied() {
+        let mut tree =
+            serde_json::json!({ "min_temp": 60, "profile": { "quiet": { "min_temp": 70 } } });
+        let unchanged = tree.clone();
+        assert_eq!(select_profile(&mut tree), Ok(()));
+        assert_eq!(tree, unchanged);
+    }
+
+    #[test]
+    fn a_missing_default_profile_is_an_error() {
+        let mut tree = serde_json::json!({ "default_profile": "loud", "profile": { "quiet": {} } });
+        assert_eq!(
+            select_profile(&mut tree),
+            Err(String::from(
+                "default_profile: there is no profile called loud"
+            ))
+        );
+    }
+
+    #[test]
+    fn includes_follow_the_file_that_includes_them() {
+        let base = write("includes", "t2macd.toml", "include = [\"conf/*.toml\"]");
+        let second = write("includes", "conf/b.toml", "");
+        let first = write("includes", "conf/a.toml", "");
+        let last = write("includes", "user.toml", "");
+        let paths = with_includes(&[base.clone(), last.clone()]);
+        remove(&base);
+        assert_eq!(paths, [base, first, second, last]);
+    }
+
+    #[test]
+    fn an_include_loop_is_cut_instead_of_recursing() {
+        let first = write("include-loop", "a.toml", "include = [\"b.toml\"]");
+        let second = write(
+            "include-loop",
+            "b.toml",
+            "include = [\"a.toml\", \"b.toml\"]",
+        );
+        let paths = with_includes(std::slice::from_ref(&first));
+        remove(&first);
+        assert_eq!(paths, [first, second]);
+    }
+
+    /// The problems `Config::validate` finds with `layer` over the defaults
+    fn problems(layer: serde_json::Value) -> Vec<String> {
+        let mut tree = serde_json::to_value(Config::default()).unwrap();
+        merge(&mut tree, layer);
+        let config: Config = serde_json::from_value(tree).unwrap();
+        config.validate()
+    }
+
+    #[test]
+    fn the_defaults_are_valid() {
+        assert!(Config::default().validate().is_empty());
+    }
+
+    #[test]
+    fn every_rule_rejects_a_config() {
+        use serde_json::json;
+        // A valid fan_template with `changes` over it
+        let template = |changes: serde_json::Value| {
+            let mut template = json!({
+                "input": "/sys/fans/fan{n}_input",
+                "output": "fan{n}_output",
+                "min": "fan{n}_min",
+                "max": "fan{n}_max",
+            });
+            merge(&mut template, changes);
+            template
+        };
+        let cases = [
+            (
+                json!({ "fan_backends": ["nope"] }),
+                format!(
+                    "fan_backends: unknown backend \"nope\", expected one of {}",
+                    backend::ids().join(", ")
+                ),
+            ),
+            (
+                json!({ "fan_glob": "/sys/[" }),
+                String::from(
+                    "fan_glob (\"/sys/[\") is not a valid glob: Pattern syntax error near position 5: invalid range pattern",
+                ),
+            ),
+            (
+                json!({ "fan_glob": "/sys/*/fan*" }),
+                String::from("fan_glob (\"/sys/*/fan*\") must match fan*_input files"),
+            ),
+            (
+                json!({ "fan_template": template(json!({ "input": "fan{n}_input" })) }),
+                String::from(
+                    "fan_template.input (\"fan{n}_input\") must be an absolute path with {n} in its file name",
+                ),
+            ),
+            (
+                json!({ "fan_template": template(json!({ "input": "/sys/fans/fan_input" })) }),
+                String::from(
+                    "fan_template.input (\"/sys/fans/fan_input\") must be an absolute path with {n} in its file name",
+                ),
+            ),
+            (
+                json!({ "fan_template": template(json!({ "input": "/sys/[/fan{n}_input" })) }),
+                String::from(
+                    "fan_template.input (\"/sys/[/fan{n}_input\") is not a valid glob: Pattern syntax error near position 5: invalid range pattern",
+                ),
+            ),
+            (
+                json!({ "fan_template": template(json!({ "output": "../fan{n}_output" })) }),
+                String::from("fan_template.output must be a file name next to fan_template.input"),
+            ),
+            (
+                json!({ "fan_template": template(json!({ "label": "" })) }),
+                String::from("fan_template.label must be a file name next to fan_template.input"),
+            ),
+            (
+                json!({ "fan_template": template(json!({})), "fan_glob": "/sys/*/fan*_input" }),
+                String::from(
+                    "fan_template replaces the built-in backends, fan_glob and fan_backends cannot be used with it",
+                ),
+            ),
+            (
+                json!({ "min_temp": 90, "max_temp": 90 }),
+                String::from("min_temp (90) must be below max_temp (90)"),
+            ),
+            (
+                json!({ "fan_curve": "POINTS", "curve_points": [] }),
+                String::from("curve_points: POINTS curve needs at least one point"),
+            ),
+            (
+                json!({ "curve_points": [{ "temp": 60, "percent": 10 }, { "temp": 60, "percent": 20 }] }),
+                String::from("curve_points[1].temp (60) must be above the previous point (60)"),
+            ),
+            (
+                json!({ "curve_points": [{ "temp": 60, "percent": 101 }] }),
+                String::from("curve_points[0].percent (101) must be at most 100"),
+            ),
+            (
+                json!({ "curve_exponent": 0.0 }),
+                String::from("curve_exponent (0) must be above 0"),
+            ),
+            (
+                json!({ "sigmoid_steepness": -1.0 }),
+                String::from("sigmoid_steepness (-1) must be above 0"),
+            ),
+            (
+                json!({ "adaptive_polling": { "calm_interval_ms": 500, "calm_temp": 50, "calm_trend": 0.1 } }),
+                String::from(
+                    "adaptive_polling.calm_interval_ms (500) must not be below poll_interval_ms (1000)",
+                ),
+            ),
+            (
+                json!({ "adaptive_polling": { "calm_interval_ms": 5000, "calm_temp": 50, "calm_trend": -0.1 } }),
+                String::from("adaptive_polling.calm_trend (-0.1) must not be negative"),
+            ),
+            (
+                json!({ "poll_interval_ms": 0 }),
+                String::from("poll_interval_ms must be above 0"),
+            ),
+            (
+                json!({ "ramp_up_time": -1.0 }),
+                String::from("ramp_up_time (-1) must not be negative"),
+            ),
+            (
+                json!({ "ramp_down_time": -1.0 }),
+                String::from("ramp_down_time (-1) must not be negative"),
+            ),
+            (
+                json!({ "transition_time": -1.0 }),
+                String::from("transition_time (-1) must not be negative"),
+            ),
+            (
+                json!({ "fan_failure": { "time": -1.0 } }),
+                String::from("fan_failure.time (-1) must not be negative"),
+            ),
+            (
+                json!({ "curve_blend_time": -1.0 }),
+                String::from("curve_blend_time (-1) must not be negative"),
+            ),
+            (
+                json!({ "fan_curve": "EXPRESSION" }),
+                String::from("curve_expression: EXPRESSION curve needs a curve_expression"),
+            ),
+            (
+                json!({ "zero_rpm": { "idle_temp": 60, "spin_up_temp": 50 } }),
+                String::from(
+                    "zero_rpm.idle_temp (60) must not be above zero_rpm.spin_up_temp (50)",
+                ),
+            ),
+            (
+                json!({ "control_mode": "SCRIPT" }),
+                String::from("script: SCRIPT control mode needs a script path"),
+            ),
+            (
+                json!({ "sensors": { "virtual": { "delta": "cpu -" } } }),
+                String::from("sensors.virtual.delta: unexpected end of expression"),
+            ),
+            (
+                json!({ "sensors": { "median_window": 0 } }),
+                String::from("sensors.median_window must be at least 1"),
+            ),
+            (
+                json!({ "sensors": { "smoothing": 1.0 } }),
+                String::from("sensors.smoothing (1) must be at least 0 and below 1"),
+            ),
+            (
+                json!({ "sensors": { "core_aggregation": "WEIGHTED" } }),
+                String::from("sensors.core_aggregation must be MAX or AVG, CPU cores have no weight"),
+            ),
+            (
+                json!({ "sensors": { "coretemp": { "weight": -1.0 } } }),
+                String::from("sensors.coretemp.weight (-1) must not be negative"),
+            ),
+            (
+                json!({ "fans": [{ "min_temp": 60 }] }),
+                String::from("fans[0]: needs an index or a label"),
+            ),
+            (
+                json!({ "fans": [{ "index": 1, "max_temp": 70 }] }),
+                String::from("fans[0].min_temp (80) must be below fans[0].max_temp (70)"),
+            ),
+            (
+                json!({ "fans": [{ "index": 1, "speed_offset": 101.0 }] }),
+                String::from("fans[0].speed_offset (101) must be between -100 and 100"),
+            ),
+            (
+                json!({ "fans": [{ "index": 1, "scale": 0.0 }] }),
+                String::from("fans[0].scale (0) must be above 0"),
+            ),
+            (
+                json!({ "fans": [{ "index": 1, "min_speed": 3000, "noise_cap": 2000 }] }),
+                String::from("fans[0].min_speed (3000) must not be above fans[0].noise_cap (2000)"),
+            ),
+        ];
+        for (layer, problem) in cases {
+            assert_eq!(problems(layer.clone()), [problem], "for {}", layer);
+        }
+    }
+
+    #[test]
+    fn defaults_are_converted_to_the_unit_of_the_drop_ins() {
+        let drop_in = write(
+            "fahrenheit-defaults",
+            "10-fahrenheit.toml",
+            "temperature_unit = \"FAHRENHEIT\"\nmax_temp = 221",
+        );
+        let missing = drop_in.with_file_name("t2macd.toml");
+        let config = Config::get(&[missing, drop_in.clone()], false).unwrap();
+        remove(&drop_in);
+        let defaults = Config::for_model(model::detect());
+        assert_eq!((config.min_temp, config.max_temp), (defaults.min_temp, 105));
+        assert_eq!(config.pid.target_temp, defaults.pid.target_temp);
+        assert!((config.pid.kp - defaults.pid.kp).abs() < 1e-9);
+    }
+
+    #[test]
+    fn fahrenheit_below_freezing_is_rejected() {
+        let errors = problems(serde_json::json!({
+            "temperature_unit": "FAHRENHEIT",
+            "min_temp": 20,
+            "max_temp": 200,
+            "fans": [{ "index": 1, "min_temp": 40 }],
+            "zero_rpm": { "idle_temp": 10, "spin_up_temp": 100 },
+        }));
+        assert_eq!(
+            errors,
+            [
+                "min_temp (20°F) must be at least 32°F, temperatures below 0°C can not be set",
+                "zero_rpm.idle_temp (10°F) must be at least 32°F, temperatures below 0°C can not be set",
+            ]
+        );
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::curve::Speed;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PidConfig {
+    pub target_temp: f64,
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+}
+
+impl Default for PidConfig {
+    fn default() -> PidConfig {
+        PidConfig {
+            target_temp: 85.0,
+            kp: 0.05,
+            ki: 0.005,
+            kd: 0.01,
+        }
+    }
+}
+
+/// Stops the fans once every temperature is below `idle_temp`, and only spins
+/// them back up after `spin_up_temp` is crossed
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct ZeroRpmConfig {
+    pub idle_temp: u32,
+    pub spin_up_temp: u32,
+}
+
+/// Caps the fans at `max_speed` to keep them quiet, letting temperatures rise
+/// instead until `critical_temp` is reached
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct NoiseCapConfig {
+    pub max_speed: Speed,
+    pub critical_temp: u32,
+}
+
+/// Skips writing a new speed while it is within `speed` RPM of the last one
+/// written and the temperature has moved less than `temp` degrees since
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct DeadBandConfig {
+    pub speed: u32,
+    pub temp: u32,
+}
+
+/// Closed loop controller that drives a fan to hold `target_temp`
+pub struct PidController {
+    gains: PidConfig,
+    integral: f64,
+    last_error: Option<f64>,
+    last_update: Option<Instant>,
+}
+
+impl PidController {
+    pub fn new(gains: PidConfig) -> PidController {
+        PidController {
+            gains,
+            integral: 0.0,
+            last_error: None,
+            last_update: None,
+        }
+    }
+
+    pub fn gains(&self) -> PidConfig {
+        self.gains
+    }
+
+    /// Returns the fan output as a fraction of the fans speed range
+    pub fn update(&mut self, current_temp: f64) -> f64 {
+        self.update_at(current_temp, Instant::now())
+    }
+
+    /// `update` as of `now`
+    fn update_at(&mut self, current_temp: f64, now: Instant) -> f64 {
+        let dt = match self.last_update {
+            Some(last) => now.duration_since(last).as_secs_f64(),
+            None => 0.0,
+        };
+        self.last_update = Some(now);
+
+        // Positive error means we are too hot, so the fans need to speed up
+        let error = current_temp - self.gains.target_temp;
+        let derivative = match self.last_error {
+            Some(last_error) if dt > 0.0 => (error - last_error) / dt,
+            _ => 0.0,
+        };
+        self.last_error = Some(error);
+
+        let integral = self.integral + error * dt;
+        let output = self.gains.kp * error + self.gains.ki * integral + self.gains.kd * derivative;
+
+        // Anti-windup: stop accumulating while the output is saturated, unless
+        // the error is pulling it back into range
+        let saturated_high = output > 1.0 && error > 0.0;
+        let saturated_low = output < 0.0 && error < 0.0;
+        if !saturated_high && !saturated_low {
+            self.integral = integral;
+        }
+
+        output.clamp(0.0, 1.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PredictiveConfig {
+    /// How many seconds ahead to extrapolate a rising temperature
+    pub lookahead: f64,
+    /// Number of samples the rate of change is measured over
+    pub history: usize,
+    /// Fastest the predicted temperature may fall, in degrees per second
+    pub max_fall_rate: f64,
+}
+
+impl Default for PredictiveConfig {
+    fn default() -> PredictiveConfig {
+        PredictiveConfig {
+            lookahead: 5.0,
+            history: 10,
+            max_fall_rate: 0.5,
+        }
+    }
+}
+
+/// Feeds the curve with where the temperature is heading instead of where it
+/// is, so the fans spin up early and relax slowly
+pub struct Predictor {
+    config: PredictiveConfig,
+    history: VecDeque<(Instant, f64)>,
+    last_prediction: Option<(Instant, f64)>,
+}
+
+impl Predictor {
+    pub fn new(config: PredictiveConfig) -> Predictor {
+        Predictor {
+            config,
+            history: VecDeque::with_capacity(config.history),
+            last_prediction: None,
+        }
+    }
+
+    pub fn config(&self) -> PredictiveConfig {
+        self.config
+    }
+
+    pub fn update(&mut self, current_temp: f64) -> f64 {
+        self.update_at(current_temp, Instant::now())
+    }
+
+    /// `update` as of `now`
+    fn update_at(&mut self, current_temp: f64, now: Instant) -> f64 {
+        self.history.push_back((now, current_temp));
+        while self.history.len() > self.config.history.max(2) {
+            self.history.pop_front();
+        }
+
+        let (oldest_time, oldest_temp) = self.history[0];
+        let elapsed = now.duration_since(oldest_time).as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            (current_temp - oldest_temp) / elapsed
+        } else {
+            0.0
+        };
+
+        let mut prediction = current_temp + rate.max(0.0) * self.config.lookahead;
+        if let Some((last_time, last_prediction)) = self.last_prediction {
+            let floor = last_prediction
+                - self.config.max_fall_rate * now.duration_since(last_time).as_secs_f64();
+            prediction = prediction.max(floor);
+        }
+        self.last_prediction = Some((now, prediction));
+        prediction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Feeds `temps` to `update` one second apart from `start`, returning
+    /// the last output
+    fn run_pid(pid: &mut PidController, start: Instant, temps: &[f64]) -> f64 {
+        let mut output = f64::NAN;
+        for (i, temp) in temps.iter().enumerate() {
+            output = pid.update_at(*temp, start + Duration::from_secs(i as u64));
+        }
+        output
+    }
+
+    #[test]
+    fn pid_integral_stays_put_while_saturated_high() {
+        let mut pid = PidController::new(PidConfig::default());
+        let start = Instant::now();
+        assert_eq!(run_pid(&mut pid, start, &[105.0; 300]), 1.0);
+        assert_eq!(pid.integral, 0.0);
+        // Falls as soon as the temperature is below the target, instead of
+        // waiting for a wound up integral to drain
+        let later = start + Duration::from_secs(300);
+        assert_eq!(run_pid(&mut pid, later, &[80.0, 80.0]), 0.0);
+        assert_eq!(pid.integral, 0.0);
+        // And accumulates again once the output is back in range
+        let output = run_pid(&mut pid, later + Duration::from_secs(2), &[86.0, 86.0]);
+        assert!(output > 0.0 && output < 1.0);
+        assert!(pid.integral > 0.0);
+    }
+
+    #[test]
+    fn pid_integral_stays_put_while_saturated_low() {
+        let mut pid = PidController::new(PidConfig::default());
+        let start = Instant::now();
+        assert_eq!(run_pid(&mut pid, start, &[40.0; 300]), 0.0);
+        assert_eq!(pid.integral, 0.0);
+        let later = start + Duration::from_secs(300);
+        let output = run_pid(&mut pid, later, &[90.0, 90.0]);
+        assert!(output > 0.2, "output {} after the error turned", output);
+        assert!(pid.integral > 0.0);
+    }
+
+    #[test]
+    fn pid_integral_accumulates_in_range() {
+        let mut pid = PidController::new(PidConfig::default());
+        let output = run_pid(&mut pid, Instant::now(), &[90.0; 11]);
+        assert_eq!(pid.integral, 50.0);
+        assert!((output - (0.25 + 0.25)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn pid_derivative_follows_the_trend() {
+        let start = Instant::now();
+        let mut steady = PidController::new(PidConfig::default());
+        let steady = run_pid(&mut steady, start, &[86.0, 86.0]);
+        let mut rising = PidController::new(PidConfig::default());
+        let rising = run_pid(&mut rising, start, &[82.0, 86.0]);
+        let mut falling = PidController::new(PidConfig::default());
+        let falling = run_pid(&mut falling, start, &[90.0, 86.0]);
+        assert!(rising > steady && steady > falling);
+    }
+
+    /// Feeds `temps` to `update` one second apart from `start`, returning
+    /// every prediction
+    fn run_predictor(predictor: &mut Predictor, start: Instant, temps: &[f64]) -> Vec<f64> {
+        temps
+            .iter()
+            .enumerate()
+            .map(|(i, temp)| predictor.update_at(*temp, start + Duration::from_secs(i as u64)))
+            .collect()
+    }
+
+    #[test]
+    fn predictor_runs_ahead_of_a_rising_temperature() {
+        let mut predictor = Predictor::new(PredictiveConfig::default());
+        let temps = [50.0, 51.0, 52.0, 53.0, 54.0];
+        let predictions = run_predictor(&mut predictor, Instant::now(), &temps);
+        // One degree a second, five seconds ahead
+        assert_eq!(predictions[4], 59.0);
+        for (prediction, temp) in predictions.iter().zip(temps).skip(1) {
+            assert!(*prediction > temp);
+        }
+    }
+
+    #[test]
+    fn predictor_leaves_a_flat_temperature_alone() {
+        let mut predictor = Predictor::new(PredictiveConfig::default());
+        let predictions = run_predictor(&mut predictor, Instant::now(), &[60.0; 5]);
+        assert_eq!(predictions, [60.0; 5]);
+    }
+
+    #[test]
+    fn predictor_falls_no_faster_than_max_fall_rate() {
+        let mut predictor = Predictor::new(PredictiveConfig::default());
+        let predictions = run_predictor(&mut predictor, Instant::now(), &[60.0, 60.0, 40.0]);
+        assert_eq!(predictions[2], 59.5);
+    }
+}
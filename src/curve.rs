@@ -0,0 +1,414 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::Config;
+use crate::config::TemperatureUnit;
+use crate::expression::Expression;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Name of a curve implementation, used to select one from the config
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum FanCurve {
+    LINEAR,
+    POINTS,
+    EXPONENTIAL,
+    SIGMOID,
+    EXPRESSION,
+}
+
+impl FanCurve {
+    pub fn build(&self, config: &Config) -> Box<dyn SpeedCurve> {
+        match self {
+            FanCurve::LINEAR => Box::new(Linear {
+                min_temp: config.min_temp as f64,
+                max_temp: config.max_temp as f64,
+            }),
+            FanCurve::POINTS => Box::new(Points {
+                points: config.curve_points.clone(),
+            }),
+            FanCurve::EXPONENTIAL => Box::new(Exponential {
+                min_temp: config.min_temp as f64,
+                max_temp: config.max_temp as f64,
+                exponent: config.curve_exponent,
+            }),
+            FanCurve::SIGMOID => Box::new(Sigmoid {
+                midpoint: config.sigmoid_midpoint,
+                steepness: config.sigmoid_steepness,
+            }),
+            FanCurve::EXPRESSION => {
+                let source = config.curve_expression.as_deref().unwrap_or_default();
+                match Expression::parse(source) {
+                    Ok(expression) => Box::new(Custom {
+                        expression,
+                        unit: config.temperature_unit,
+                    }),
+                    // The expression is validated when loading the config, so
+                    // this is unreachable. Run the fans at full speed just in case
+                    Err(..) => Box::new(Points { points: Vec::new() }),
+                }
+            }
+        }
+    }
+}
+
+/// Built-in `FanCurve::POINTS` curves tuned for T2 MacBooks
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    QUIET,
+    BALANCED,
+    PERFORMANCE,
+}
+
+impl Preset {
+    pub fn from_name(name: &str) -> Option<Preset> {
+        match name {
+            "quiet" => Some(Preset::QUIET),
+            "balanced" => Some(Preset::BALANCED),
+            "performance" => Some(Preset::PERFORMANCE),
+            _ => None,
+        }
+    }
+
+    pub fn points(&self) -> Vec<CurvePoint> {
+        let points: &[(u32, u32)] = match self {
+            Preset::QUIET => &[(60, 0), (75, 10), (85, 40), (95, 100)],
+            Preset::BALANCED => &[(50, 0), (65, 20), (80, 60), (90, 100)],
+            Preset::PERFORMANCE => &[(40, 10), (55, 40), (70, 75), (80, 100)],
+        };
+        points
+            .iter()
+            .map(|&(temp, percent)| CurvePoint { temp, percent })
+            .collect()
+    }
+}
+
+/// Hardware speed range of a fan
+#[derive(Clone, Copy)]
+pub struct FanLimits {
+    pub min_speed: u32,
+    pub max_speed: u32,
+}
+
+impl FanLimits {
+    /// Maps `progress` through the speed range, 0.0 is `min_speed` and 1.0 is
+    /// `max_speed`. Values outside of that are clamped, and the result is
+    /// rounded to the nearest RPM
+    pub fn speed_at(&self, progress: f64) -> u32 {
+        let progress = if progress.is_nan() {
+            1.0 // A broken curve should never stop the fans
+        } else {
+            progress.clamp(0.0, 1.0)
+        };
+        let range = self.max_speed.saturating_sub(self.min_speed) as f64;
+        (range * progress).round() as u32 + self.min_speed
+    }
+}
+
+/// A fan speed, either in RPM or as a percentage of the fans speed range. In
+/// the config it is written as a number of RPM or a string like `"40%"`
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "SpeedRepr", into = "SpeedRepr")]
+pub enum Speed {
+    Rpm(u32),
+    Percent(f64),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SpeedRepr {
+    Rpm(u32),
+    Text(String),
+}
+
+impl TryFrom<SpeedRepr> for Speed {
+    type Error = String;
+
+    fn try_from(repr: SpeedRepr) -> Result<Speed, String> {
+        match repr {
+            SpeedRepr::Rpm(rpm) => Ok(Speed::Rpm(rpm)),
+            SpeedRepr::Text(text) => Speed::parse(&text),
+        }
+    }
+}
+
+impl From<Speed> for SpeedRepr {
+    fn from(speed: Speed) -> SpeedRepr {
+        match speed {
+            Speed::Rpm(rpm) => SpeedRepr::Rpm(rpm),
+            Speed::Percent(..) => SpeedRepr::Text(speed.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for Speed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Speed::Rpm(rpm) => write!(f, "{} RPM", rpm),
+            Speed::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+impl Speed {
+    /// Parses `"3000"` as RPM and `"40%"` as a percentage
+    pub fn parse(text: &str) -> Result<Speed, String> {
+        let text = text.trim();
+        match text.strip_suffix('%') {
+            Some(percent) => match percent.trim().parse::<f64>() {
+                Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(Speed::Percent(percent)),
+                _ => Err(format!(
+                    "\"{}\" is not a percentage between 0% and 100%",
+                    text
+                )),
+            },
+            None => text
+                .parse::<u32>()
+                .map(Speed::Rpm)
+                .map_err(|_| format!("\"{}\" is not a speed in RPM or a percentage", text)),
+        }
+    }
+
+    /// Converts the speed to RPM for a fan with the given limits
+    pub fn resolve(&self, fan: &FanLimits) -> u32 {
+        match self {
+            Speed::Rpm(rpm) => *rpm,
+            Speed::Percent(percent) => fan.speed_at(percent / 100.0),
+        }
+    }
+}
+
+pub trait SpeedCurve {
+    fn speed(&self, temp: f64, fan: &FanLimits) -> u32;
+}
+
+pub struct Linear {
+    pub min_temp: f64,
+    pub max_temp: f64,
+}
+
+impl SpeedCurve for Linear {
+    fn speed(&self, temp: f64, fan: &FanLimits) -> u32 {
+        fan.speed_at((temp - self.min_temp) / (self.max_temp - self.min_temp))
+    }
+}
+
+/// A breakpoint of a `FanCurve::POINTS` curve, `percent` is the share of the
+/// fans speed range to use at `temp`
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct CurvePoint {
+    pub temp: u32,
+    pub percent: u32,
+}
+
+pub struct Points {
+    pub points: Vec<CurvePoint>,
+}
+
+impl SpeedCurve for Points {
+    fn speed(&self, temp: f64, fan: &FanLimits) -> u32 {
+        fan.speed_at(self.percent_at(temp) / 100.0)
+    }
+}
+
+impl Points {
+    /// Linearly interpolates the speed percentage between the two points around
+    /// `temp`, holding the first and last points flat outside of the curve
+    fn percent_at(&self, temp: f64) -> f64 {
+        let (first, last) = match (self.points.first(), self.points.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return 100.0, // Without a curve the only safe speed is full speed
+        };
+        if temp <= first.temp as f64 {
+            return first.percent as f64;
+        }
+        if temp >= last.temp as f64 {
+            return last.percent as f64;
+        }
+        for pair in self.points.windows(2) {
+            let (low, high) = (pair[0], pair[1]);
+            if temp >= low.temp as f64 && temp <= high.temp as f64 {
+                if high.temp == low.temp {
+                    return high.percent as f64;
+                }
+                let progress = (temp - low.temp as f64) / (high.temp - low.temp) as f64;
+                return low.percent as f64 + (high.percent as f64 - low.percent as f64) * progress;
+            }
+        }
+        last.percent as f64
+    }
+}
+
+pub struct Exponential {
+    pub min_temp: f64,
+    pub max_temp: f64,
+    pub exponent: f64,
+}
+
+impl SpeedCurve for Exponential {
+    fn speed(&self, temp: f64, fan: &FanLimits) -> u32 {
+        let progress = (temp - self.min_temp) / (self.max_temp - self.min_temp);
+        fan.speed_at(progress.clamp(0.0, 1.0).powf(self.exponent))
+    }
+}
+
+pub struct Sigmoid {
+    pub midpoint: f64,
+    pub steepness: f64,
+}
+
+impl SpeedCurve for Sigmoid {
+    fn speed(&self, temp: f64, fan: &FanLimits) -> u32 {
+        let exponent = -self.steepness * (temp - self.midpoint);
+        fan.speed_at(1.0 / (1.0 + exponent.exp()))
+    }
+}
+
+/// Variables available to a `FanCurve::EXPRESSION` curve
+pub const EXPRESSION_VARIABLES: [&str; 3] = ["t", "min", "max"];
+
+/// A user supplied expression evaluating to the fan speed in RPM
+pub struct Custom {
+    pub expression: Expression,
+    /// Unit `t` is given to the expression in
+    pub unit: TemperatureUnit,
+}
+
+impl SpeedCurve for Custom {
+    fn speed(&self, temp: f64, fan: &FanLimits) -> u32 {
+        let speed = self.expression.eval(&|name| match name {
+            "t" => Some(self.unit.celsius_to(temp)),
+            "min" => Some(fan.min_speed as f64),
+            "max" => Some(fan.max_speed as f64),
+            _ => None,
+        });
+        fan.speed_at((speed - fan.min_speed as f64) / (fan.max_speed as f64 - fan.min_speed as f64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAN: FanLimits = FanLimits {
+        min_speed: 1000,
+        max_speed: 5000,
+    };
+
+    fn points() -> Points {
+        Points {
+            points: vec![
+                CurvePoint {
+                    temp: 40,
+                    percent: 0,
+                },
+                CurvePoint {
+                    temp: 60,
+                    percent: 50,
+                },
+                CurvePoint {
+                    temp: 80,
+                    percent: 100,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn speed_at_clamps_and_rounds() {
+        assert_eq!(FAN.speed_at(-0.5), 1000);
+        assert_eq!(FAN.speed_at(0.0), 1000);
+        assert_eq!(FAN.speed_at(0.5), 3000);
+        assert_eq!(FAN.speed_at(1.0), 5000);
+        assert_eq!(FAN.speed_at(1.5), 5000);
+        assert_eq!(FAN.speed_at(0.00012), 1000);
+        assert_eq!(FAN.speed_at(0.00013), 1001);
+        assert_eq!(FAN.speed_at(f64::NAN), 5000);
+    }
+
+    #[test]
+    fn linear() {
+        let curve = Linear {
+            min_temp: 40.0,
+            max_temp: 80.0,
+        };
+        assert_eq!(curve.speed(20.0, &FAN), 1000);
+        assert_eq!(curve.speed(40.0, &FAN), 1000);
+        assert_eq!(curve.speed(60.0, &FAN), 3000);
+        assert_eq!(curve.speed(80.0, &FAN), 5000);
+        assert_eq!(curve.speed(100.0, &FAN), 5000);
+    }
+
+    #[test]
+    fn points_interpolate() {
+        let curve = points();
+        assert_eq!(curve.speed(30.0, &FAN), 1000);
+        assert_eq!(curve.speed(40.0, &FAN), 1000);
+        assert_eq!(curve.speed(50.0, &FAN), 2000);
+        assert_eq!(curve.speed(60.0, &FAN), 3000);
+        assert_eq!(curve.speed(70.0, &FAN), 4000);
+        assert_eq!(curve.speed(80.0, &FAN), 5000);
+        assert_eq!(curve.speed(90.0, &FAN), 5000);
+    }
+
+    #[test]
+    fn points_without_a_curve_run_at_full_speed() {
+        let curve = Points { points: Vec::new() };
+        assert_eq!(curve.speed(20.0, &FAN), 5000);
+    }
+
+    #[test]
+    fn exponential() {
+        let curve = Exponential {
+            min_temp: 40.0,
+            max_temp: 80.0,
+            exponent: 2.0,
+        };
+        assert_eq!(curve.speed(30.0, &FAN), 1000);
+        assert_eq!(curve.speed(40.0, &FAN), 1000);
+        assert_eq!(curve.speed(60.0, &FAN), 2000);
+        assert_eq!(curve.speed(80.0, &FAN), 5000);
+        assert_eq!(curve.speed(90.0, &FAN), 5000);
+    }
+
+    #[test]
+    fn sigmoid() {
+        let curve = Sigmoid {
+            midpoint: 60.0,
+            steepness: 1.0,
+        };
+        assert_eq!(curve.speed(0.0, &FAN), 1000);
+        assert_eq!(curve.speed(60.0, &FAN), 3000);
+        assert_eq!(curve.speed(120.0, &FAN), 5000);
+    }
+
+    #[test]
+    fn expression() {
+        let curve = Custom {
+            expression: Expression::parse("min + (max - min) * (t - 40) / 40").unwrap(),
+            unit: TemperatureUnit::CELSIUS,
+        };
+        assert_eq!(curve.speed(20.0, &FAN), 1000);
+        assert_eq!(curve.speed(40.0, &FAN), 1000);
+        assert_eq!(curve.speed(60.0, &FAN), 3000);
+        assert_eq!(curve.speed(80.0, &FAN), 5000);
+        assert_eq!(curve.speed(100.0, &FAN), 5000);
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Optional D-Bus interface, `org.t2macd.Daemon` on the system bus. `Boost`
+//! runs every fan at full speed like `t2macd boost`. It sends
+//! `FanNotResponding` when a fan stops reaching the speed it is set to, and
+//! `FanResponding` once it does again. Without the "dbus" feature, or without
+//! a system bus, the daemon runs the same without it.
+
+#[cfg(feature = "dbus")]
+use crate::curve::Speed;
+#[cfg(feature = "dbus")]
+use crate::manual;
+#[cfg(feature = "dbus")]
+use tracing::warn;
+
+/// Bus name and interface of the daemon
+#[cfg(feature = "dbus")]
+const NAME: &str = "org.t2macd.Daemon";
+
+/// Object the interface is served at
+#[cfg(feature = "dbus")]
+const PATH: &str = "/org/t2macd/Daemon";
+
+/// Policy that lets the daemon own `NAME` on the system bus
+#[cfg(feature = "dbus")]
+pub const POLICY_PATH: &str = "/etc/dbus-1/system.d/org.t2macd.Daemon.conf";
+
+#[cfg(feature = "dbus")]
+pub const POLICY: &str = r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-BUS Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<!-- Written by `t2macd install` -->
+<busconfig>
+  <policy user="root">
+    <allow own="org.t2macd.Daemon"/>
+  </policy>
+  <policy context="default">
+    <allow send_destination="org.t2macd.Daemon"
+           send_interface="org.freedesktop.DBus.Introspectable"/>
+    <allow send_destination="org.t2macd.Daemon"
+           send_interface="org.freedesktop.DBus.Peer"/>
+    <!-- Boost only ever makes the fans louder, anyone may ask for it -->
+    <allow send_destination="org.t2macd.Daemon"
+           send_interface="org.t2macd.Daemon" send_member="Boost"/>
+  </policy>
+</busconfig>
+"#;
+
+#[cfg(feature = "dbus")]
+struct Daemon;
+
+#[cfg(feature = "dbus")]
+#[zbus::interface(name = "org.t2macd.Daemon")]
+impl Daemon {
+    /// Runs every fan at full speed for `seconds`, until `t2macd set all auto`
+    fn boost(&self, seconds: u64) -> zbus::fdo::Result<()> {
+        if seconds == 0 {
+            return Err(zbus::fdo::Error::InvalidArgs(String::from(
+                "The boost needs to last at least a second",
+            )));
+        }
+        let duration = std::time::Duration::from_secs(seconds);
+        manual::pin(&[], None, Some(Speed::Percent(100.0)), duration).map_err(|error| {
+            zbus::fdo::Error::Failed(format!("Failed to boost the fans: {}", error))
+        })
+    }
+
+    /// A fan is at `speed` RPM instead of the `target` it is set to
+    #[zbus(signal)]
+    async fn fan_not_responding(
+        emitter: &zbus::object_server::SignalEmitter<'_>,
+        fan: &str,
+        target: u32,
+        speed: u32,
+    ) -> zbus::Result<()>;
+
+    /// A fan reported by `FanNotResponding` reaches its target again
+    #[zbus(signal)]
+    async fn fan_responding(
+        emitter: &zbus::object_server::SignalEmitter<'_>,
+        fan: &str,
+    ) -> zbus::Result<()>;
+}
+
+#[cfg(feature = "dbus")]
+pub struct Bus {
+    connection: zbus::blocking::Connection,
+}
+
+#[cfg(feature = "dbus")]
+impl Bus {
+    /// Serves the interface on the system bus, `None` when that fails
+    pub fn start() -> Option<Bus> {
+        let connection = zbus::blocking::connection::Builder::system()
+            .and_then(|builder| builder.name(NAME))
+            .and_then(|builder| builder.serve_at(PATH, Daemon))
+            .and_then(|builder| builder.build());
+        match connection {
+            Ok(connection) => Some(Bus { connection }),
+            Err(error) => {
+                warn!("Failed to connect to D-Bus, running without it: {}", error);
+                None
+            }
+        }
+    }
+
+    pub fn fan_not_responding(&self, fan: &str, target: u32, speed: u32) {
+        self.emit("FanNotResponding", &(fan, target, speed));
+    }
+
+    pub fn fan_responding(&self, fan: &str) {
+        self.emit("FanResponding", &(fan,));
+    }
+
+    /// Sends the signal `name`, failures are ignored like those of
+    /// `notify::notify`
+    fn emit<B: serde::Serialize + zbus::zvariant::DynamicType>(&self, name: &str, body: &B) {
+        let _ = self
+            .connection
+            .emit_signal(None::<zbus::names::BusName>, PATH, NAME, name, body);
+    }
+}
+
+#[cfg(not(feature = "dbus"))]
+pub struct Bus;
+
+#[cfg(not(feature = "dbus"))]
+impl Bus {
+    pub fn start() -> Option<Bus> {
+        None
+    }
+
+    pub fn fan_not_responding(&self, _fan: &str, _target: u32, _speed: u32) {}
+
+    pub fn fan_responding(&self, _fan: &str) {}
+}
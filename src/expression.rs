@@ -0,0 +1,397 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A small arithmetic expression language for custom curves. Expressions
+//! support numbers, variables, `+ - * / ^`, parentheses and the functions
+//! `min`, `max` and `abs`. Nothing else can be expressed, so evaluating a
+//! user supplied expression is always safe.
+
+#[derive(Clone, Copy)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+}
+
+enum Node {
+    Number(f64),
+    Variable(String),
+    Negate(Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>),
+    Call(String, Vec<Node>),
+}
+
+pub struct Expression {
+    root: Node,
+}
+
+#[derive(Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Identifier(String),
+    Symbol(char),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !c.is_ascii_digit() && c != '.' {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let number = &source[start..end];
+            match number.parse::<f64>() {
+                Ok(number) => tokens.push(Token::Number(number)),
+                Err(..) => return Err(format!("invalid number \"{}\"", number)),
+            }
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !c.is_ascii_alphanumeric() && c != '_' {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token::Identifier(source[start..end].to_string()));
+        } else if "+-*/^(),".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else {
+            return Err(format!("unexpected character '{}' at {}", c, start));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Deepest an expression can nest, counting parentheses, calls, signs,
+/// powers and chained operators, so parsing and evaluating it can never run
+/// out of stack
+const MAX_DEPTH: usize = 100;
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    /// How deep the node being parsed sits in the expression
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// Goes one level deeper, failing past `MAX_DEPTH`
+    fn nest(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(format!("nested deeper than {} levels", MAX_DEPTH));
+        }
+        Ok(())
+    }
+
+    fn eat(&mut self, symbol: char) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    // sum := product (('+' | '-') product)*
+    fn sum(&mut self) -> Result<Node, String> {
+        let depth = self.depth;
+        let mut node = self.product()?;
+        loop {
+            let operator = if self.eat('+') {
+                Operator::Add
+            } else if self.eat('-') {
+                Operator::Subtract
+            } else {
+                self.depth = depth;
+                return Ok(node);
+            };
+            // Every operator puts the terms before it one level deeper
+            self.nest()?;
+            node = Node::Binary(operator, Box::new(node), Box::new(self.product()?));
+        }
+    }
+
+    // product := unary (('*' | '/') unary)*
+    fn product(&mut self) -> Result<Node, String> {
+        let depth = self.depth;
+        let mut node = self.unary()?;
+        loop {
+            let operator = if self.eat('*') {
+                Operator::Multiply
+            } else if self.eat('/') {
+                Operator::Divide
+            } else {
+                self.depth = depth;
+                return Ok(node);
+            };
+            self.nest()?;
+            node = Node::Binary(operator, Box::new(node), Box::new(self.unary()?));
+        }
+    }
+
+    // unary := '-' unary | power
+    fn unary(&mut self) -> Result<Node, String> {
+        // Every nesting passes through here
+        let depth = self.depth;
+        self.nest()?;
+        let node = if self.eat('-') {
+            Node::Negate(Box::new(self.unary()?))
+        } else {
+            self.power()?
+        };
+        self.depth = depth;
+        Ok(node)
+    }
+
+    // power := atom ('^' unary)?, right associative
+    fn power(&mut self) -> Result<Node, String> {
+        let base = self.atom()?;
+        if self.eat('^') {
+            return Ok(Node::Binary(
+                Operator::Power,
+                Box::new(base),
+                Box::new(self.unary()?),
+            ));
+        }
+        Ok(base)
+    }
+
+    // atom := number | identifier | identifier '(' args ')' | '(' sum ')'
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Node::Number(number)),
+            Some(Token::Identifier(name)) => {
+                if !self.eat('(') {
+                    return Ok(Node::Variable(name));
+                }
+                let mut args = Vec::new();
+                if !self.eat(')') {
+                    loop {
+                        args.push(self.sum()?);
+                        if self.eat(')') {
+                            break;
+                        }
+                        if !self.eat(',') {
+                            return Err(format!("expected ',' or ')' in call to {}", name));
+                        }
+                    }
+                }
+                match (name.as_str(), args.len()) {
+                    ("min" | "max", 1..) | ("abs", 1) => Ok(Node::Call(name, args)),
+                    ("min" | "max" | "abs", _) => {
+                        Err(format!("wrong number of arguments to {}", name))
+                    }
+                    _ => Err(format!("unknown function {}", name)),
+                }
+            }
+            Some(Token::Symbol('(')) => {
+                let node = self.sum()?;
+                if !self.eat(')') {
+                    return Err(String::from("missing ')'"));
+                }
+                Ok(node)
+            }
+            Some(Token::Symbol(c)) => Err(format!("unexpected '{}'", c)),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+            depth: 0,
+        };
+        let root = parser.sum()?;
+        if let Some(token) = parser.peek() {
+            return Err(match token {
+                Token::Number(number) => format!("unexpected number {}", number),
+                Token::Identifier(name) => format!("unexpected name {}", name),
+                Token::Symbol(c) => format!("unexpected '{}'", c),
+            });
+        }
+        Ok(Expression { root })
+    }
+
+    /// Names of every variable used by the expression
+    pub fn variables(&self) -> Vec<&str> {
+        fn walk<'a>(node: &'a Node, variables: &mut Vec<&'a str>) {
+            match node {
+                Node::Number(..) => {}
+                Node::Variable(name) => variables.push(name),
+                Node::Negate(node) => walk(node, variables),
+                Node::Binary(_, left, right) => {
+                    walk(left, variables);
+                    walk(right, variables);
+                }
+                Node::Call(_, args) => args.iter().for_each(|arg| walk(arg, variables)),
+            }
+        }
+        let mut variables = Vec::new();
+        walk(&self.root, &mut variables);
+        variables
+    }
+
+    /// Evaluates the expression, looking up variables with `lookup`. Unknown
+    /// variables evaluate to NaN
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<f64>) -> f64 {
+        fn eval(node: &Node, lookup: &dyn Fn(&str) -> Option<f64>) -> f64 {
+            match node {
+                Node::Number(number) => *number,
+                Node::Variable(name) => lookup(name).unwrap_or(f64::NAN),
+                Node::Negate(node) => -eval(node, lookup),
+                Node::Binary(operator, left, right) => {
+                    let (left, right) = (eval(left, lookup), eval(right, lookup));
+                    match operator {
+                        Operator::Add => left + right,
+                        Operator::Subtract => left - right,
+                        Operator::Multiply => left * right,
+                        Operator::Divide => left / right,
+                        Operator::Power => left.powf(right),
+                    }
+                }
+                Node::Call(name, args) => {
+                    let mut values = args.iter().map(|arg| eval(arg, lookup));
+                    match name.as_str() {
+                        "min" => values.fold(f64::INFINITY, f64::min),
+                        "max" => values.fold(f64::NEG_INFINITY, f64::max),
+                        _ => values.next().unwrap_or(f64::NAN).abs(),
+                    }
+                }
+            }
+        }
+        eval(&self.root, lookup)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `source` evaluated with `t` at 2
+    fn eval(source: &str) -> f64 {
+        let expression = Expression::parse(source).unwrap_or_else(|error| panic!("{}", error));
+        expression.eval(&|name| (name == "t").then_some(2.0))
+    }
+
+    fn error(source: &str) -> String {
+        match Expression::parse(source) {
+            Ok(..) => panic!("{} parsed", source),
+            Err(error) => error,
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), 7.0);
+        assert_eq!(eval("(1 + 2) * 3"), 9.0);
+        assert_eq!(eval("10 - 4 - 3"), 3.0);
+        assert_eq!(eval("12 / 2 / 3"), 2.0);
+        assert_eq!(eval("2 * 3 ^ 2"), 18.0);
+        assert_eq!(eval("1 + t * 4"), 9.0);
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert_eq!(eval("-3"), -3.0);
+        assert_eq!(eval("--3"), 3.0);
+        assert_eq!(eval("2 - -3"), 5.0);
+        assert_eq!(eval("-2 ^ 2"), -4.0);
+        assert_eq!(eval("2 ^ -1"), 0.5);
+        assert_eq!(eval("-t * 3"), -6.0);
+    }
+
+    #[test]
+    fn power_is_right_associative() {
+        assert_eq!(eval("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(eval("(2 ^ 3) ^ 2"), 64.0);
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(eval("min(3, t, 5)"), 2.0);
+        assert_eq!(eval("max(3, t, 5)"), 5.0);
+        assert_eq!(eval("max(t)"), 2.0);
+        assert_eq!(eval("abs(1 - 4)"), 3.0);
+        assert!(eval("unknown + 1").is_nan());
+    }
+
+    #[test]
+    fn function_arity() {
+        assert_eq!(error("min()"), "wrong number of arguments to min");
+        assert_eq!(error("max()"), "wrong number of arguments to max");
+        assert_eq!(error("abs()"), "wrong number of arguments to abs");
+        assert_eq!(error("abs(1, 2)"), "wrong number of arguments to abs");
+        assert_eq!(error("min(1 2)"), "expected ',' or ')' in call to min");
+    }
+
+    #[test]
+    fn unknown_functions() {
+        assert_eq!(error("sqrt(4)"), "unknown function sqrt");
+    }
+
+    #[test]
+    fn trailing_input() {
+        assert_eq!(error("1 2"), "unexpected number 2");
+        assert_eq!(error("t x"), "unexpected name x");
+        assert_eq!(error("(1))"), "unexpected ')'");
+        assert_eq!(error("1 +"), "unexpected end of expression");
+        assert_eq!(error("(1"), "missing ')'");
+        assert_eq!(error("1 % 2"), "unexpected character '%' at 2");
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let deep = |open: &str, close: &str, levels: usize| {
+            format!("{}1{}", open.repeat(levels), close.repeat(levels))
+        };
+        assert_eq!(eval(&deep("(", ")", 20)), 1.0);
+        assert_eq!(eval(&deep("abs(", ")", 20)), 1.0);
+        assert_eq!(eval(&format!("{}1", "-".repeat(20))), 1.0);
+        let too_deep = "nested deeper than 100 levels";
+        assert_eq!(error(&deep("(", ")", 10000)), too_deep);
+        assert_eq!(error(&deep("max(", ")", 10000)), too_deep);
+        assert_eq!(error(&format!("{}1", "-".repeat(10000))), too_deep);
+        assert_eq!(error(&format!("1{}", " ^ 1".repeat(10000))), too_deep);
+        assert_eq!(error(&format!("1{}", " + 1".repeat(10000))), too_deep);
+        assert_eq!(error(&format!("1{}", " * 1".repeat(10000))), too_deep);
+    }
+}
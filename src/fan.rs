@@ -0,0 +1,980 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::backend;
+use crate::backend::Backend;
+use crate::config::Config;
+use crate::config::ControlMode;
+use crate::config::ExitAction;
+use crate::controller::PidController;
+use crate::controller::Predictor;
+use crate::curve::FanLimits;
+use crate::curve::Speed;
+use crate::curve::SpeedCurve;
+use crate::history::FanStatus;
+use crate::sysfs;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::time::Instant;
+use tracing::debug;
+use tracing::error;
+use tracing::info;
+
+/// How long a fan can stay away from the speed written before it is
+/// reported, long enough to spin up over its whole range
+pub const RESPONSE_TIME: Duration = Duration::from_secs(10);
+
+/// Share of the hardware top speed a fan can be off its target by
+const RESPONSE_TOLERANCE: f64 = 0.1;
+
+/// Set by `--dry-run`, every write to the fans is skipped
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Skips every write to the fans from now on, for `--dry-run`
+pub fn set_dry_run() {
+    DRY_RUN.store(true, Ordering::Relaxed);
+}
+
+/// Whether `--dry-run` was passed
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Fans under our control and what to leave them at, kept where `hand_back`
+/// can reach them from a panic hook
+static CONTROLLED: Mutex<Controlled> = Mutex::new(Controlled {
+    fans: Vec::new(),
+    exit_action: ExitAction::AUTO,
+    exit_speed: Speed::Percent(100.0),
+});
+
+struct Controlled {
+    /// Backend, path and hardware speed range of every fan
+    fans: Vec<(&'static dyn Backend, PathBuf, FanLimits)>,
+    exit_action: ExitAction,
+    exit_speed: Speed,
+}
+
+pub struct Fan {
+    pub backend: &'static dyn Backend,
+    pub path: PathBuf,
+    pub index: u32,
+    pub label: Option<String>,
+    /// Speed range used by the curve, with the configured minimum applied
+    pub limits: FanLimits,
+    /// Speed range reported by the hardware
+    hardware_limits: FanLimits,
+    pub speed_curve: Box<dyn SpeedCurve>,
+    /// Curve being faded out after a switch, and when the switch happened
+    previous_curve: Option<(Box<dyn SpeedCurve>, Instant)>,
+    pid: Option<PidController>,
+    predictor: Option<Predictor>,
+    /// Temperature the current speed was calculated from
+    curve_temp: Option<f64>,
+    /// Whether the fan has been stopped by `zero_rpm`
+    idle: bool,
+    noise_cap: Option<u32>,
+    /// Percentage of the speed range added to the curve output
+    speed_offset: f64,
+    /// RPM added to the curve output, after `speed_offset`
+    offset_rpm: i32,
+    /// Factor the curve output is multiplied by before the offsets
+    scale: f64,
+    /// Sensors the fan follows instead of every sensor, see `group_temp`
+    pub sensors: Vec<String>,
+    /// Last speed written, and the temperature it was written at
+    last_written: Option<(u32, f64)>,
+    /// Last speed let through by the ramp, and when
+    ramped: Option<(u32, Instant)>,
+    /// When the current transition ends and when it last stepped, see
+    /// `transition_time`
+    transition: Option<(Instant, Instant)>,
+    /// Output kept open once the first speed is written, so setting the
+    /// speed is a single `pwrite`
+    output: OnceLock<Option<File>>,
+    /// Input kept open, so reading the speed is a single `pread`
+    input: Option<File>,
+    /// `Backend::mode` file kept open, read on every check for lost control
+    mode: Option<File>,
+    /// Speed read back on the last check
+    speed: Option<u32>,
+    /// Since when the speed read back has been off the last speed written
+    off_target_since: Option<Instant>,
+    /// Whether it has been off for longer than `RESPONSE_TIME`
+    unresponsive: bool,
+    /// Since when the fan has reported 0 RPM while told to spin
+    stopped_since: Option<Instant>,
+    /// Whether it has been stopped for longer than `fan_failure.time`
+    pub failed: bool,
+    /// Seconds spent in each of `DUTY_BANDS` since the daemon started
+    duty: [f64; DUTY_BANDS.len()],
+    /// When the time in `duty` was last counted
+    duty_counted: Option<Instant>,
+}
+
+/// How a fan started or stopped following the speed written to it
+pub enum Response {
+    /// It has been away from `target` for `RESPONSE_TIME`, at `speed`
+    Lost {
+        target: u32,
+        speed: u32,
+    },
+    Recovered,
+}
+
+impl Fan {
+    pub fn new(
+        backend: &'static dyn Backend,
+        path: PathBuf,
+        config: &Config,
+    ) -> Result<Fan, std::io::Error> {
+        let index = backend.index(&path);
+        let label = backend.label(&path);
+        let hardware_limits = backend.limits(&path)?;
+        let speed_curve = config.for_fan(index, label.as_deref()).speed_curve();
+        let input = File::open(backend.input(&path)).ok();
+        let mode = backend.mode(&path).and_then(|mode| File::open(mode).ok());
+        let mut fan = Fan {
+            backend,
+            limits: hardware_limits,
+            hardware_limits,
+            path,
+            index,
+            label,
+            speed_curve,
+            previous_curve: None,
+            pid: None,
+            predictor: None,
+            curve_temp: None,
+            idle: false,
+            noise_cap: None,
+            speed_offset: 0.0,
+            offset_rpm: 0,
+            scale: 1.0,
+            sensors: Vec::new(),
+            last_written: None,
+            ramped: None,
+            transition: None,
+            output: OnceLock::new(),
+            input,
+            mode,
+            speed: None,
+            off_target_since: None,
+            unresponsive: false,
+            stopped_since: None,
+            failed: false,
+            duty: [0.0; DUTY_BANDS.len()],
+            duty_counted: None,
+        };
+        let errors = fan.validate(config);
+        if !errors.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                errors.join(", "),
+            ));
+        }
+        fan.configure(config);
+        Ok(fan)
+    }
+
+    /// Checks the config against the limits of this fan
+    pub fn validate(&self, config: &Config) -> Vec<String> {
+        config.validate_fan(self.index, self.label.as_deref(), &self.hardware_limits)
+    }
+
+    /// Switches the fan to a new config at runtime, fading over from the old
+    /// curve for `curve_blend_time` seconds. The config must have passed
+    /// `validate` first
+    pub fn apply_config(&mut self, config: &Config) {
+        self.configure(config);
+        let fan_config = config.for_fan(self.index, self.label.as_deref());
+        let previous = std::mem::replace(&mut self.speed_curve, fan_config.speed_curve());
+        self.previous_curve = Some((previous, Instant::now()));
+    }
+
+    /// Derives the settings of this fan other than its curve from `config`,
+    /// keeping controller state when its settings are unchanged
+    fn configure(&mut self, config: &Config) {
+        let (index, label) = (self.index, self.label.as_deref());
+        let hardware_limits = &self.hardware_limits;
+        self.noise_cap = config.noise_cap_for_fan(index, label, hardware_limits);
+        self.speed_offset = config.speed_offset_for_fan(index, label);
+        self.offset_rpm = config.offset_rpm_for_fan(index, label);
+        self.scale = config.scale_for_fan(index, label);
+        self.sensors = config.sensors_for_fan(index, label);
+        self.limits = *hardware_limits;
+        if let Some(min_speed) = config.min_speed_for_fan(index, label, hardware_limits) {
+            self.limits.min_speed = min_speed.max(hardware_limits.min_speed);
+        }
+        self.pid = match (config.control_mode, self.pid.take()) {
+            (ControlMode::PID, Some(pid)) if pid.gains() == config.pid => Some(pid),
+            (ControlMode::PID, _) => Some(PidController::new(config.pid)),
+            _ => None,
+        };
+        self.predictor = match (config.control_mode, self.predictor.take()) {
+            (ControlMode::PREDICTIVE, Some(predictor))
+                if predictor.config() == config.predictive =>
+            {
+                Some(predictor)
+            }
+            (ControlMode::PREDICTIVE, _) => Some(Predictor::new(config.predictive)),
+            _ => None,
+        };
+    }
+
+    /// Takes the fan over from the firmware so the speeds written are applied
+    pub fn take_control(&self) -> Result<(), std::io::Error> {
+        if dry_run() {
+            return Ok(());
+        }
+        self.backend.set_manual(&self.path, true)
+    }
+
+    /// Whether the firmware has taken the fan back, as the SMC does on resume
+    pub fn lost_control(&self) -> bool {
+        if dry_run() {
+            return false;
+        }
+        self.backend
+            .mode(&self.path)
+            .and_then(|path| sysfs::read_input(&path, self.mode.as_ref()).ok())
+            .is_some_and(|mode| !self.backend.is_manual(&mode))
+    }
+
+    /// Takes control again and writes the last speed, which the firmware
+    /// dropped when it took the fan back
+    pub fn reassert_control(&self) -> Result<(), std::io::Error> {
+        self.take_control()?;
+        match self.last_written {
+            Some((speed, _)) => self.set_speed(speed),
+            None => Ok(()),
+        }
+    }
+
+    /// Hands the fan back to the firmwares automatic control
+    pub fn release_control(&self) -> Result<(), std::io::Error> {
+        if dry_run() {
+            return Ok(());
+        }
+        self.backend.set_manual(&self.path, false)
+    }
+
+    /// Name of the fan for messages, like `fan1 (Left side)`
+    pub fn name(&self) -> String {
+        display_name(self.index, self.label.as_deref())
+    }
+
+    /// Speed the fan is running at
+    pub fn read_speed(&self) -> Result<u32, std::io::Error> {
+        sysfs::read_input(&self.backend.input(&self.path), self.input.as_ref())?
+            .trim()
+            .parse::<u32>()
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    /// Whether `speed` read back is close enough to `target`, within
+    /// `RESPONSE_TOLERANCE`. A fan read back in other units than it is set
+    /// in only has to spin when told to, and stop when told to
+    pub fn tracks(&self, target: u32, speed: u32) -> bool {
+        if !self.backend.reads_back_output() {
+            return (target == 0) == (speed == 0);
+        }
+        let tolerance = (self.hardware_limits.max_speed as f64 * RESPONSE_TOLERANCE) as u32;
+        speed.abs_diff(target) <= tolerance
+    }
+
+    /// Speed range reported by the hardware, without the configured minimum
+    pub fn hardware_limits(&self) -> &FanLimits {
+        &self.hardware_limits
+    }
+
+    /// Reads the speed back and compares it with the last one written,
+    /// returning a change when the fan stops or starts following it
+    pub fn check_response(&mut self) -> Option<Response> {
+        self.speed = self.read_speed().ok();
+        self.count_duty();
+        // The firmware drives the fan during a dry run, not the speed written
+        if dry_run() {
+            return None;
+        }
+        let (target, speed) = match (self.last_written, self.speed) {
+            (Some((target, _)), Some(speed)) => (target, speed),
+            _ => return None,
+        };
+        if self.tracks(target, speed) {
+            self.off_target_since = None;
+            return std::mem::take(&mut self.unresponsive).then_some(Response::Recovered);
+        }
+        let since = *self.off_target_since.get_or_insert_with(Instant::now);
+        if self.unresponsive || since.elapsed() < RESPONSE_TIME {
+            return None;
+        }
+        self.unresponsive = true;
+        Some(Response::Lost { target, speed })
+    }
+
+    /// Marks the fan failed once it has reported 0 RPM for `fan_failure.time`
+    /// seconds while told to spin, or working again once it spins. Uses the
+    /// speed read by `check_response`, and returns whether `failed` changed
+    pub fn check_failure(&mut self, config: &Config) -> bool {
+        let stopped = !dry_run()
+            && matches!(
+                (self.last_written, self.speed),
+                (Some((target, _)), Some(0)) if target > 0
+            );
+        if !stopped {
+            self.stopped_since = None;
+            return std::mem::take(&mut self.failed);
+        }
+        let since = *self.stopped_since.get_or_insert_with(Instant::now);
+        if self.failed || since.elapsed().as_secs_f64() < config.fan_failure.time {
+            return false;
+        }
+        self.failed = true;
+        true
+    }
+
+    /// Adds the time since the last count to the band of the speed just
+    /// read back
+    fn count_duty(&mut self) {
+        let now = Instant::now();
+        let counted = self.duty_counted.replace(now);
+        // In the units of the hardware range, the speed written otherwise
+        let speed = if self.backend.reads_back_output() {
+            self.speed
+        } else {
+            self.last_written.map(|(speed, _)| speed)
+        };
+        let (Some(counted), Some(speed)) = (counted, speed) else {
+            return;
+        };
+        let band = match speed {
+            0 => 0,
+            speed => {
+                let range = self
+                    .hardware_limits
+                    .max_speed
+                    .saturating_sub(self.hardware_limits.min_speed);
+                let above = speed.saturating_sub(self.hardware_limits.min_speed);
+                1 + (above * 4 / range.max(1)).min(3) as usize
+            }
+        };
+        self.duty[band] += now.duration_since(counted).as_secs_f64();
+    }
+
+    /// The state of the fan for `t2macd status`
+    pub fn status(&self) -> FanStatus {
+        FanStatus {
+            index: self.index,
+            label: self.label.clone(),
+            target: self.last_written.map(|(speed, _)| speed),
+            speed: self.speed,
+            unresponsive: self.unresponsive,
+            failed: self.failed,
+            duty: self.duty,
+        }
+    }
+
+    /// Writes `speed`, clamped to the hardware range so no curve, script or
+    /// command can ask for more or less than the fan allows. Zero is kept, it
+    /// stops the fan for `zero_rpm`
+    pub fn set_speed(&self, speed: u32) -> Result<(), std::io::Error> {
+        let speed = match speed {
+            0 => 0,
+            // Not `clamp`, which panics on a fan reporting min above max
+            speed => speed
+                .max(self.hardware_limits.min_speed)
+                .min(self.hardware_limits.max_speed),
+        };
+        if dry_run() {
+            return Ok(());
+        }
+        let output = self.output.get_or_init(|| {
+            OpenOptions::new()
+                .write(true)
+                .open(self.backend.output(&self.path))
+                .ok()
+        });
+        match output {
+            // The newline ends the value when a shorter one is written over
+            // a regular file, as with `--simulate`
+            Some(output) => output
+                .write_at(format!("{}\n", speed).as_bytes(), 0)
+                .map(drop),
+            None => fs::write(self.backend.output(&self.path), speed.to_string()),
+        }
+    }
+
+    /// Writes `speed`, raised to the configured minimum and moving towards it
+    /// no faster than the configured ramp allows, unless it falls within the
+    /// configured dead band
+    pub fn apply_speed(
+        &mut self,
+        speed: u32,
+        current_temp: f64,
+        config: &Config,
+    ) -> Result<(), std::io::Error> {
+        // Zero is left alone so `zero_rpm` can still stop the fan
+        let speed = match speed {
+            0 => 0,
+            speed => speed.max(self.limits.min_speed),
+        };
+        let speed = self.ramp(speed, config);
+        if let (Some(dead_band), Some((last_speed, last_temp))) =
+            (config.dead_band, self.last_written)
+        {
+            if last_speed.abs_diff(speed) <= dead_band.speed
+                && (last_temp - current_temp).abs() <= dead_band.temp as f64
+            {
+                return Ok(());
+            }
+        }
+        let speed = self.transition(speed, config, Instant::now());
+        if self.last_written.map(|(last, _)| last) != Some(speed) {
+            let unit = config.temperature_unit;
+            let temp = unit.celsius_to(current_temp);
+            let fan = self.name();
+            if dry_run() {
+                info!(%fan, temp, target_rpm = speed, "Would set {} to {} RPM", fan, speed);
+            } else {
+                debug!(%fan, temp, target_rpm = speed, "Setting {} to {} RPM", fan, speed);
+            }
+        }
+        self.set_speed(speed)?;
+        self.last_written = Some((speed, current_temp));
+        Ok(())
+    }
+
+    /// Moves from the last speed written to `target` over `transition_time`
+    /// seconds. A target that changes on the way is followed without
+    /// pushing the end back. Changes within the dead band, and to zero rpm,
+    /// are made at once
+    fn transition(&mut self, target: u32, config: &Config, now: Instant) -> u32 {
+        let current = match self.last_written {
+            Some((current, _)) if config.transition_time > 0.0 && target != 0 => current,
+            _ => {
+                self.transition = None;
+                return target;
+            }
+        };
+        let small = config
+            .dead_band
+            .is_some_and(|dead_band| current.abs_diff(target) <= dead_band.speed);
+        let (end, last) = match self.transition {
+            Some(transition) => transition,
+            None if small || current == 0 || current == target => return target,
+            None => {
+                // Step as if a tick had already passed, so the first write
+                // moves the fan
+                let tick = Duration::from_millis(config.poll_interval_ms);
+                let end = now + Duration::from_secs_f64(config.transition_time);
+                (end, now.checked_sub(tick).unwrap_or(now))
+            }
+        };
+        if now >= end {
+            self.transition = None;
+            return target;
+        }
+        self.transition = Some((end, now));
+        let progress = (now - last).as_secs_f64() / (end - last).as_secs_f64();
+        (current as f64 + (target as f64 - current as f64) * progress).round() as u32
+    }
+
+    /// Limits the change from the last speed to `ramp_up_time` or
+    /// `ramp_down_time` for the whole speed range. Stopping and starting from
+    /// zero rpm is not ramped
+    fn ramp(&mut self, speed: u32, config: &Config) -> u32 {
+        let now = Instant::now();
+        let speed = match self.ramped {
+            Some((last, at)) if last != 0 && speed != 0 => {
+                let ramp_time = if speed > last {
+                    config.ramp_up_time
+                } else {
+                    config.ramp_down_time
+                };
+                if ramp_time > 0.0 {
+                    let range = self.limits.max_speed.saturating_sub(self.limits.min_speed) as f64;
+                    let step = (range * at.elapsed().as_secs_f64() / ramp_time).ceil() as u32;
+                    speed.clamp(last.saturating_sub(step), last.saturating_add(step))
+                } else {
+                    speed
+                }
+            }
+            _ => speed,
+        };
+        self.ramped = Some((speed, now));
+        speed
+    }
+
+    pub fn calc_speed(&mut self, current_temp: f64, config: &Config) -> u32 {
+        let mut speed = self.target_speed(current_temp, config);
+        if speed != 0 && (self.speed_offset != 0.0 || self.offset_rpm != 0 || self.scale != 1.0) {
+            let range = self.limits.max_speed.saturating_sub(self.limits.min_speed) as f64;
+            let offset = speed as f64 * self.scale
+                + range * self.speed_offset / 100.0
+                + self.offset_rpm as f64;
+            speed = (offset.round().max(0.0) as u32)
+                .max(self.limits.min_speed)
+                .min(self.limits.max_speed);
+        }
+        match (self.noise_cap, config.noise_cap) {
+            (Some(cap), Some(noise_cap)) if current_temp < noise_cap.critical_temp as f64 => {
+                speed.min(cap)
+            }
+            _ => speed,
+        }
+    }
+
+    fn target_speed(&mut self, current_temp: f64, config: &Config) -> u32 {
+        if let Some(zero_rpm) = config.zero_rpm {
+            if self.idle && current_temp >= zero_rpm.spin_up_temp as f64 {
+                self.idle = false;
+            } else if !self.idle && current_temp < zero_rpm.idle_temp as f64 {
+                self.idle = true;
+            }
+            if self.idle {
+                return 0;
+            }
+        }
+        if let Some(pid) = &mut self.pid {
+            let output = pid.update(current_temp);
+            return self.limits.speed_at(output);
+        }
+        let current_temp = match &mut self.predictor {
+            Some(predictor) => predictor.update(current_temp),
+            None => current_temp,
+        };
+        // Only slow down once the temperature has dropped `hysteresis` degrees
+        // below the point where the speed was last raised
+        let current_temp = match self.curve_temp {
+            Some(curve_temp)
+                if current_temp < curve_temp
+                    && curve_temp - current_temp < config.hysteresis as f64 =>
+            {
+                curve_temp
+            }
+            _ => current_temp,
+        };
+        self.curve_temp = Some(current_temp);
+        let speed = self.speed_curve.speed(current_temp, &self.limits);
+        let (previous, switched) = match &self.previous_curve {
+            Some(previous_curve) => previous_curve,
+            None => return speed,
+        };
+        let progress = switched.elapsed().as_secs_f64() / config.curve_blend_time;
+        if progress.is_nan() || progress >= 1.0 {
+            self.previous_curve = None;
+            return speed;
+        }
+        let previous_speed = previous.speed(current_temp, &self.limits) as f64;
+        (previous_speed + (speed as f64 - previous_speed) * progress).round() as u32
+    }
+}
+
+/// Speed bands the time of each fan is counted in for `t2macd status`, as
+/// parts of its hardware range
+pub const DUTY_BANDS: [&str; 5] = ["stopped", "0-25%", "25-50%", "50-75%", "75-100%"];
+
+/// Name of the fan with `index` and `label` for messages
+pub fn display_name(index: u32, label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("fan{} ({})", index, label),
+        None => format!("fan{}", index),
+    }
+}
+
+/// Every fan of the backends picked by `fan_backends`, unless it is disabled
+/// in the config. Without `fan_backends` the fallback backends are only
+/// searched when the others find nothing. `fan_template` replaces all of them
+pub fn fan_paths(config: &Config) -> Vec<(&'static dyn Backend, PathBuf)> {
+    let backends: Vec<&'static dyn Backend> = match &config.fan_template {
+        Some(template) => vec![backend::template(template)],
+        None => backend::BACKENDS.to_vec(),
+    };
+    let search = |fallback: bool| {
+        let mut paths = Vec::new();
+        for &backend in &backends {
+            let picked = if config.fan_backends.is_empty() {
+                backend.fallback() == fallback
+            } else {
+                config.fan_backends.iter().any(|id| id == backend.id())
+            };
+            if !picked {
+                continue;
+            }
+            for path in backend.fan_paths(config) {
+                let label = backend.label(&path);
+                if config.fan_enabled(backend.index(&path), label.as_deref()) {
+                    paths.push((backend, path));
+                }
+            }
+        }
+        paths
+    };
+    match search(false) {
+        paths if paths.is_empty() && config.fan_backends.is_empty() => search(true),
+        paths => paths,
+    }
+}
+
+pub fn discover_fans(config: &Config) -> Result<Vec<Fan>, std::io::Error> {
+    let mut all_fans = Vec::new();
+    for (backend, path) in fan_paths(config) {
+        all_fans.push(Fan::new(backend, path, config)?);
+    }
+    if all_fans.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no fans found, is applesmc loaded?",
+        ));
+    }
+    Ok(all_fans)
+}
+
+pub fn init_fans(config: &Config) -> Result<Vec<Fan>, std::io::Error> {
+    let fans = discover_fans(config)?;
+    // Kept before taking control, so fans taken before one fails are handed back
+    {
+        let mut controlled = CONTROLLED.lock().unwrap_or_else(|error| error.into_inner());
+        controlled.fans = fans
+            .iter()
+            .map(|fan| (fan.backend, fan.path.clone(), fan.hardware_limits))
+            .collect();
+        controlled.exit_action = config.exit_action;
+        controlled.exit_speed = config.exit_speed;
+    }
+    for fan in &fans {
+        fan.take_control()?;
+    }
+    Ok(fans)
+}
+
+/// Sets what `hand_back` leaves the fans at, after the config changed
+pub fn set_exit_action(config: &Config) {
+    let mut controlled = CONTROLLED.lock().unwrap_or_else(|error| error.into_inner());
+    controlled.exit_action = config.exit_action;
+    controlled.exit_speed = config.exit_speed;
+}
+
+/// Leaves every fan taken by `init_fans` as `exit_action` says, or at full
+/// speed when that fails, so they are not stuck at the last speed once the
+/// daemon is gone
+pub fn hand_back() {
+    if dry_run() {
+        return;
+    }
+    let controlled = CONTROLLED.lock().unwrap_or_else(|error| error.into_inner());
+    for (backend, path, limits) in &controlled.fans {
+        let result = match controlled.exit_action {
+            ExitAction::AUTO => backend.set_manual(path, false),
+            ExitAction::FIXED => {
+                let speed = controlled.exit_speed.resolve(limits);
+                fs::write(backend.output(path), speed.to_string())
+            }
+            ExitAction::MAX => fs::write(backend.output(path), limits.max_speed.to_string()),
+        };
+        // A fan that can not be handed back is safest at full speed
+        let result = result.or_else(|error| {
+            error!(path = %path.display(), "Failed to hand back {}: {}", path.display(), error);
+            fs::write(backend.output(path), limits.max_speed.to_string())
+        });
+        if let Err(error) = result {
+            error!(
+                path = %path.display(),
+                "Failed to run {} at full speed: {}",
+                path.display(),
+                error
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::APPLESMC;
+    use crate::controller::DeadBandConfig;
+    use crate::controller::ZeroRpmConfig;
+    use crate::curve::Linear;
+
+    /// An `applesmc` fan in a fresh directory under the temporary directory
+    fn fan(name: &str, min_speed: u32, max_speed: u32) -> Fan {
+        let dir = std::env::temp_dir().join(format!("t2macd-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("fan1_min"), min_speed.to_string()).unwrap();
+        fs::write(dir.join("fan1_max"), max_speed.to_string()).unwrap();
+        fs::write(dir.join("fan1_output"), "").unwrap();
+        fs::write(dir.join("fan1_input"), "0").unwrap();
+        fs::write(dir.join("fan1_manual"), "1").unwrap();
+        Fan::new(&APPLESMC, dir.join("fan1"), &Config::default()).unwrap()
+    }
+
+    fn written(fan: &Fan) -> u32 {
+        let path = fan.backend.output(&fan.path);
+        fs::read_to_string(path).unwrap().trim().parse().unwrap()
+    }
+
+    fn remove(fan: Fan) {
+        fs::remove_dir_all(fan.path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn set_speed_clamps_to_the_hardware_range() {
+        let fan = fan("clamps", 1200, 6000);
+        fan.set_speed(3000).unwrap();
+        assert_eq!(written(&fan), 3000);
+        fan.set_speed(100).unwrap();
+        assert_eq!(written(&fan), 1200);
+        fan.set_speed(9000).unwrap();
+        assert_eq!(written(&fan), 6000);
+        remove(fan);
+    }
+
+    #[test]
+    fn set_speed_keeps_zero() {
+        let fan = fan("zero", 1200, 6000);
+        fan.set_speed(0).unwrap();
+        assert_eq!(written(&fan), 0);
+        remove(fan);
+    }
+
+    #[test]
+    fn set_speed_survives_an_inverted_range() {
+        let fan = fan("inverted", 6000, 1200);
+        fan.set_speed(3000).unwrap();
+        assert_eq!(written(&fan), 1200);
+        fan.set_speed(9000).unwrap();
+        assert_eq!(written(&fan), 1200);
+        remove(fan);
+    }
+
+    #[test]
+    fn calc_speed_and_ramp_survive_an_inverted_range() {
+        let mut fan = fan("inverted-offset", 6000, 1200);
+        let config = Config {
+            ramp_up_time: 10.0,
+            ..Config::default()
+        };
+        fan.speed_offset = 10.0;
+        let speed = fan.calc_speed(60.0, &config);
+        assert_eq!(speed, 1200);
+        let (low, high) = (1200, 6000);
+        fan.apply_speed(speed, 60.0, &config).unwrap();
+        assert!((low..=high).contains(&written(&fan)));
+        assert_eq!(written(&fan), 1200);
+        fan.apply_speed(speed + 100, 60.0, &config).unwrap();
+        assert!((low..=high).contains(&written(&fan)));
+        assert_eq!(written(&fan), 1200);
+        remove(fan);
+    }
+
+    /// The speed of the default curve at `temp` for a 1000-5000 RPM fan
+    fn curve_speed(config: &Config, temp: f64) -> u32 {
+        config.speed_curve().speed(
+            temp,
+            &FanLimits {
+                min_speed: 1000,
+                max_speed: 5000,
+            },
+        )
+    }
+
+    #[test]
+    fn hysteresis_holds_the_speed_until_the_temperature_falls_past_it() {
+        let mut fan = fan("hysteresis", 1000, 5000);
+        let config = Config {
+            hysteresis: 5,
+            ..Config::default()
+        };
+        let temp = (config.min_temp + config.max_temp) as f64 / 2.0;
+        let raised = fan.calc_speed(temp, &config);
+        assert_eq!(raised, curve_speed(&config, temp));
+        // Inside the band the speed raised to is kept
+        assert_eq!(fan.calc_speed(temp - 2.0, &config), raised);
+        assert_eq!(fan.calc_speed(temp - 4.9, &config), raised);
+        // Past it the curve is followed again
+        let dropped = fan.calc_speed(temp - 6.0, &config);
+        assert_eq!(dropped, curve_speed(&config, temp - 6.0));
+        assert!(dropped < raised);
+        // Rising is never held back
+        assert_eq!(
+            fan.calc_speed(temp + 3.0, &config),
+            curve_speed(&config, temp + 3.0)
+        );
+        remove(fan);
+    }
+
+    #[test]
+    fn zero_rpm_stops_below_idle_temp_and_spins_up_above_spin_up_temp() {
+        let mut fan = fan("zero-rpm", 1000, 5000);
+        let config = Config {
+            zero_rpm: Some(ZeroRpmConfig {
+                idle_temp: 45,
+                spin_up_temp: 55,
+            }),
+            ..Config::default()
+        };
+        assert!(fan.calc_speed(60.0, &config) > 0);
+        // Between the two the fan keeps spinning
+        assert!(fan.calc_speed(50.0, &config) > 0);
+        assert!(fan.calc_speed(45.0, &config) > 0);
+        assert_eq!(fan.calc_speed(44.9, &config), 0);
+        // And stays stopped until spin_up_temp
+        assert_eq!(fan.calc_speed(50.0, &config), 0);
+        assert_eq!(fan.calc_speed(54.9, &config), 0);
+        // At the bottom of the curve, which starts at the default min_temp
+        assert_eq!(fan.calc_speed(55.0, &config), 1000);
+        assert!(fan.calc_speed(50.0, &config) > 0);
+        remove(fan);
+    }
+
+    /// `transition` from `from` towards `target` for every tick in `ticks`,
+    /// seconds after the first, as `apply_speed` would. Returns each speed
+    fn transition(
+        fan: &mut Fan,
+        from: u32,
+        target: u32,
+        ticks: &[f64],
+        config: &Config,
+    ) -> Vec<u32> {
+        fan.last_written = Some((from, 60.0));
+        let start = Instant::now();
+        ticks
+            .iter()
+            .map(|tick| {
+                let now = start + Duration::from_secs_f64(*tick);
+                let speed = fan.transition(target, config, now);
+                fan.last_written = Some((speed, 60.0));
+                speed
+            })
+            .collect()
+    }
+
+    #[test]
+    fn transition_steps_evenly_to_the_target() {
+        let mut fan = fan("transition", 1000, 5000);
+        let config = Config {
+            transition_time: 3.0,
+            ..Config::default()
+        };
+        // The first tick already moves, the last one lands on the target
+        let speeds = transition(&mut fan, 1000, 4000, &[0.0, 1.0, 2.0, 3.0, 4.0], &config);
+        assert_eq!(speeds, [1750, 2500, 3250, 4000, 4000]);
+        assert!(fan.transition.is_none());
+        let speeds = transition(&mut fan, 4000, 2000, &[0.0, 1.5, 3.0], &config);
+        assert_eq!(speeds, [3500, 2750, 2000]);
+        remove(fan);
+    }
+
+    #[test]
+    fn transition_follows_a_moving_target_without_ending_later() {
+        let mut fan = fan("transition-moving", 1000, 5000);
+        let config = Config {
+            transition_time: 3.0,
+            ..Config::default()
+        };
+        assert_eq!(transition(&mut fan, 1000, 4000, &[0.0], &config), [1750]);
+        let start = Instant::now();
+        let speed = fan.transition(2000, &config, start + Duration::from_secs(1));
+        assert!((1750..2000).contains(&speed));
+        fan.last_written = Some((speed, 60.0));
+        assert_eq!(
+            fan.transition(2000, &config, start + Duration::from_secs(3)),
+            2000
+        );
+        remove(fan);
+    }
+
+    #[test]
+    fn transition_skips_small_changes_and_stopping() {
+        let mut fan = fan("transition-skips", 1000, 5000);
+        let config = Config {
+            transition_time: 3.0,
+            dead_band: Some(DeadBandConfig {
+                speed: 200,
+                temp: 2,
+            }),
+            ..Config::default()
+        };
+        assert_eq!(transition(&mut fan, 1000, 1150, &[0.0], &config), [1150]);
+        assert_eq!(transition(&mut fan, 3000, 0, &[0.0], &config), [0]);
+        let off = Config {
+            transition_time: 0.0,
+            ..Config::default()
+        };
+        assert_eq!(transition(&mut fan, 1000, 4000, &[0.0], &off), [4000]);
+        remove(fan);
+    }
+
+    #[test]
+    fn a_new_curve_is_blended_in_over_curve_blend_time() {
+        let mut fan = fan("curve-blend", 1000, 5000);
+        let config = Config::default();
+        // From full speed to the bottom of the range, halfway through
+        let full = || {
+            Box::new(Linear {
+                min_temp: 0.0,
+                max_temp: 1.0,
+            })
+        };
+        let switched = Instant::now() - Duration::from_secs_f64(config.curve_blend_time / 2.0);
+        fan.previous_curve = Some((full(), switched));
+        let speed = fan.calc_speed(40.0, &config);
+        assert!((2950..=3000).contains(&speed), "speed {}", speed);
+        // After it the new curve alone is used
+        let switched = Instant::now() - Duration::from_secs_f64(config.curve_blend_time);
+        fan.previous_curve = Some((full(), switched));
+        assert_eq!(fan.calc_speed(40.0, &config), 1000);
+        assert!(fan.previous_curve.is_none());
+        remove(fan);
+    }
+
+    #[test]
+    fn the_output_is_only_opened_to_write_a_speed() {
+        let fan = fan("lazy-output", 1200, 6000);
+        assert!(fan.output.get().is_none());
+        fan.set_speed(3000).unwrap();
+        assert!(fan.output.get().is_some_and(Option::is_some));
+        remove(fan);
+    }
+
+    #[test]
+    fn the_open_input_and_mode_follow_their_files() {
+        let fan = fan("open-input", 1200, 6000);
+        let (input, manual) = (
+            fan.backend.input(&fan.path),
+            fan.backend.mode(&fan.path).unwrap(),
+        );
+        assert!(fan.input.is_some() && fan.mode.is_some());
+        fs::write(&input, "2500\n").unwrap();
+        assert_eq!(fan.read_speed().unwrap(), 2500);
+        fs::write(&input, "900\n").unwrap();
+        assert_eq!(fan.read_speed().unwrap(), 900);
+        assert!(!fan.lost_control());
+        fs::write(&manual, "0\n").unwrap();
+        assert!(fan.lost_control());
+        remove(fan);
+    }
+}
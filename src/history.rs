@@ -0,0 +1,165 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::fan::DUTY_BANDS;
+use crate::sensor::Reading;
+use crate::sysfs;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Where the daemon keeps its latest readings and trends for `t2macd status`,
+/// as JSON
+pub const STATUS_PATH: &str = "/run/t2macd/status";
+
+/// `STATUS_PATH`, under the generated tree with `--simulate`
+fn status_path() -> PathBuf {
+    PathBuf::from(sysfs::path(STATUS_PATH))
+}
+
+/// Recent readings of every sensor, kept for `sensors.history_time` seconds
+#[derive(Default)]
+pub struct History {
+    samples: HashMap<String, VecDeque<(Instant, f64)>>,
+}
+
+/// What is written to `STATUS_PATH`
+#[derive(Serialize, Deserialize)]
+pub struct Status {
+    pub sensors: Vec<SensorStatus>,
+    pub fans: Vec<FanStatus>,
+}
+
+/// A fan as written to `STATUS_PATH`
+#[derive(Serialize, Deserialize)]
+pub struct FanStatus {
+    pub index: u32,
+    pub label: Option<String>,
+    /// Speed last written
+    pub target: Option<u32>,
+    /// Speed the fan reported on the last tick
+    pub speed: Option<u32>,
+    /// Whether the fan has kept away from its target for a while, which
+    /// points at a failing fan or driver
+    pub unresponsive: bool,
+    /// Whether the fan has stopped while told to spin, see `fan_failure`
+    pub failed: bool,
+    /// Seconds spent in each of `DUTY_BANDS` since the daemon started
+    #[serde(default)]
+    pub duty: [f64; DUTY_BANDS.len()],
+}
+
+/// A sensor as written to `STATUS_PATH`
+#[derive(Serialize, Deserialize)]
+pub struct SensorStatus {
+    pub name: String,
+    pub label: String,
+    pub temp: f64,
+    /// Change in degrees per minute over the history
+    pub trend: f64,
+    /// Hottest reading in the history
+    pub max: f64,
+}
+
+impl History {
+    /// Adds `readings`, dropping samples older than `keep` and sensors that
+    /// are gone
+    pub fn record(&mut self, readings: &[Reading], keep: Duration) {
+        let now = Instant::now();
+        self.samples
+            .retain(|name, _| readings.iter().any(|reading| reading.name == *name));
+        for reading in readings {
+            let samples = self.samples.entry(reading.name.clone()).or_default();
+            samples.push_back((now, reading.temp));
+            while samples
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) > keep)
+            {
+                samples.pop_front();
+            }
+        }
+    }
+
+    /// Change of the sensor `name` in degrees per minute, from the oldest
+    /// sample kept to the newest
+    pub fn trend(&self, name: &str) -> Option<f64> {
+        let samples = self.samples.get(name)?;
+        let (first_at, first) = samples.front()?;
+        let (last_at, last) = samples.back()?;
+        let minutes = last_at.duration_since(*first_at).as_secs_f64() / 60.0;
+        (minutes > 0.0).then(|| (last - first) / minutes)
+    }
+
+    /// Change of the sensor `name` in degrees per minute over the samples
+    /// from the last `window`
+    pub fn recent_trend(&self, name: &str, window: Duration) -> Option<f64> {
+        let samples = self.samples.get(name)?;
+        let (last_at, last) = samples.back()?;
+        let (first_at, first) = samples
+            .iter()
+            .find(|(at, _)| last_at.duration_since(*at) <= window)?;
+        let minutes = last_at.duration_since(*first_at).as_secs_f64() / 60.0;
+        (minutes > 0.0).then(|| (last - first) / minutes)
+    }
+
+    /// Hottest sample of the sensor `name` kept
+    pub fn max(&self, name: &str) -> Option<f64> {
+        let samples = self.samples.get(name)?;
+        samples.iter().map(|(_, temp)| *temp).reduce(f64::max)
+    }
+
+    /// The latest `readings` with the trend and maximum of each
+    pub fn status(&self, readings: &[Reading]) -> Vec<SensorStatus> {
+        readings
+            .iter()
+            .map(|reading| SensorStatus {
+                name: reading.name.clone(),
+                label: reading.label.clone(),
+                temp: reading.temp,
+                trend: self.trend(&reading.name).unwrap_or(0.0),
+                max: self.max(&reading.name).unwrap_or(reading.temp),
+            })
+            .collect()
+    }
+}
+
+/// Replaces `STATUS_PATH` with `status`, through a temporary file so readers
+/// never see half of it
+pub fn write_status(status: &Status) -> io::Result<()> {
+    let path = status_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, serde_json::to_string(status)?)?;
+    fs::rename(temporary, path)
+}
+
+/// Reads `STATUS_PATH` if the daemon updated it within `max_age`
+pub fn read_status(max_age: Duration) -> Option<Status> {
+    let path = status_path();
+    let modified = fs::metadata(&path).ok()?.modified().ok()?;
+    if modified.elapsed().ok()? > max_age {
+        return None;
+    }
+    serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+}
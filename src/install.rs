@@ -0,0 +1,162 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The systemd unit written by `t2macd install`
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+
+pub const UNIT_PATH: &str = "/etc/systemd/system/t2macd.service";
+
+/// `path` as a single word of a systemd command line, in double quotes so
+/// spaces are kept, and with the characters systemd expands escaped
+fn quote(path: &Path) -> String {
+    let mut quoted = String::from("\"");
+    for c in path.display().to_string().chars() {
+        match c {
+            '\\' | '"' => quoted.push('\\'),
+            '%' | '$' => quoted.push(c),
+            _ => {}
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The unit running `exe`, with `--config config` when one is given. Both
+/// paths must be absolute, systemd runs the unit from `/`
+pub fn unit(exe: &Path, config: Option<&Path>) -> String {
+    let mut command = quote(exe);
+    if let Some(config) = config {
+        command += &format!(" --config {}", quote(config));
+    }
+    format!(
+        "\
+# Written by `t2macd install`, running it again replaces this file. Changes
+# belong in a drop-in made with `systemctl edit t2macd`
+
+[Unit]
+Description=Fan control for T2 Macs
+Documentation=https://github.com/sharpenedblade/t2macd
+# apple-bce and applesmc are loaded by udev and modules-load.d. Fans that show
+# up later are waited for, up to fan_wait_time
+After=systemd-modules-load.service systemd-udev-trigger.service
+Wants=systemd-modules-load.service
+
+[Service]
+Type=notify
+ExecStart={}
+ExecReload=/bin/kill -HUP $MAINPID
+Restart=on-failure
+RestartSec=2
+# Longer than the default stall_timeout, so the daemon hands the fans back
+# itself before systemd kills it
+WatchdogSec=60
+RuntimeDirectory=t2macd
+RuntimeDirectoryPreserve=yes
+ReadWritePaths=-/etc/t2macd
+ProtectSystem=strict
+ProtectHome=yes
+PrivateTmp=yes
+PrivateDevices=yes
+PrivateNetwork=yes
+NoNewPrivileges=yes
+ProtectKernelModules=yes
+ProtectKernelLogs=yes
+ProtectControlGroups=yes
+ProtectClock=yes
+ProtectHostname=yes
+RestrictAddressFamilies=AF_UNIX
+RestrictNamespaces=yes
+RestrictRealtime=yes
+RestrictSUIDSGID=yes
+LockPersonality=yes
+MemoryDenyWriteExecute=yes
+SystemCallArchitectures=native
+
+[Install]
+WantedBy=multi-user.target
+",
+        command
+    )
+}
+
+/// Writes the unit to `UNIT_PATH` for the running binary and the absolute
+/// path of `config`, and the D-Bus
+/// policy when built with the "dbus" feature. With `enable` systemd is
+/// reloaded and the unit enabled and started
+pub fn install(config: Option<&Path>, enable: bool) -> Result<PathBuf, io::Error> {
+    let exe = std::env::current_exe()?;
+    let config = config
+        .map(|config| {
+            fs::canonicalize(config).map_err(|error| {
+                io::Error::new(error.kind(), format!("{}: {}", config.display(), error))
+            })
+        })
+        .transpose()?;
+    let path = PathBuf::from(UNIT_PATH);
+    fs::write(&path, unit(&exe, config.as_deref()))?;
+    #[cfg(feature = "dbus")]
+    {
+        let policy = Path::new(crate::dbus::POLICY_PATH);
+        if let Some(parent) = policy.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(policy, crate::dbus::POLICY)?;
+    }
+    if enable {
+        systemctl(&["daemon-reload"])?;
+        systemctl(&["enable", "--now", "t2macd"])?;
+    }
+    Ok(path)
+}
+
+fn systemctl(args: &[&str]) -> Result<(), io::Error> {
+    let status = process::Command::new("systemctl").args(args).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "systemctl {} failed with {}",
+            args.join(" "),
+            status
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_start_is_quoted() {
+        let unit = unit(
+            Path::new("/usr/bin/t2macd"),
+            Some(Path::new("/etc/t2macd/my \"fans\" 100%.toml")),
+        );
+        assert!(unit.contains(
+            "ExecStart=\"/usr/bin/t2macd\" --config \"/etc/t2macd/my \\\"fans\\\" 100%%.toml\"\n"
+        ));
+    }
+
+    #[test]
+    fn expansions_are_escaped() {
+        assert_eq!(quote(Path::new("/a\\b$HOME")), "\"/a\\\\b$$HOME\"");
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Where the daemon's messages go. Under systemd they are sent to the journal
+//! with their fields, like the fan and its target speed, kept for
+//! `journalctl -o verbose`, everywhere else they are written to stderr
+
+use std::env;
+use std::io;
+use std::io::IsTerminal;
+use tracing::Level;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Starts logging messages at least as severe as `level`
+pub fn init(level: Level) {
+    let filter = LevelFilter::from_level(level);
+    // systemd sets this when stderr is connected to the journal
+    if env::var_os("JOURNAL_STREAM").is_some() {
+        if let Ok(journal) = tracing_journald::layer() {
+            let _ = tracing_subscriber::registry()
+                .with(journal)
+                .with(filter)
+                .try_init();
+            return;
+        }
+    }
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(filter)
+        .with_writer(io::stderr)
+        .with_target(false);
+    // The terminal shows when things happened, a log file needs the time
+    let _ = if io::stderr().is_terminal() {
+        builder.without_time().try_init()
+    } else {
+        builder.with_ansi(false).try_init()
+    };
+}
@@ -14,148 +14,1181 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use glob::glob;
-use serde::Deserialize;
-use serde::Serialize;
+mod backend;
+mod calibrate;
+mod cli;
+mod config;
+mod controller;
+mod curve;
+mod dbus;
+mod expression;
+mod fan;
+mod history;
+mod install;
+mod log;
+mod manual;
+mod migrate;
+mod model;
+mod notify;
+mod script;
+mod sensor;
+mod simulate;
+mod stall;
+mod sweep;
+mod sysfs;
+mod watch;
+
+use cli::Args;
+use cli::Command;
+use config::Config;
+use config::ControlMode;
+use config::SensorFailure;
+use curve::FanLimits;
+use curve::Speed;
+use fan::discover_fans;
+use fan::init_fans;
+use fan::Fan;
+use fan::Response;
+use history::History;
+use history::Status;
+use manual::Pins;
+use script::Script;
+use sensor::all_sources;
+use sensor::combined_temp;
+use sensor::describe_readings;
+use sensor::detect_gpus;
+use sensor::discover_sensors;
+use sensor::group_temp;
+use sensor::read_sensors;
+use sensor::MedianFilter;
+use sensor::Reading;
+use sensor::SensorKind;
+use sensor::SensorSet;
+use sensor::Smoother;
+use signal_hook::consts::SIGHUP;
+use signal_hook::consts::SIGINT;
+use signal_hook::consts::SIGTERM;
+use std::collections::HashMap;
 use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
 use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+use watch::ConfigWatcher;
 
-#[derive(Serialize, Deserialize, Clone, Copy)]
-enum FanCurve {
-    LINEAR,
-}
-
-#[derive(Serialize, Deserialize)]
-struct Config {
-    fan_curve: FanCurve,
-    min_temp: u32,
-    max_temp: u32,
-}
-
-impl Config {
-    fn get(path: &Path) -> Result<Config, std::io::Error> {
-        match fs::read_to_string(path) {
-            Ok(config_file) => match serde_json::from_str(&config_file) {
-                Ok(config) => Ok(config),
-                Err(..) => {
-                    eprintln!("Could not parse config, using default config");
-                    Ok(Config {
-                        fan_curve: FanCurve::LINEAR,
-                        min_temp: 80,
-                        max_temp: 100,
-                    })
-                }
-            },
-            Err(error) => match error.kind() {
-                io::ErrorKind::NotFound => {
-                    let config = Config {
-                        fan_curve: FanCurve::LINEAR,
-                        min_temp: 80,
-                        max_temp: 100,
-                    };
-                    match fs::write(path, serde_json::to_string(&config).unwrap()) {
-                        Ok(..) => println!("Created default config"),
-                        Err(..) => eprintln!("Failed to write default config"),
-                    };
-                    Ok(config)
+/// How often the fans and sensors are looked for again, to pick up drivers
+/// loaded after startup and devices that went away during suspend
+const RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How far the wall clock has to run ahead of the monotonic clock between two
+/// ticks to count as a resume from suspend
+const RESUME_GAP: Duration = Duration::from_secs(5);
+
+/// How far back `adaptive_polling` looks to tell whether a sensor is steady
+const CALM_WINDOW: Duration = Duration::from_secs(30);
+
+/// Locked by whichever instance controls the fans, and holding its pid
+const LOCK_PATH: &str = "/run/t2macd/lock";
+
+fn main() {
+    // Whatever goes wrong, the fans must not be left at their last speed
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        fan::hand_back();
+        // A panic on another thread would leave the loop running without it
+        process::exit(101);
+    }));
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
+    log::init(args.log_level);
+    if let Command::PrintDefaultConfig = args.command {
+        print!("{}", config::commented_default());
+        return;
+    }
+    if let Command::Init = args.command {
+        let path = args
+            .config
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(config::DEFAULT_PATH));
+        match config::init(&path) {
+            Ok(..) => println!("Created {}", path.display()),
+            Err(error) => {
+                eprintln!("Failed to create config: {}", error);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Command::Install { enable } = args.command {
+        match install::install(args.config.as_deref(), enable) {
+            Ok(path) if enable => println!("Wrote {} and started t2macd", path.display()),
+            Ok(path) => println!(
+                "Wrote {}, run `systemctl daemon-reload` and `systemctl enable --now t2macd` to start it",
+                path.display()
+            ),
+            Err(error) => {
+                eprintln!("Failed to install the unit: {}", error);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Command::ConfigConvert = args.command {
+        let (from, to) = match &args.config {
+            Some(path) => (path.clone(), path.with_extension("toml")),
+            None => (
+                PathBuf::from(config::LEGACY_PATH),
+                PathBuf::from(config::DEFAULT_PATH),
+            ),
+        };
+        match config::convert(&from, &to) {
+            Ok(..) => println!(
+                "Converted {} to {}, the old file can be removed",
+                from.display(),
+                to.display()
+            ),
+            Err(error) => {
+                eprintln!("Failed to convert config: {}", error);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(scenario) = &args.simulate {
+        match simulate::start(scenario) {
+            Ok(root) => println!(
+                "Simulating {}, sysfs is at {}",
+                scenario.display(),
+                root.display()
+            ),
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+    if args.dry_run {
+        fan::set_dry_run();
+        info!("Dry run, the fans are left to the firmware and nothing is written");
+    }
+    let config_paths = config_paths(&args);
+    if let Command::ConfigValidate = args.command {
+        let problems = Config::check(&config_paths);
+        if !problems.is_empty() {
+            for problem in &problems {
+                eprintln!("{}", problem);
+            }
+            std::process::exit(1);
+        }
+        println!("Config is valid");
+        return;
+    }
+    let mut config = match Config::get(&config_paths, args.force_defaults) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
+    if args.preset.is_some() {
+        config.preset = args.preset;
+    }
+    match args.command {
+        Command::Daemon if args.once => run_once(&config),
+        Command::Daemon => run(config, &args),
+        Command::CurveShow { plot } => show_curve(&config, plot),
+        Command::Status => show_status(&config),
+        Command::Sensors => list_sensors(&config),
+        Command::Profile { name: None } => {
+            for name in config.profile.keys() {
+                let active = config.default_profile.as_ref() == Some(name);
+                println!("{} {}", if active { "*" } else { " " }, name);
+            }
+        }
+        Command::Profile { name: Some(name) } => {
+            if !config.profile.contains_key(&name) {
+                eprintln!("There is no profile called {}", name);
+                std::process::exit(1);
+            }
+            if let Err(error) = config::switch_profile(&name) {
+                eprintln!("Failed to switch profile: {}", error);
+                std::process::exit(1);
+            }
+            println!("Switched to profile {}", name);
+        }
+        Command::Set {
+            fan,
+            speed,
+            duration,
+        } => {
+            let fans = match discover_fans(&config) {
+                Ok(fans) => fans,
+                Err(error) => {
+                    eprintln!("Failed to find the fans: {}", error);
+                    std::process::exit(1);
+                }
+            };
+            let fans: Vec<_> = fans
+                .iter()
+                .map(|fan| (fan.index, fan.label.clone()))
+                .collect();
+            if let Err(error) = manual::pin(&fans, fan.as_deref(), speed, duration) {
+                eprintln!("Failed to set the fan speed: {}", error);
+                std::process::exit(1);
+            }
+            let fans = match &fan {
+                Some(fan) => format!("fan {}", fan),
+                None => String::from("every fan"),
+            };
+            match speed {
+                Some(speed) => println!(
+                    "Set {} to {} for {} seconds",
+                    fans,
+                    speed,
+                    duration.as_secs()
+                ),
+                None => println!("Handed {} back to automatic control", fans),
+            }
+        }
+        Command::Auto { duration } => {
+            if let Err(error) = manual::hand_to_firmware(duration) {
+                eprintln!(
+                    "Failed to stop the daemon from controlling the fans: {}",
+                    error
+                );
+                std::process::exit(1);
+            }
+            // Done here as well, for when the daemon is not running
+            let fans = discover_fans(&config).unwrap_or_default();
+            for fan in &fans {
+                if let Err(error) = fan.release_control() {
+                    eprintln!("Failed to hand {} to the firmware: {}", fan.name(), error);
+                }
+            }
+            match duration {
+                Some(duration) => println!(
+                    "Handed the fans to the firmware for {} seconds",
+                    duration.as_secs()
+                ),
+                None => println!("Handed the fans to the firmware until `t2macd resume`"),
+            }
+        }
+        Command::Boost { duration } => {
+            if let Err(error) = manual::pin(&[], None, Some(Speed::Percent(100.0)), duration) {
+                eprintln!("Failed to boost the fans: {}", error);
+                std::process::exit(1);
+            }
+            println!(
+                "Running every fan at full speed for {} seconds, `t2macd set all auto` ends it",
+                duration.as_secs()
+            );
+        }
+        Command::Resume => {
+            if let Err(error) = manual::resume() {
+                eprintln!("Failed to resume: {}", error);
+                std::process::exit(1);
+            }
+            println!("The daemon takes the fans back on its next update");
+        }
+        Command::Init
+        | Command::Install { .. }
+        | Command::ConfigConvert
+        | Command::ConfigValidate
+        | Command::PrintDefaultConfig => unreachable!(),
+        Command::Calibrate => {
+            let _lock = lock_fans();
+            let fans = match init_fans(&config) {
+                Ok(fans) => fans,
+                Err(error) => fatal(format!("Failed to take control of the fans: {}", error)),
+            };
+            if let Err(error) = calibrate::calibrate(&fans, &config, &config_paths[0]) {
+                fatal(format!("Calibration failed: {}", error));
+            }
+        }
+        Command::TestFans => {
+            let _lock = lock_fans();
+            let fans = match init_fans(&config) {
+                Ok(fans) => fans,
+                Err(error) => fatal(format!("Failed to take control of the fans: {}", error)),
+            };
+            // Stop at the next step instead of leaving the fans at a test speed
+            let stop = Arc::new(AtomicBool::new(false));
+            for signal in [SIGTERM, SIGINT] {
+                if let Err(error) = signal_hook::flag::register(signal, stop.clone()) {
+                    warn!("Failed to handle signal {}: {}", signal, error);
                 }
-                _ => Err(error),
-            },
+            }
+            if !sweep::test_fans(&fans, &stop) {
+                std::process::exit(1);
+            }
         }
     }
 }
 
-struct Fan {
-    path: PathBuf,
-    max_speed: u32,
-    min_speed: u32,
-    speed_curve: FanCurve,
+fn show_curve(config: &Config, plot: bool) {
+    if !matches!(
+        config.control_mode,
+        ControlMode::CURVE | ControlMode::PREDICTIVE
+    ) {
+        println!("The configured control mode does not use a curve");
+        return;
+    }
+    let fans = match discover_fans(config) {
+        Ok(fans) => fans,
+        Err(error) => {
+            eprintln!("Failed to find the fans: {}", error);
+            std::process::exit(1);
+        }
+    };
+    for fan in &fans {
+        println!(
+            "{} ({}-{} RPM)",
+            fan.path.display(),
+            fan.limits.min_speed,
+            fan.limits.max_speed
+        );
+        println!("  Temp    RPM");
+        let unit = config.temperature_unit;
+        for temp in (30..=110).step_by(5) {
+            let speed = fan.speed_curve.speed(temp as f64, &fan.limits);
+            let temp = format!("{:.0}{}", unit.celsius_to(temp as f64), unit.symbol());
+            if plot {
+                let width = speed as u64 * 50 / fan.limits.max_speed.max(1) as u64;
+                println!(
+                    "  {:>5}  {:>5}  {}",
+                    temp,
+                    speed,
+                    "#".repeat(width as usize)
+                );
+            } else {
+                println!("  {:>5}  {:>5}", temp, speed);
+            }
+        }
+    }
 }
 
-impl Fan {
-    fn new(path: PathBuf, config: &Config) -> Result<Fan, std::io::Error> {
-        let fan = Fan {
-            max_speed: fs::read_to_string(Path::join(&path, "_max"))?
-                .parse::<u32>()
-                .unwrap(), // This file will always be an int
-            min_speed: fs::read_to_string(Path::join(&path, "_min"))?
-                .parse::<u32>()
-                .unwrap(), // Same as above
-            path,
-            speed_curve: config.fan_curve.clone(),
+/// Prints every temperature input on the system, with its reading and the
+/// sensor it is read as, if any
+fn list_sensors(config: &Config) {
+    let sensors = discover_sensors(config);
+    let unit = config.temperature_unit;
+    println!(
+        "{:<14} {:<16} {:>8} {:>7}  {:<12} Path",
+        "Driver", "Label", "Temp", "Offset", "Sensor"
+    );
+    for source in all_sources() {
+        let temp = match source.read() {
+            Some(temp) => unit.format(temp),
+            None => String::from("-"),
+        };
+        let sensor = sensors
+            .iter()
+            .find(|sensor| sensor.paths.contains(&source.path))
+            .map_or("-", |sensor| sensor.name.as_str());
+        // The reading is shown raw, the offset is added when it is read as a sensor
+        let offset = match config.sensors.devices.get(sensor) {
+            Some(device) if device.offset != 0.0 => {
+                format!("{:+.1}", device.offset / unit.delta_scale())
+            }
+            _ => String::from("-"),
         };
-        fs::write(Path::join(&fan.path, "_manual"), "1")?;
-        return Ok(fan);
+        println!(
+            "{:<14} {:<16} {:>8} {:>7}  {:<12} {}",
+            source.driver,
+            source.label.as_deref().unwrap_or("-"),
+            temp,
+            offset,
+            sensor,
+            source.path.display()
+        );
     }
+}
 
-    fn set_speed(&self, speed: u32) -> Result<(), std::io::Error> {
-        fs::write(Path::join(&self.path, "_output"), speed.to_string())
+/// Prints the readings of the running daemon with their trends, or reads the
+/// sensors directly when it is not running
+fn show_status(config: &Config) {
+    let max_age = Duration::from_millis(config.poll_interval_ms) + Duration::from_secs(5);
+    let status = match history::read_status(max_age) {
+        Some(status) => status,
+        None => match read_sensors(config) {
+            Ok(readings) => {
+                println!("{}", describe_readings(&readings, config));
+                return;
+            }
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+        },
+    };
+    let unit = config.temperature_unit;
+    let minutes = config.sensors.history_time as f64 / 60.0;
+    for sensor in status.sensors {
+        println!(
+            "{}: {:.0}{}, {:+.1}{}/min, max {:.0}{} over {:.0} min",
+            sensor.label,
+            unit.celsius_to(sensor.temp),
+            unit.symbol(),
+            sensor.trend / unit.delta_scale(),
+            unit.symbol(),
+            unit.celsius_to(sensor.max),
+            unit.symbol(),
+            minutes
+        );
+    }
+    for fan in status.fans {
+        let name = fan::display_name(fan.index, fan.label.as_deref());
+        let speed = fan
+            .speed
+            .map_or(String::from("-"), |speed| speed.to_string());
+        let target = fan
+            .target
+            .map_or(String::from("-"), |speed| speed.to_string());
+        let warning = if fan.failed {
+            ", stopped while told to spin"
+        } else if fan.unresponsive {
+            ", not reaching its target"
+        } else {
+            ""
+        };
+        println!("{}: {} RPM, target {}{}", name, speed, target, warning);
+        let total: f64 = fan.duty.iter().sum();
+        if total > 0.0 {
+            let bands: Vec<String> = fan::DUTY_BANDS
+                .iter()
+                .zip(fan.duty)
+                .map(|(band, time)| format!("{} {:.0}%", band, time / total * 100.0))
+                .collect();
+            let over = match total {
+                total if total < 3600.0 => format!("{:.0} min", total / 60.0),
+                total => format!("{:.1} h", total / 3600.0),
+            };
+            println!("  over {}: {}", over, bands.join(", "));
+        }
     }
+}
 
-    fn calc_speed(&self, current_temp: u32, config: &Config) -> u32 {
-        match self.speed_curve {
-            FanCurve::LINEAR => {
-                (current_temp - config.min_temp) / (config.max_temp - config.min_temp)
-                    * (self.max_speed - self.max_speed)
-                    + self.min_speed
+/// Returns the files the config is read from, either the one passed with
+/// `--config` or every layer, with the files they include and the profile
+/// picked with `t2macd profile`
+fn config_paths(args: &Args) -> Vec<PathBuf> {
+    let mut paths = match &args.config {
+        Some(path) => vec![path.clone()],
+        None => config::layers(),
+    };
+    paths = config::with_includes(&paths);
+    let profile = config::profile_path();
+    if profile.exists() {
+        paths.push(profile);
+    }
+    paths
+}
+
+fn load_script(config: &Config) -> Result<Option<Script>, String> {
+    match (config.control_mode, &config.script) {
+        (ControlMode::SCRIPT, Some(path)) => match Script::load(path) {
+            Ok(script) => Ok(Some(script)),
+            Err(error) => Err(format!("Failed to load script: {}", error)),
+        },
+        (ControlMode::SCRIPT, None) => Err(String::from("SCRIPT control mode needs a script path")),
+        _ => Ok(None),
+    }
+}
+
+/// Reads the config again and switches `fans` over to it, leaving everything
+/// untouched if it is invalid
+fn reload(args: &Args, fans: &mut [Fan]) -> Result<(Config, Option<Script>), String> {
+    let mut config = Config::load(&config_paths(args)).map_err(|error| error.to_string())?;
+    if args.preset.is_some() {
+        config.preset = args.preset;
+    }
+    let errors: Vec<String> = fans.iter().flat_map(|fan| fan.validate(&config)).collect();
+    if !errors.is_empty() {
+        return Err(format!("Invalid config:\n  {}", errors.join("\n  ")));
+    }
+    let script = load_script(&config)?;
+    for fan in fans {
+        fan.apply_config(&config);
+    }
+    fan::set_exit_action(&config);
+    Ok((config, script))
+}
+
+/// Takes control of the fans, waiting for them to show up when their driver
+/// is not loaded yet. The wait between tries doubles up to `RESCAN_INTERVAL`,
+/// and the daemon gives up after `fan_wait_time` seconds
+fn wait_for_fans(config: &Config) -> Vec<Fan> {
+    let started = Instant::now();
+    let mut delay = Duration::from_secs(1);
+    let mut waiting = false;
+    loop {
+        match init_fans(config) {
+            Ok(fans) => {
+                if waiting {
+                    info!(
+                        "Found the fans after {} seconds",
+                        started.elapsed().as_secs()
+                    );
+                }
+                return fans;
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                let waited = started.elapsed();
+                if config.fan_wait_time != 0 && waited.as_secs() >= config.fan_wait_time {
+                    fatal(format!(
+                        "Gave up waiting for fans after {} seconds: {}",
+                        waited.as_secs(),
+                        error
+                    ));
+                }
+                if !waiting {
+                    warn!("Waiting for fans: {}", missing_drivers(&error));
+                    waiting = true;
+                }
+                // Keeps systemd from timing out the start while waiting
+                notify::notify(&format!(
+                    "STATUS=Waiting for fans: {}\nEXTEND_TIMEOUT_USEC={}",
+                    missing_drivers(&error),
+                    (RESCAN_INTERVAL * 2).as_micros()
+                ));
+                let left = Duration::from_secs(config.fan_wait_time).saturating_sub(waited);
+                std::thread::sleep(match config.fan_wait_time {
+                    0 => delay,
+                    _ => delay.min(left),
+                });
+                delay = (delay * 2).min(RESCAN_INTERVAL);
             }
+            Err(error) => fatal(format!("Failed to take control of the fans: {}", error)),
+        }
+    }
+}
+
+/// Describes what the fans are missing for, from the kernel modules that
+/// are not loaded yet
+fn missing_drivers(error: &io::Error) -> String {
+    let missing: Vec<&str> = ["apple_bce", "applesmc"]
+        .into_iter()
+        .filter(|module| !Path::new(&sysfs::path(&format!("/sys/module/{}", module))).exists())
+        .collect();
+    if missing.is_empty() {
+        error.to_string()
+    } else {
+        format!("{} not loaded yet", missing.join(" and "))
+    }
+}
+
+/// Looks for the fans again and takes over the new set if it changed, as
+/// after applesmc is reloaded
+fn rescan_fans(config: &Config, fans: &mut Vec<Fan>) {
+    let paths = fan::fan_paths(config);
+    if paths
+        .iter()
+        .map(|(_, path)| path)
+        .eq(fans.iter().map(|fan| &fan.path))
+    {
+        return;
+    }
+    // Disabled fans go back to the firmware, for ones that are gone this fails
+    for fan in fans
+        .iter()
+        .filter(|fan| !paths.iter().any(|(_, path)| *path == fan.path))
+    {
+        let _ = fan.release_control();
+    }
+    if paths.is_empty() {
+        warn!("Lost every fan, waiting for them to come back");
+        fans.clear();
+        return;
+    }
+    match init_fans(config) {
+        Ok(found) => {
+            info!("Fans changed");
+            log_fans(&found);
+            *fans = found;
         }
+        Err(error) => warn!("Failed to take over the fans again: {}", error),
     }
 }
 
-fn init_fans(config: &Config) -> Result<Vec<Fan>, std::io::Error> {
-    let mut all_fans = Vec::new();
-    for i in glob("/sys/devices/*/*/*/*/APP0001:00/fan*_input").unwrap() {
-        let mut i: String = String::from(i.unwrap().to_str().unwrap());
-        i.truncate(i.len() - 6);
-        let i: PathBuf = PathBuf::from(i);
-        all_fans.push(Fan::new(i, config)?);
+/// Reads the sensors, sets every fan once and exits, for `--once`. The fans
+/// are left at the speeds set
+fn run_once(config: &Config) {
+    let _lock = lock_fans();
+    let mut fans = match init_fans(config) {
+        Ok(fans) => fans,
+        Err(error) => fatal(format!("Failed to take control of the fans: {}", error)),
+    };
+    let script = load_script(config).unwrap_or_else(|error| fatal(error));
+    let readings = read_sensors(config).unwrap_or_else(|error| fatal(error));
+    println!("{}", describe_readings(&readings, config));
+    let current_temp = combined_temp(&readings, config);
+    let temps = fan_temps(&fans, &readings, current_temp, config);
+    let speeds = match config.critical_sensor(&readings) {
+        Some((reading, critical_temp)) => {
+            let unit = config.temperature_unit;
+            error!(
+                "Critical: {} is at {}, above its critical_temp of {}. Running fans at full speed",
+                reading.label,
+                unit.format(reading.temp),
+                unit.format(critical_temp as f64)
+            );
+            fans.iter().map(|fan| fan.limits.max_speed).collect()
+        }
+        None => target_speeds(&mut fans, &readings, &temps, script.as_ref(), config)
+            .unwrap_or_else(|error| fatal(format!("Script failed: {}", error))),
+    };
+    let unit = config.temperature_unit;
+    let mut failed = false;
+    for ((fan, speed), temp) in fans.iter_mut().zip(speeds).zip(temps) {
+        match fan.apply_speed(speed, temp, config) {
+            // A dry run logs the speed itself
+            Ok(()) if fan::dry_run() => {}
+            Ok(()) => println!(
+                "{}: {:.1}{}, set {} RPM",
+                fan.name(),
+                unit.celsius_to(temp),
+                unit.symbol(),
+                fan.status().target.unwrap_or(speed)
+            ),
+            Err(error) => {
+                error!("Failed to set {}: {}", fan.name(), error);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        fan::hand_back();
+        process::exit(1);
     }
-    if all_fans.len() == 0 {
-        panic!();
+}
+
+/// Temperature each fan follows. Fans with sensors of their own follow those
+/// while any can be read, the others `current_temp`
+fn fan_temps(fans: &[Fan], readings: &[Reading], current_temp: f64, config: &Config) -> Vec<f64> {
+    fans.iter()
+        .map(|fan| group_temp(readings, &fan.sensors, config).unwrap_or(current_temp))
+        .collect()
+}
+
+/// Speed of each fan from `script`, or from its curve at its temperature in
+/// `temps`
+fn target_speeds(
+    fans: &mut [Fan],
+    readings: &[Reading],
+    temps: &[f64],
+    script: Option<&Script>,
+    config: &Config,
+) -> Result<Vec<u32>, String> {
+    match script {
+        Some(script) => {
+            let limits: Vec<FanLimits> = fans.iter().map(|fan| fan.limits).collect();
+            script.fan_speeds(readings, &limits)
+        }
+        None => Ok(fans
+            .iter_mut()
+            .zip(temps)
+            .map(|(fan, temp)| fan.calc_speed(*temp, config))
+            .collect()),
     }
-    return Ok(all_fans);
 }
 
-fn get_current_temp() -> u32 {
-    let mut cpu_temp_path: PathBuf = Default::default();
-    for path in glob("/sys/devices/platform/coretemp.0/hwmon/hwmon*/temp1_input").unwrap() {
-        cpu_temp_path = PathBuf::from(path.unwrap());
+/// Takes `LOCK_PATH`, so two instances never fight over the fans. Exits
+/// when another instance holds it. The lock lasts until the file is dropped.
+/// It is under the generated tree with `--simulate`, and not taken at all on
+/// a dry run, which leaves the fans alone
+fn lock_fans() -> Option<File> {
+    if fan::dry_run() {
+        return None;
     }
-    let cpu_temp: String = match fs::read_to_string(cpu_temp_path) {
-        Ok(temp) => temp,
-        Err(..) => panic!("Failed to read CPU temp. Are you running as root?"),
+    let path = PathBuf::from(sysfs::path(LOCK_PATH));
+    let opened = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+        });
+    let mut file = match opened {
+        Ok(file) => file,
+        Err(error) => {
+            error!("Failed to open {}: {}", path.display(), error);
+            process::exit(1);
+        }
     };
-    let cpu_temp: u32 = cpu_temp.parse::<u32>().unwrap(); // Always parsable
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) => {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            error!(
+                "Another t2macd (pid {}) is already controlling the fans",
+                pid.trim()
+            );
+            process::exit(1);
+        }
+        Err(fs::TryLockError::Error(error)) => {
+            error!("Failed to lock {}: {}", path.display(), error);
+            process::exit(1);
+        }
+    }
+    let _ = file.set_len(0);
+    let _ = write!(file, "{}", process::id());
+    Some(file)
+}
 
-    let gpu_temp_path = PathBuf::from("/sys/class/drm/card0/device/hwmon/hwmon*/temp1_input");
-    let gpu_temp: String = match fs::read_to_string(gpu_temp_path) {
-        Ok(temp) => temp,
-        Err(..) => panic!("Failed to read GPU temp. Are you running as root?"),
+/// Logs `error` and exits, handing back the fans taken so far first so a
+/// daemon that dies does not leave them at their last speed
+fn fatal(error: impl std::fmt::Display) -> ! {
+    error!("{}", error);
+    fan::hand_back();
+    process::exit(1);
+}
+
+/// Sleeps for `wait`, or until a signal writes to `wake`
+fn sleep_until_signal(mut wake: &UnixStream, wait: Duration) {
+    if wake.set_read_timeout(Some(wait)).is_err() {
+        std::thread::sleep(wait);
+        return;
+    }
+    // Several signals can be waiting, one read takes them all
+    let _ = wake.read(&mut [0; 64]);
+}
+
+/// Whether every sensor is below `adaptive_polling.calm_temp` and changing
+/// slower than `calm_trend`, so the daemon can tick less often
+fn calm(readings: &[Reading], history: &History, config: &Config) -> bool {
+    let Some(adaptive_polling) = config.adaptive_polling else {
+        return false;
     };
-    let gpu_temp: u32 = gpu_temp.parse::<u32>().unwrap(); // Same as above
+    readings.iter().all(|reading| {
+        let trend = history.recent_trend(&reading.name, CALM_WINDOW);
+        reading.temp < adaptive_polling.calm_temp as f64
+            && trend.is_none_or(|trend| trend.abs() <= adaptive_polling.calm_trend)
+    })
+}
 
-    if gpu_temp > cpu_temp {
-        gpu_temp
-    } else {
-        cpu_temp
+/// Runs `fan_failure.command` for the fan `name`, without waiting for it
+fn run_fan_failure_command(command: &str, name: &str) {
+    let spawned = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("T2MACD_FAN", name)
+        .spawn();
+    match spawned {
+        // Waited for on the side so it does not linger as a zombie
+        Ok(mut child) => drop(std::thread::spawn(move || child.wait())),
+        Err(error) => warn!("Failed to run fan_failure.command: {}", error),
     }
 }
 
-fn main() {
-    let config = Config::get(&PathBuf::from("/etc/t2macd.json")).unwrap();
-    let fans = match init_fans(&config) {
-        Ok(fans) => fans,
-        Err(..) => panic!("An error occured when initializing fans"),
+fn log_fans(fans: &[Fan]) {
+    for fan in fans {
+        info!(
+            fan = %fan.name(),
+            backend = fan.backend.name(),
+            "Controlling {} through {}",
+            fan.name(),
+            fan.backend.name()
+        );
+    }
+}
+
+fn log_sensors(sensors: &SensorSet) {
+    for sensor in &sensors.sensors {
+        info!("Reading {} as {}", sensor.description(), sensor.name);
+    }
+}
+
+/// Warns about sensors named in the config that are not among `sensors`
+fn check_sensors(sensors: &SensorSet, config: &Config) {
+    for problem in config.validate_sensors(&sensors.names()) {
+        warn!("{}", problem);
+    }
+}
+
+fn run(mut config: Config, args: &Args) {
+    let _lock = lock_fans();
+    match model::product_name() {
+        Some(product) => match model::find(&product) {
+            Some(model) => info!("Running on a {} ({})", model.name, product),
+            None => info!("Running on {}, which has no built-in defaults", product),
+        },
+        None => info!("Could not read the Mac model"),
+    }
+    let mut fans = wait_for_fans(&config);
+    log_fans(&fans);
+    // Set by SIGTERM and SIGINT, a second one exits right away
+    let terminate = Arc::new(AtomicBool::new(false));
+    // Written to by every signal handled, to cut the sleep between ticks short
+    let (wake, wake_write) = match UnixStream::pair() {
+        Ok(pair) => pair,
+        Err(error) => fatal(format!("Failed to create the signal pipe: {}", error)),
+    };
+    let wake_on = |signal| {
+        wake_write
+            .try_clone()
+            .and_then(|pipe| signal_hook::low_level::pipe::register(signal, pipe))
+    };
+    for signal in [SIGTERM, SIGINT] {
+        let registered =
+            signal_hook::flag::register_conditional_shutdown(signal, 1, terminate.clone())
+                .and_then(|_| signal_hook::flag::register(signal, terminate.clone()))
+                .and_then(|_| wake_on(signal));
+        if let Err(error) = registered {
+            warn!("Failed to handle signal {}: {}", signal, error);
+        }
+    }
+    let mut sensors = SensorSet::open(&config);
+    log_sensors(&sensors);
+    check_sensors(&sensors, &config);
+    let bus = dbus::Bus::start();
+    if config.sensors.selects(&["gpu"]) {
+        for gpu in detect_gpus() {
+            // Integrated GPUs have no temperature of their own, they share the CPUs
+            if gpu.integrated {
+                info!(
+                    "Found integrated GPU {} ({}), covered by the CPU temperature",
+                    gpu.card, gpu.driver
+                );
+            } else {
+                info!("Found GPU {} ({})", gpu.card, gpu.driver);
+            }
+        }
+        if !sensors
+            .sensors
+            .iter()
+            .any(|sensor| sensor.kind == SensorKind::GPU)
+        {
+            info!("No GPU temperature found, using the CPU temperature alone");
+        }
+    }
+    let mut script = load_script(&config).unwrap_or_else(|error| fatal(error));
+    let config_paths = config_paths(args);
+    let mut watch_dirs: Vec<&Path> = config_paths
+        .iter()
+        .filter_map(|path| path.parent())
+        .collect();
+    if args.config.is_none() {
+        watch_dirs.extend(Path::new(config::DROP_IN_GLOB).parent());
+    }
+    // Created up front so profile switches are seen
+    let profile_path = config::profile_path();
+    let profile_dir = profile_path.parent().unwrap();
+    if fs::create_dir_all(profile_dir).is_ok() {
+        watch_dirs.push(profile_dir);
+    }
+    let mut watcher = match ConfigWatcher::new(watch_dirs) {
+        Ok(watcher) => Some(watcher),
+        Err(error) => {
+            warn!("Failed to watch config, changes need a restart: {}", error);
+            None
+        }
     };
+    // Set by SIGHUP, sent by `systemctl reload`
+    let hangup = Arc::new(AtomicBool::new(false));
+    let registered =
+        signal_hook::flag::register(SIGHUP, hangup.clone()).and_then(|_| wake_on(SIGHUP));
+    if let Err(error) = registered {
+        warn!("Failed to handle SIGHUP: {}", error);
+    }
+    let mut critical = false;
+    let mut sensors_failed = false;
+    let mut median_filter = MedianFilter::default();
+    let mut smoother = Smoother::default();
+    let mut history = History::default();
+    let mut last_rescan = Instant::now();
+    let mut write_failed = false;
+    // Whether writing the status failed, so it is only reported once
+    let mut status_failed = false;
+    let mut pins = Pins::default();
+    let (mut last_tick, mut last_tick_wall) = (Instant::now(), SystemTime::now());
+    // Whether the fans were handed over by `t2macd auto`
+    let mut with_firmware = false;
+    // Pinned speed of the fans running at one, by index
+    let mut pinned = HashMap::new();
+    let mut next_tick = Instant::now();
+    let mut interval = Duration::from_millis(config.poll_interval_ms);
+    let watchdog = notify::watchdog_interval();
+    let heartbeat = stall::Heartbeat::start();
+    notify::notify("READY=1");
     loop {
+        // Ticks keep to the interval however long the last one took, and
+        // start over from now after falling behind. They never get further
+        // apart than the watchdog allows
+        next_tick += interval.min(watchdog.unwrap_or(Duration::MAX));
+        interval = Duration::from_millis(config.poll_interval_ms);
+        let now = Instant::now();
+        // The tick after the sleep has stall_timeout to finish in
+        let stall_timeout = Duration::from_secs(config.stall_timeout);
+        heartbeat.beat((!stall_timeout.is_zero()).then(|| next_tick.max(now) + stall_timeout));
+        match next_tick.checked_duration_since(now) {
+            Some(wait) if !wait.is_zero() => sleep_until_signal(&wake, wait),
+            _ => next_tick = now,
+        }
+        if watchdog.is_some() {
+            notify::notify("WATCHDOG=1");
+        }
+        if terminate.load(Ordering::Relaxed) {
+            notify::notify("STOPPING=1");
+            info!("Stopping, handing the fans back");
+            fan::hand_back();
+            let _ = io::stdout().flush();
+            return;
+        }
+        let firmware = manual::firmware_control();
+        if firmware != with_firmware {
+            with_firmware = firmware;
+            if firmware {
+                info!("Handing the fans to the firmware for t2macd auto");
+                notify::notify("STATUS=Fans handed to the firmware by t2macd auto");
+                for fan in &fans {
+                    if let Err(error) = fan.release_control() {
+                        warn!("Failed to hand {} to the firmware: {}", fan.name(), error);
+                    }
+                }
+            } else {
+                info!("Taking the fans back from the firmware");
+                for fan in &fans {
+                    if fan.take_control().is_err() {
+                        write_failed = true;
+                    }
+                }
+            }
+        }
+        if with_firmware {
+            continue;
+        }
+        // The monotonic clock stops during suspend, the wall clock does not
+        let wall_elapsed = last_tick_wall.elapsed().unwrap_or_default();
+        let resumed = wall_elapsed > last_tick.elapsed() + RESUME_GAP;
+        (last_tick, last_tick_wall) = (Instant::now(), SystemTime::now());
+        if resumed {
+            info!("Resumed from suspend, taking the fans back");
+        }
         for fan in &fans {
-            match fan.set_speed(fan.calc_speed(get_current_temp(), &config)) {
-                Ok(..) => continue,
-                Err(..) => println!("Error: Failed to set fan speed"),
+            if !resumed && !fan.lost_control() {
+                continue;
+            }
+            if let Err(error) = fan.reassert_control() {
+                warn!("Failed to take {} back: {}", fan.name(), error);
+                write_failed = true;
+            } else if !resumed {
+                info!("The firmware took {} back, taking it again", fan.name());
+            }
+        }
+        if write_failed || last_rescan.elapsed() >= RESCAN_INTERVAL {
+            last_rescan = Instant::now();
+            write_failed = false;
+            rescan_fans(&config, &mut fans);
+            // Opened again either way, a device can come back under a new
+            // hwmon number with the same name
+            let found = SensorSet::open(&config);
+            if found.names() != sensors.names() {
+                info!("Sensors changed");
+                log_sensors(&found);
+                check_sensors(&found, &config);
+            }
+            sensors.replace(found);
+        }
+        let changed = watcher.as_mut().is_some_and(|watcher| watcher.changed());
+        if hangup.swap(false, Ordering::Relaxed) || changed {
+            notify::notify("RELOADING=1");
+            let reloaded = reload(args, &mut fans);
+            notify::notify("READY=1");
+            match reloaded {
+                Ok((new_config, new_script)) => {
+                    config = new_config;
+                    script = new_script;
+                    sensors.replace(SensorSet::open(&config));
+                    check_sensors(&sensors, &config);
+                    // Picks up fans that were enabled or disabled
+                    rescan_fans(&config, &mut fans);
+                    info!("Reloaded config");
+                }
+                Err(error) => warn!("Keeping the old config: {}", error),
+            }
+        }
+        for fan in &mut fans {
+            match fan.check_response() {
+                Some(Response::Lost { target, speed }) => {
+                    warn!(
+                        fan = %fan.name(),
+                        rpm = speed,
+                        target_rpm = target,
+                        "{} is at {} RPM instead of {}, the fan or its driver may be failing",
+                        fan.name(),
+                        speed,
+                        target
+                    );
+                    if let Some(bus) = &bus {
+                        bus.fan_not_responding(&fan.name(), target, speed);
+                    }
+                }
+                Some(Response::Recovered) => {
+                    info!("{} is reaching its target again", fan.name());
+                    if let Some(bus) = &bus {
+                        bus.fan_responding(&fan.name());
+                    }
+                }
+                None => {}
+            }
+            if !fan.check_failure(&config) {
+                continue;
+            }
+            if fan.failed {
+                error!(
+                    fan = %fan.name(),
+                    "{} has stopped while told to spin. Running the other fans at full speed",
+                    fan.name()
+                );
+                if let Some(command) = &config.fan_failure.command {
+                    run_fan_failure_command(command, &fan.name());
+                }
+            } else {
+                info!("{} is spinning again", fan.name());
+            }
+        }
+        let readings = match sensors.read(&config) {
+            Ok(readings) => readings,
+            Err(error) => {
+                if !sensors_failed {
+                    error!("{}", error);
+                }
+                sensors_failed = true;
+                for fan in &fans {
+                    let speed = match config.sensor_failure {
+                        SensorFailure::HOLD => continue,
+                        SensorFailure::FIXED => config.sensor_failure_speed.resolve(&fan.limits),
+                        SensorFailure::MAX => fan.limits.max_speed,
+                    };
+                    if fan.set_speed(speed).is_err() {
+                        error!(fan = %fan.name(), target_rpm = speed, "Failed to set fan speed");
+                        write_failed = true;
+                    }
+                }
+                continue;
+            }
+        };
+        if sensors_failed {
+            info!("Sensors can be read again");
+            sensors_failed = false;
+        }
+        let readings = median_filter.filter(&readings, &config);
+        // The critical check comes before smoothing, so it is never delayed
+        let critical_sensor = config.critical_sensor(&readings);
+        history.record(&readings, Duration::from_secs(config.sensors.history_time));
+        if calm(&readings, &history, &config) {
+            let calm_interval = config.adaptive_polling.map(|calm| calm.calm_interval_ms);
+            interval = Duration::from_millis(calm_interval.unwrap_or(config.poll_interval_ms));
+        }
+        // The status is only for people to look at, the fans do not depend on
+        // it. A dry run leaves it to the daemon it may run next to
+        if !fan::dry_run() {
+            let status = Status {
+                sensors: history.status(&readings),
+                fans: fans.iter().map(Fan::status).collect(),
+            };
+            match history::write_status(&status) {
+                Ok(()) => status_failed = false,
+                Err(error) if !status_failed => {
+                    warn!("Failed to write the status for `t2macd status`: {}", error);
+                    status_failed = true;
+                }
+                Err(..) => {}
+            }
+        }
+        let readings = smoother.smooth(&readings, &config);
+        let current_temp = combined_temp(&readings, &config);
+        match (critical_sensor, critical) {
+            (Some((reading, critical_temp)), false) => {
+                let unit = config.temperature_unit;
+                error!(
+                    sensor = %reading.name,
+                    temp = unit.celsius_to(reading.temp),
+                    "{} is at {}, above its critical_temp of {}. Running fans at full speed",
+                    reading.label,
+                    unit.format(reading.temp),
+                    unit.format(critical_temp as f64)
+                )
+            }
+            (None, true) => info!("All sensors are below their critical_temp again"),
+            _ => {}
+        }
+        critical = critical_sensor.is_some();
+
+        let temps = fan_temps(&fans, &readings, current_temp, &config);
+        let emergency = critical || fans.iter().any(|fan| fan.failed);
+        let speeds = if emergency {
+            fans.iter().map(|fan| fan.limits.max_speed).collect()
+        } else {
+            match target_speeds(&mut fans, &readings, &temps, script.as_ref(), &config) {
+                Ok(speeds) => speeds,
+                Err(error) => {
+                    error!("Script failed: {}", error);
+                    continue;
+                }
+            }
+        };
+        pins.refresh();
+        for ((fan, speed), temp) in fans.iter_mut().zip(speeds).zip(temps) {
+            let pin = pins.speed_for(fan.index, fan.label.as_deref());
+            // Pins give way to a critical temperature or a failed fan
+            let speed = match pin {
+                Some(pin) if !emergency => pin.resolve(&fan.limits),
+                _ => speed,
+            };
+            if !emergency && pinned.get(&fan.index).copied() != pin {
+                match pin {
+                    Some(pin) => {
+                        info!("{} set to {} by t2macd set", fan.name(), pin);
+                        pinned.insert(fan.index, pin);
+                    }
+                    None => {
+                        info!("{} is back under automatic control", fan.name());
+                        pinned.remove(&fan.index);
+                    }
+                }
+            }
+            if fan.apply_speed(speed, temp, &config).is_err() {
+                error!(fan = %fan.name(), temp, target_rpm = speed, "Failed to set fan speed");
+                write_failed = true;
             }
         }
+        let fan_speeds: Vec<String> = fans
+            .iter()
+            .map(|fan| match fan.status().target {
+                Some(target) => format!("{} {} RPM", fan.name(), target),
+                None => fan.name(),
+            })
+            .collect();
+        notify::notify(&format!(
+            "STATUS={}; {}",
+            describe_readings(&readings, &config),
+            fan_speeds.join(", ")
+        ));
     }
 }
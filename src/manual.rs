@@ -0,0 +1,351 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Speeds pinned by `t2macd set`, handed to the daemon through `PINS_PATH`
+
+use crate::config::label_matches;
+use crate::curve::Speed;
+use crate::sysfs;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Where `t2macd set` leaves the pinned speeds for the daemon, as JSON
+pub const PINS_PATH: &str = "/run/t2macd/pins";
+
+/// Left by `t2macd auto` while the fans are with the firmware, as JSON
+pub const AUTO_PATH: &str = "/run/t2macd/auto";
+
+/// `PINS_PATH`, under the generated tree with `--simulate`
+fn pins_path() -> PathBuf {
+    PathBuf::from(sysfs::path(PINS_PATH))
+}
+
+/// `AUTO_PATH`, under the generated tree with `--simulate`
+fn auto_path() -> PathBuf {
+    PathBuf::from(sysfs::path(AUTO_PATH))
+}
+
+/// How long a speed stays pinned without `--for`
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(600);
+
+/// A fan held at a fixed speed until `until`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Pin {
+    /// Index or label of the fan, every fan when missing
+    pub fan: Option<String>,
+    pub speed: Speed,
+    /// Seconds since the Unix epoch the pin ends at
+    pub until: u64,
+}
+
+impl Pin {
+    pub fn matches(&self, index: u32, label: Option<&str>) -> bool {
+        self.fan
+            .as_deref()
+            .is_none_or(|fan| names(fan, index, label))
+    }
+
+    pub fn expired(&self) -> bool {
+        now() >= self.until
+    }
+}
+
+/// Whether `fan` is the index or label of the fan with `index` and `label`
+fn names(fan: &str, index: u32, label: Option<&str>) -> bool {
+    fan == index.to_string() || label.is_some_and(|label| label_matches(label, fan))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Seconds since the Unix epoch `duration` from now ends at
+fn until(duration: Duration) -> io::Result<u64> {
+    now().checked_add(duration.as_secs()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}s is too long to wait", duration.as_secs()),
+        )
+    })
+}
+
+/// Every pin in `PINS_PATH`, expired or not
+pub fn read() -> Vec<Pin> {
+    fs::read_to_string(pins_path())
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+/// Pins `fan`, or every fan when it is `None`, at `speed` for `duration`.
+/// Earlier pins of the same fans are replaced, and dropped without a new
+/// one when `speed` is `None`. `fans` are the index and label of every fan,
+/// to tell which pins name the same fans
+pub fn pin(
+    fans: &[(u32, Option<String>)],
+    fan: Option<&str>,
+    speed: Option<Speed>,
+    duration: Duration,
+) -> io::Result<()> {
+    let live = read().into_iter().filter(|pin| !pin.expired()).collect();
+    let mut pins = unpin(live, fans, fan)?;
+    if let Some(speed) = speed {
+        pins.push(Pin {
+            fan: fan.map(String::from),
+            speed,
+            until: until(duration)?,
+        });
+    }
+    let path = pins_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Through a temporary file, so the daemon never reads half of it
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, serde_json::to_string(&pins)?)?;
+    fs::rename(temporary, &path)
+}
+
+/// `pins` without the fans `fan` names, every fan when it is `None`. A pin
+/// covering other fans as well is kept for those
+fn unpin(
+    pins: Vec<Pin>,
+    fans: &[(u32, Option<String>)],
+    fan: Option<&str>,
+) -> io::Result<Vec<Pin>> {
+    let Some(fan) = fan else {
+        return Ok(Vec::new());
+    };
+    let pinned: Vec<u32> = fans
+        .iter()
+        .filter(|(index, label)| names(fan, *index, label.as_deref()))
+        .map(|(index, _)| *index)
+        .collect();
+    if pinned.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("there is no fan called {}", fan),
+        ));
+    }
+    let mut kept = Vec::new();
+    for pin in pins {
+        let covered: Vec<u32> = fans
+            .iter()
+            .filter(|(index, label)| pin.matches(*index, label.as_deref()))
+            .map(|(index, _)| *index)
+            .collect();
+        if !covered.iter().any(|index| pinned.contains(index)) {
+            kept.push(pin);
+            continue;
+        }
+        kept.extend(
+            covered
+                .into_iter()
+                .filter(|index| !pinned.contains(index))
+                .map(|index| Pin {
+                    fan: Some(index.to_string()),
+                    ..pin.clone()
+                }),
+        );
+    }
+    Ok(kept)
+}
+
+/// Contents of `AUTO_PATH`
+#[derive(Serialize, Deserialize)]
+struct Auto {
+    /// Seconds since the Unix epoch the daemon takes the fans back at, never
+    /// when missing
+    until: Option<u64>,
+}
+
+/// Keeps the daemon away from the fans for `duration`, or until `resume`
+/// when it is `None`
+pub fn hand_to_firmware(duration: Option<Duration>) -> io::Result<()> {
+    let auto = Auto {
+        until: duration.map(until).transpose()?,
+    };
+    let path = auto_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, serde_json::to_string(&auto)?)
+}
+
+/// Lets the daemon take the fans back after `hand_to_firmware`
+pub fn resume() -> io::Result<()> {
+    match fs::remove_file(auto_path()) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the fans are with the firmware through `hand_to_firmware`
+pub fn firmware_control() -> bool {
+    let auto: Option<Auto> = fs::read_to_string(auto_path())
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok());
+    match auto {
+        Some(Auto { until: Some(until) }) => now() < until,
+        Some(Auto { until: None }) => true,
+        None => false,
+    }
+}
+
+/// The pins as seen by the daemon, read again whenever `PINS_PATH` changes
+#[derive(Default)]
+pub struct Pins {
+    pins: Vec<Pin>,
+    modified: Option<SystemTime>,
+}
+
+impl Pins {
+    /// Reads `PINS_PATH` again if it changed since the last call
+    pub fn refresh(&mut self) {
+        let modified = fs::metadata(pins_path())
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified != self.modified {
+            self.modified = modified;
+            self.pins = read();
+        }
+    }
+
+    /// The speed the fan with `index` and `label` is pinned at, the latest
+    /// pin winning
+    pub fn speed_for(&self, index: u32, label: Option<&str>) -> Option<Speed> {
+        self.pins
+            .iter()
+            .rev()
+            .find(|pin| !pin.expired() && pin.matches(index, label))
+            .map(|pin| pin.speed)
+    }
+}
+
+/// Reads a duration like `90`, `90s`, `5m` or `1h`
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => text.split_at(at),
+        None => (text, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => return Err(format!("\"{}\" is not a duration like 90s, 5m or 1h", text)),
+    };
+    match number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(scale))
+    {
+        Some(seconds) => Ok(Duration::from_secs(seconds)),
+        None => Err(format!("\"{}\" is not a duration like 90s, 5m or 1h", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fans() -> Vec<(u32, Option<String>)> {
+        vec![
+            (1, Some(String::from("Left"))),
+            (2, Some(String::from("Right"))),
+        ]
+    }
+
+    fn pin(fan: Option<&str>) -> Pin {
+        Pin {
+            fan: fan.map(String::from),
+            speed: Speed::Rpm(3000),
+            until: u64::MAX,
+        }
+    }
+
+    fn names(pins: &[Pin]) -> Vec<Option<&str>> {
+        pins.iter().map(|pin| pin.fan.as_deref()).collect()
+    }
+
+    #[test]
+    fn index_clears_a_pin_set_by_label() {
+        let pins = unpin(vec![pin(Some("left"))], &fans(), Some("1")).unwrap();
+        assert!(pins.is_empty());
+    }
+
+    #[test]
+    fn label_clears_a_pin_set_by_index() {
+        let pins = unpin(vec![pin(Some("2"))], &fans(), Some("right")).unwrap();
+        assert!(pins.is_empty());
+    }
+
+    #[test]
+    fn other_fans_keep_their_pins() {
+        let pins = unpin(vec![pin(Some("right"))], &fans(), Some("left")).unwrap();
+        assert_eq!(names(&pins), [Some("right")]);
+    }
+
+    #[test]
+    fn a_pin_on_every_fan_is_kept_for_the_others() {
+        let pins = unpin(vec![pin(None)], &fans(), Some("left")).unwrap();
+        assert_eq!(names(&pins), [Some("2")]);
+    }
+
+    #[test]
+    fn every_fan_clears_every_pin() {
+        let pins = unpin(vec![pin(Some("left")), pin(None)], &fans(), None).unwrap();
+        assert!(pins.is_empty());
+    }
+
+    #[test]
+    fn unknown_fans_are_an_error() {
+        assert!(unpin(vec![pin(None)], &fans(), Some("middle")).is_err());
+    }
+
+    #[test]
+    fn durations_take_a_unit() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+    }
+
+    #[test]
+    fn bad_durations_are_errors() {
+        for text in ["", "m", "5d", "-5m", "1.5h", "5 m"] {
+            assert_eq!(
+                parse_duration(text),
+                Err(format!("\"{}\" is not a duration like 90s, 5m or 1h", text))
+            );
+        }
+    }
+
+    #[test]
+    fn durations_too_long_to_count_are_errors() {
+        let text = format!("{}h", u64::MAX / 3600 + 1);
+        assert!(parse_duration(&text).is_err());
+        assert!(parse_duration("99999999999999999999s").is_err());
+        assert!(until(Duration::from_secs(u64::MAX)).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Upgrades config files written for older versions of t2macd. Every file
+//! carries the `version` of the schema it was written for, files without one
+//! are from before versioning and count as version 1.
+
+use crate::config::write_value;
+use serde_json::Map;
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Schema version written to new configs
+pub const CONFIG_VERSION: u32 = MIGRATIONS.len() as u32 + 1;
+
+/// Upgrades a config by one version, returning whether anything changed
+type Migration = fn(&mut Map<String, Value>) -> bool;
+
+/// The migration at `index` upgrades from version `index + 1`
+const MIGRATIONS: &[Migration] = &[versioned];
+
+/// Version 2 adds `version`. The JSON config of version 1 only has
+/// `fan_curve`, `min_temp` and `max_temp`, which version 2 reads the same
+fn versioned(_config: &mut Map<String, Value>) -> bool {
+    false
+}
+
+/// Brings a config layer up to `CONFIG_VERSION`, returning the version it was
+/// at if it had to be changed
+pub fn upgrade(layer: &mut Value) -> Result<Option<u32>, String> {
+    upgrade_with(layer, MIGRATIONS)
+}
+
+/// `upgrade` through `migrations`
+fn upgrade_with(layer: &mut Value, migrations: &[Migration]) -> Result<Option<u32>, String> {
+    let config = match layer.as_object_mut() {
+        Some(config) => config,
+        None => return Ok(None),
+    };
+    let version = match config.get("version") {
+        Some(version) => match version.as_u64() {
+            Some(version) if version >= 1 => version as u32,
+            _ => return Err(format!("version ({}) must be a number above 0", version)),
+        },
+        None => 1,
+    };
+    let latest = migrations.len() as u32 + 1;
+    if version > latest {
+        return Err(format!(
+            "version {} is newer than the {} this t2macd understands",
+            version, latest
+        ));
+    }
+    let mut changed = false;
+    for migration in migrations.iter().skip(version as usize - 1) {
+        changed |= migration(config);
+    }
+    if !changed {
+        return Ok(None);
+    }
+    config.insert(String::from("version"), Value::from(latest));
+    Ok(Some(version))
+}
+
+/// Rewrites the config file at `path` with its upgraded contents, keeping the
+/// original next to it. Returns where the original was kept
+pub fn migrate_file(path: &Path, layer: &Value, from: u32) -> Result<PathBuf, io::Error> {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{}.bak", from));
+    let backup = PathBuf::from(backup);
+    fs::copy(path, &backup)?;
+    write_value(path, layer.clone())?;
+    Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Stands in for a rename in a later version, `old` becoming `new`
+    fn rename(config: &mut Map<String, Value>) -> bool {
+        match config.remove("old") {
+            Some(value) => {
+                config.insert(String::from("new"), value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[test]
+    fn legacy_json_is_read_as_it_is() {
+        let legacy = json!({ "fan_curve": "LINEAR", "min_temp": 70, "max_temp": 95 });
+        let mut layer = legacy.clone();
+        assert_eq!(upgrade(&mut layer), Ok(None));
+        assert_eq!(layer, legacy);
+    }
+
+    #[test]
+    fn current_configs_are_left_alone() {
+        let mut layer = json!({ "version": CONFIG_VERSION, "min_temp": 70 });
+        assert_eq!(upgrade(&mut layer), Ok(None));
+        assert_eq!(layer, json!({ "version": CONFIG_VERSION, "min_temp": 70 }));
+    }
+
+    #[test]
+    fn migrations_run_from_the_version_of_the_file() {
+        let migrations: &[Migration] = &[versioned, rename];
+        let mut layer = json!({ "old": 1 });
+        assert_eq!(upgrade_with(&mut layer, migrations), Ok(Some(1)));
+        assert_eq!(layer, json!({ "version": 3, "new": 1 }));
+        let mut layer = json!({ "version": 2, "old": 1 });
+        assert_eq!(upgrade_with(&mut layer, migrations), Ok(Some(2)));
+        assert_eq!(layer, json!({ "version": 3, "new": 1 }));
+        // Already past the rename, so `old` is left as it is
+        let mut layer = json!({ "version": 3, "old": 1 });
+        assert_eq!(upgrade_with(&mut layer, migrations), Ok(None));
+    }
+
+    #[test]
+    fn bad_versions_are_errors() {
+        let newer = CONFIG_VERSION + 1;
+        assert_eq!(
+            upgrade(&mut json!({ "version": newer })),
+            Err(format!(
+                "version {} is newer than the {} this t2macd understands",
+                newer, CONFIG_VERSION
+            ))
+        );
+        assert_eq!(
+            upgrade(&mut json!({ "version": 0 })),
+            Err(String::from("version (0) must be a number above 0"))
+        );
+        assert_eq!(
+            upgrade(&mut json!({ "version": "two" })),
+            Err(String::from("version (\"two\") must be a number above 0"))
+        );
+    }
+}
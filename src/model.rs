@@ -0,0 +1,123 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The Mac model, read from DMI, and the defaults that suit it
+
+use crate::curve::Preset;
+use crate::sysfs;
+use std::fs;
+
+const PRODUCT_NAME_PATH: &str = "/sys/class/dmi/id/product_name";
+
+/// A family of Macs with the same cooling layout
+pub struct Model {
+    /// Product names of the family, like `MacBookPro16,1`
+    pub products: &'static [&'static str],
+    pub name: &'static str,
+    /// Default for `sensors.include`
+    pub include: &'static [&'static str],
+    /// Curve whose points are the default `curve_points`
+    pub preset: Preset,
+    /// Fans, by label, that cool one part and follow only its sensors
+    pub fans: &'static [(&'static str, &'static [&'static str])],
+}
+
+const MODELS: &[Model] = &[
+    Model {
+        products: &[
+            "MacBookPro15,1",
+            "MacBookPro15,3",
+            "MacBookPro16,1",
+            "MacBookPro16,4",
+        ],
+        name: "15\"/16\" MacBook Pro",
+        include: &["cpu", "gpu", "nvme", "battery"],
+        preset: Preset::BALANCED,
+        fans: &[],
+    },
+    Model {
+        products: &[
+            "MacBookPro15,2",
+            "MacBookPro15,4",
+            "MacBookPro16,2",
+            "MacBookPro16,3",
+        ],
+        name: "13\" MacBook Pro",
+        include: &["cpu", "nvme", "battery"],
+        preset: Preset::BALANCED,
+        fans: &[],
+    },
+    Model {
+        products: &["MacBookAir8,1", "MacBookAir8,2", "MacBookAir9,1"],
+        name: "MacBook Air",
+        include: &["cpu", "nvme", "battery"],
+        preset: Preset::PERFORMANCE,
+        fans: &[],
+    },
+    Model {
+        products: &["Macmini8,1"],
+        name: "Mac mini",
+        include: &["cpu", "nvme"],
+        preset: Preset::BALANCED,
+        fans: &[],
+    },
+    Model {
+        products: &["iMac20,1", "iMac20,2", "iMacPro1,1"],
+        name: "iMac",
+        include: &["cpu", "gpu", "nvme"],
+        preset: Preset::QUIET,
+        fans: &[],
+    },
+    Model {
+        products: &["MacPro7,1"],
+        name: "Mac Pro",
+        include: &["cpu", "gpu", "nvme"],
+        preset: Preset::QUIET,
+        fans: &[],
+    },
+    // The older SMC, with a fan each for the optical drive, hard drive and CPU
+    Model {
+        products: &[
+            "iMac10,1", "iMac11,1", "iMac11,2", "iMac11,3", "iMac12,1", "iMac12,2",
+        ],
+        name: "iMac (2009-2011)",
+        include: &["cpu", "gpu", "drive", "smc"],
+        preset: Preset::QUIET,
+        fans: &[
+            ("odd", &["gpu", "smc"]),
+            ("hdd", &["drive"]),
+            ("cpu", &["cpu"]),
+        ],
+    },
+];
+
+/// The DMI product name of this machine, like `MacBookPro16,1`
+pub fn product_name() -> Option<String> {
+    let name = fs::read_to_string(sysfs::path(PRODUCT_NAME_PATH)).ok()?;
+    Some(name.trim().to_string())
+}
+
+/// The built-in defaults for `product`
+pub fn find(product: &str) -> Option<&'static Model> {
+    MODELS
+        .iter()
+        .find(|model| model.products.contains(&product))
+}
+
+/// The built-in defaults for this machine, if it is a known model
+pub fn detect() -> Option<&'static Model> {
+    find(&product_name()?)
+}
@@ -0,0 +1,110 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Optional Rhai backend for fully custom fan logic. The script must define
+//! `fn fan_speeds(sensors, fans)`, where `sensors` maps sensor names to their
+//! reading in degrees Celsius and `fans` is an array of
+//! `#{ min_speed, max_speed }`. It returns an array with the target speed of
+//! every fan.
+
+use crate::curve::FanLimits;
+use crate::sensor::Reading;
+use std::path::Path;
+
+#[cfg(feature = "scripting")]
+pub struct Script {
+    engine: rhai::Engine,
+    ast: rhai::AST,
+}
+
+#[cfg(feature = "scripting")]
+impl Script {
+    pub fn load(path: &Path) -> Result<Script, String> {
+        let engine = rhai::Engine::new();
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|error| error.to_string())?;
+        Ok(Script { engine, ast })
+    }
+
+    pub fn fan_speeds(&self, sensors: &[Reading], fans: &[FanLimits]) -> Result<Vec<u32>, String> {
+        let mut sensor_map = rhai::Map::new();
+        for reading in sensors {
+            sensor_map.insert(
+                reading.name.as_str().into(),
+                rhai::Dynamic::from(reading.temp as rhai::FLOAT),
+            );
+        }
+        let fan_array: rhai::Array = fans
+            .iter()
+            .map(|fan| {
+                let mut map = rhai::Map::new();
+                map.insert("min_speed".into(), (fan.min_speed as rhai::INT).into());
+                map.insert("max_speed".into(), (fan.max_speed as rhai::INT).into());
+                rhai::Dynamic::from_map(map)
+            })
+            .collect();
+        let speeds: rhai::Array = self
+            .engine
+            .call_fn(
+                &mut rhai::Scope::new(),
+                &self.ast,
+                "fan_speeds",
+                (sensor_map, fan_array),
+            )
+            .map_err(|error| error.to_string())?;
+        if speeds.len() != fans.len() {
+            return Err(format!(
+                "fan_speeds returned {} speeds for {} fans",
+                speeds.len(),
+                fans.len()
+            ));
+        }
+        speeds
+            .iter()
+            .zip(fans)
+            .map(|(speed, fan)| {
+                let speed = match speed.as_int() {
+                    Ok(speed) => speed as f64,
+                    Err(..) => speed
+                        .as_float()
+                        .map_err(|kind| format!("fan speed must be a number, got {}", kind))?,
+                };
+                Ok((speed.round() as u32).max(fan.min_speed).min(fan.max_speed))
+            })
+            .collect()
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+pub struct Script;
+
+#[cfg(not(feature = "scripting"))]
+impl Script {
+    pub fn load(_path: &Path) -> Result<Script, String> {
+        Err(String::from(
+            "t2macd was built without the \"scripting\" feature",
+        ))
+    }
+
+    pub fn fan_speeds(
+        &self,
+        _sensors: &[Reading],
+        _fans: &[FanLimits],
+    ) -> Result<Vec<u32>, String> {
+        unreachable!()
+    }
+}
@@ -0,0 +1,1206 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::Aggregation;
+use crate::config::Config;
+use crate::expression::Expression;
+use crate::sysfs;
+use glob::glob;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+use tracing::info;
+use tracing::warn;
+
+/// Every hwmon device, by the class links that survive driver reloads
+const HWMON_GLOB: &str = "/sys/class/hwmon/hwmon*";
+
+/// Every thermal zone, the other place the kernel reports temperatures
+const THERMAL_ZONE_GLOB: &str = "/sys/class/thermal/thermal_zone*";
+
+/// Every DRM card, without the connectors listed next to them
+const DRM_GLOB: &str = "/sys/class/drm/card[0-9]*";
+
+/// Every power supply, for the battery temperature
+const POWER_SUPPLY_GLOB: &str = "/sys/class/power_supply/*";
+
+/// SMC key of the battery temperature
+const BATTERY_SMC_KEY: &str = "TB0T";
+
+/// `critical_temp` of the battery when it has none configured, as lithium
+/// cells suffer long before the CPU is anywhere near its limit
+pub const BATTERY_CRITICAL_TEMP: u32 = 45;
+
+/// What a sensor measures, worked out from its hwmon device
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum SensorKind {
+    CPU,
+    GPU,
+    NVME,
+    /// SATA and USB drives, through the `drivetemp` driver
+    DRIVE,
+    /// The System Management Controller, with the chassis sensors
+    SMC,
+    /// Read from the battery or the SMC rather than a hwmon device of its own
+    BATTERY,
+    /// A thermal zone rather than a hwmon device
+    ZONE,
+    OTHER,
+}
+
+impl SensorKind {
+    /// Works out the kind of the hwmon device at `path` called `name`. GPUs
+    /// with a driver not listed here are still found through their DRM card
+    fn of(name: &str, path: &Path) -> SensorKind {
+        match name {
+            "coretemp" | "k10temp" | "zenpower" => SensorKind::CPU,
+            "amdgpu" | "radeon" | "nouveau" | "i915" => SensorKind::GPU,
+            "nvme" => SensorKind::NVME,
+            "drivetemp" => SensorKind::DRIVE,
+            "applesmc" => SensorKind::SMC,
+            _ if path.join("device/drm").is_dir() => SensorKind::GPU,
+            _ => SensorKind::OTHER,
+        }
+    }
+
+    /// Name the kind can be picked by in `sensors.include` and
+    /// `sensors.exclude`
+    pub fn name(&self) -> &'static str {
+        match self {
+            SensorKind::CPU => "cpu",
+            SensorKind::GPU => "gpu",
+            SensorKind::NVME => "nvme",
+            SensorKind::DRIVE => "drive",
+            SensorKind::SMC => "smc",
+            SensorKind::BATTERY => "battery",
+            SensorKind::ZONE => "thermal_zone",
+            SensorKind::OTHER => "other",
+        }
+    }
+
+    /// Labels of the input that stands for the whole device called `name`,
+    /// like the CPU package rather than a single core, best first
+    fn primary_labels(&self, name: &str) -> &'static [&'static str] {
+        match self {
+            SensorKind::CPU => CpuBackend::of(name).map_or(&[], |backend| backend.package_labels()),
+            SensorKind::GPU => &["edge"],
+            SensorKind::NVME => &["Composite"],
+            SensorKind::DRIVE
+            | SensorKind::SMC
+            | SensorKind::BATTERY
+            | SensorKind::ZONE
+            | SensorKind::OTHER => &[],
+        }
+    }
+}
+
+/// Driver the CPU temperature comes from, Intel Macs have `coretemp` but the
+/// same daemon runs on AMD machines with `k10temp` or `zenpower`
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum CpuBackend {
+    CORETEMP,
+    K10TEMP,
+    ZENPOWER,
+}
+
+impl CpuBackend {
+    fn of(name: &str) -> Option<CpuBackend> {
+        match name {
+            "coretemp" => Some(CpuBackend::CORETEMP),
+            "k10temp" => Some(CpuBackend::K10TEMP),
+            "zenpower" => Some(CpuBackend::ZENPOWER),
+            _ => None,
+        }
+    }
+
+    /// Labels of the package temperature, best first. `Tctl` on AMD can have an
+    /// offset added for the firmware fan curve, so `Tdie` is preferred
+    fn package_labels(&self) -> &'static [&'static str] {
+        match self {
+            CpuBackend::CORETEMP => &["Package id 0"],
+            CpuBackend::K10TEMP | CpuBackend::ZENPOWER => &["Tdie", "Tctl"],
+        }
+    }
+
+    /// Whether the input labelled `label` is a single core or die, read
+    /// along with the package
+    fn is_core(&self, label: &str) -> bool {
+        match self {
+            CpuBackend::CORETEMP => label.starts_with("Core "),
+            CpuBackend::K10TEMP | CpuBackend::ZENPOWER => label.starts_with("Tccd"),
+        }
+    }
+}
+
+/// Labels for applesmc temperature keys found on T2 Macs, which only expose
+/// the four letter key themselves
+const SMC_LABELS: &[(&str, &str)] = &[
+    ("TA0P", "ambient"),
+    ("TA0V", "ambient_case"),
+    ("TB0T", "battery"),
+    ("TB1T", "battery_1"),
+    ("TB2T", "battery_2"),
+    ("TC0P", "cpu_proximity"),
+    ("TCXC", "cpu_peci"),
+    ("TG0P", "gpu_proximity"),
+    ("TH0a", "ssd"),
+    ("TM0P", "memory_proximity"),
+    ("TPCD", "pch"),
+    ("Ts0P", "palm_rest"),
+    ("Ts1P", "palm_rest_1"),
+    ("TI0P", "thunderbolt"),
+    ("TI1P", "thunderbolt_1"),
+    ("TTLD", "thunderbolt_left"),
+    ("TTRD", "thunderbolt_right"),
+    ("TW0P", "wifi"),
+];
+
+/// Label of the SMC key `key` from `SMC_LABELS`
+fn smc_label(key: &str) -> Option<&'static str> {
+    SMC_LABELS
+        .iter()
+        .find(|(smc_key, _)| *smc_key == key)
+        .map(|(_, label)| *label)
+}
+
+/// A temperature input of a hwmon device
+pub struct Sensor {
+    /// Name used for the sensor in the config, the hwmon device name with a
+    /// number added when there is more than one device of that name
+    pub name: String,
+    /// Contents of `temp*_label`
+    pub label: Option<String>,
+    pub kind: SensorKind,
+    /// The `temp*_input` files, more than one for a CPU with a temperature
+    /// for every core
+    pub paths: Vec<PathBuf>,
+    /// Factor turning a raw reading into millidegrees
+    pub scale: f64,
+}
+
+impl Sensor {
+    /// Name of the sensor for people, like `CPU package` or `GPU edge`
+    pub fn description(&self) -> String {
+        let kind = match self.kind {
+            SensorKind::CPU => "CPU",
+            SensorKind::GPU => "GPU",
+            SensorKind::NVME => "SSD",
+            SensorKind::DRIVE => "Drive",
+            SensorKind::BATTERY => return String::from("Battery"),
+            SensorKind::SMC => {
+                let label = self.label.as_deref().unwrap_or(&self.name);
+                return label.replace('_', " ");
+            }
+            SensorKind::ZONE | SensorKind::OTHER => return self.name.clone(),
+        };
+        let input = match self.label.as_deref() {
+            Some("Package id 0" | "Tdie" | "Tctl") => String::from("package"),
+            Some(label) => label.to_lowercase(),
+            None => self.name.clone(),
+        };
+        format!("{} {}", kind, input)
+    }
+}
+
+/// Finds the main temperature input of every hwmon device picked by
+/// `sensors.include` and `sensors.exclude`, along with every core of a CPU
+/// and the battery. Devices are matched by their `name` or kind, so this
+/// holds up when the hwmon numbering changes between boots
+pub fn discover_sensors(config: &Config) -> Vec<Sensor> {
+    let mut sensors: Vec<Sensor> = Vec::new();
+    let mut devices: Vec<PathBuf> = glob(&sysfs::path(HWMON_GLOB))
+        .unwrap()
+        .filter_map(Result::ok)
+        .collect();
+    devices.sort_by_key(|device| hwmon_index(device, "hwmon"));
+    if config.sensors.selects(&[SensorKind::BATTERY.name()]) {
+        sensors.extend(battery_sensor(&devices));
+    }
+    sensors.extend(thermal_zone_sensors(config));
+    for device in devices {
+        let device_name = match fs::read_to_string(device.join("name")) {
+            Ok(name) => name.trim().to_string(),
+            Err(..) => continue,
+        };
+        let kind = SensorKind::of(&device_name, &device);
+        // Picking single SMC keys reads those instead of the whole device
+        if kind == SensorKind::SMC && !config.sensors.smc.is_empty() {
+            let names = [device_name.as_str(), kind.name()];
+            if !config
+                .sensors
+                .exclude
+                .iter()
+                .any(|name| names.contains(&name.as_str()))
+            {
+                sensors.extend(smc_sensors(&device, config));
+            }
+            continue;
+        }
+        if !config.sensors.selects(&[&device_name, kind.name()]) {
+            continue;
+        }
+        let labelled = temp_inputs(&device);
+        // Devices without a temperature, like fan controllers, have no inputs
+        let primary = kind
+            .primary_labels(&device_name)
+            .iter()
+            .find_map(|primary| {
+                labelled
+                    .iter()
+                    .find(|(_, label)| label.as_deref() == Some(*primary))
+            })
+            .or(labelled.first());
+        let (path, label) = match primary {
+            Some(primary) => primary.clone(),
+            None => continue,
+        };
+        // Which input is the package differs between models, and a single
+        // core can run hotter than the package reports
+        let paths = match CpuBackend::of(&device_name) {
+            Some(backend) if kind == SensorKind::CPU => {
+                let cores = labelled.into_iter().filter(|(core, core_label)| {
+                    *core != path
+                        && core_label
+                            .as_deref()
+                            .is_some_and(|core| backend.is_core(core))
+                });
+                let mut paths: Vec<PathBuf> = cores.map(|(core, _)| core).collect();
+                paths.insert(0, path);
+                paths
+            }
+            _ => vec![path],
+        };
+        let count = sensors
+            .iter()
+            .filter(|sensor| sensor.name.starts_with(&device_name))
+            .count();
+        let name = match count {
+            0 => device_name,
+            count => format!("{}{}", device_name, count),
+        };
+        sensors.push(Sensor {
+            name,
+            label,
+            kind,
+            paths,
+            scale: 1.0,
+        });
+    }
+    sensors
+}
+
+/// A GPU found through its DRM card
+pub struct Gpu {
+    /// Name of the card, like `card1`
+    pub card: String,
+    /// Kernel driver bound to the card
+    pub driver: String,
+    /// Whether the GPU is part of the CPU package and shares its temperature
+    pub integrated: bool,
+}
+
+/// Finds every GPU in the machine, whatever card number it got. 13" models
+/// only have the Intel iGPU, the 15" and 16" ones add an AMD GPU
+pub fn detect_gpus() -> Vec<Gpu> {
+    let mut cards: Vec<PathBuf> = glob(&sysfs::path(DRM_GLOB))
+        .unwrap()
+        .filter_map(Result::ok)
+        .filter(|card| !card.to_string_lossy().contains('-'))
+        .collect();
+    cards.sort_by_key(|card| hwmon_index(card, "card"));
+    cards
+        .into_iter()
+        .filter_map(|card| {
+            let driver = fs::read_link(card.join("device/driver")).ok()?;
+            let driver = driver.file_name()?.to_string_lossy().to_string();
+            Some(Gpu {
+                card: card.file_name()?.to_string_lossy().to_string(),
+                integrated: driver == "i915",
+                driver,
+            })
+        })
+        .collect()
+}
+
+/// Every `temp*_input` of the hwmon device at `device` in order, with the
+/// contents of its `temp*_label`
+fn temp_inputs(device: &Path) -> Vec<(PathBuf, Option<String>)> {
+    let mut inputs: Vec<PathBuf> = glob(&format!("{}/temp*_input", device.display()))
+        .unwrap()
+        .filter_map(Result::ok)
+        .collect();
+    inputs.sort_by_key(|input| hwmon_index(input, "temp"));
+    inputs
+        .into_iter()
+        .map(|input| {
+            let label_path = input.to_string_lossy().replace("_input", "_label");
+            let label = fs::read_to_string(label_path)
+                .ok()
+                .map(|label| label.trim().to_string());
+            (input, label)
+        })
+        .collect()
+}
+
+/// Finds the inputs of the applesmc device at `device` listed in
+/// `sensors.smc`. Each is named after its SMC key
+fn smc_sensors(device: &Path, config: &Config) -> Vec<Sensor> {
+    let mut sensors = Vec::new();
+    for (path, key) in temp_inputs(device) {
+        let key = match key {
+            Some(key) => key,
+            None => continue,
+        };
+        let label = smc_label(&key).map(String::from);
+        // A label also picks its numbered siblings, so `thunderbolt` reads
+        // every Thunderbolt controller
+        let wanted = config.sensors.smc.iter().any(|name| {
+            *name == key
+                || label.as_ref().is_some_and(|label| {
+                    label == name
+                        || label
+                            .strip_prefix(name.as_str())
+                            .is_some_and(|rest| rest.starts_with('_'))
+                })
+        });
+        if wanted && !sensors.iter().any(|sensor: &Sensor| sensor.name == key) {
+            sensors.push(Sensor {
+                name: key,
+                label,
+                kind: SensorKind::SMC,
+                paths: vec![path],
+                scale: 1.0,
+            });
+        }
+    }
+    sensors
+}
+
+/// Finds the thermal zones listed in `sensors.thermal_zones`. Each is named
+/// after its type, with a number added when more than one zone has it
+fn thermal_zone_sensors(config: &Config) -> Vec<Sensor> {
+    if config.sensors.thermal_zones.is_empty() {
+        return Vec::new();
+    }
+    let mut zones: Vec<PathBuf> = glob(&sysfs::path(THERMAL_ZONE_GLOB))
+        .unwrap()
+        .filter_map(Result::ok)
+        .collect();
+    zones.sort_by_key(|zone| hwmon_index(zone, "thermal_zone"));
+    let mut sensors: Vec<Sensor> = Vec::new();
+    for zone in zones {
+        let zone_type = match fs::read_to_string(zone.join("type")) {
+            Ok(zone_type) => zone_type.trim().to_string(),
+            Err(..) => continue,
+        };
+        if !config.sensors.thermal_zones.contains(&zone_type) {
+            continue;
+        }
+        let count = sensors
+            .iter()
+            .filter(|sensor| sensor.name.starts_with(&zone_type))
+            .count();
+        let name = match count {
+            0 => zone_type,
+            count => format!("{}{}", zone_type, count),
+        };
+        sensors.push(Sensor {
+            name,
+            label: zone
+                .file_name()
+                .map(|zone| zone.to_string_lossy().to_string()),
+            kind: SensorKind::ZONE,
+            paths: vec![zone.join("temp")],
+            scale: 1.0,
+        });
+    }
+    sensors
+}
+
+/// Finds the battery temperature, from the battery itself when its driver
+/// reports one and from the SMC otherwise
+fn battery_sensor(hwmon_devices: &[PathBuf]) -> Option<Sensor> {
+    let battery = |path, label, scale| Sensor {
+        name: String::from(SensorKind::BATTERY.name()),
+        label,
+        kind: SensorKind::BATTERY,
+        paths: vec![path],
+        scale,
+    };
+    for supply in glob(&sysfs::path(POWER_SUPPLY_GLOB))
+        .unwrap()
+        .filter_map(Result::ok)
+    {
+        let is_battery = fs::read_to_string(supply.join("type"))
+            .is_ok_and(|supply_type| supply_type.trim() == "Battery");
+        let path = supply.join("temp");
+        if is_battery && path.exists() {
+            let label = fs::read_to_string(supply.join("model_name"))
+                .ok()
+                .map(|label| label.trim().to_string());
+            // power_supply reports tenths of a degree
+            return Some(battery(path, label, 100.0));
+        }
+    }
+    hwmon_devices
+        .iter()
+        .filter(|device| {
+            fs::read_to_string(device.join("name")).is_ok_and(|name| name.trim() == "applesmc")
+        })
+        .flat_map(|device| temp_inputs(device))
+        .find(|(_, key)| key.as_deref() == Some(BATTERY_SMC_KEY))
+        .map(|(path, key)| battery(path, key, 1.0))
+}
+
+/// A temperature input found on the system, whether it is read or not
+pub struct Source {
+    /// hwmon device name, thermal zone type or power supply name
+    pub driver: String,
+    pub label: Option<String>,
+    pub path: PathBuf,
+    /// Factor turning a raw reading into millidegrees
+    pub scale: f64,
+}
+
+impl Source {
+    /// Current reading in degrees Celsius, if it can be read and is
+    /// plausible
+    pub fn read(&self) -> Option<f64> {
+        let raw: f64 = fs::read_to_string(&self.path).ok()?.trim().parse().ok()?;
+        let temp = celsius_from_raw(raw, self.scale);
+        (raw != 0.0 && PLAUSIBLE_TEMPS.contains(&temp)).then_some(temp)
+    }
+}
+
+/// Every temperature input of every hwmon device, thermal zone and battery,
+/// for `t2macd sensors`
+pub fn all_sources() -> Vec<Source> {
+    let mut sources = Vec::new();
+    let mut devices: Vec<PathBuf> = glob(&sysfs::path(HWMON_GLOB))
+        .unwrap()
+        .filter_map(Result::ok)
+        .collect();
+    devices.sort_by_key(|device| hwmon_index(device, "hwmon"));
+    for device in devices {
+        let driver = fs::read_to_string(device.join("name"))
+            .map(|name| name.trim().to_string())
+            .unwrap_or_default();
+        for (path, label) in temp_inputs(&device) {
+            let label = match (driver.as_str(), label) {
+                ("applesmc", Some(key)) => match smc_label(&key) {
+                    Some(smc_label) => Some(format!("{} {}", key, smc_label)),
+                    None => Some(key),
+                },
+                (_, label) => label,
+            };
+            sources.push(Source {
+                driver: driver.clone(),
+                label,
+                path,
+                scale: 1.0,
+            });
+        }
+    }
+    let mut zones: Vec<PathBuf> = glob(&sysfs::path(THERMAL_ZONE_GLOB))
+        .unwrap()
+        .filter_map(Result::ok)
+        .collect();
+    zones.sort_by_key(|zone| hwmon_index(zone, "thermal_zone"));
+    for zone in zones {
+        sources.push(Source {
+            driver: fs::read_to_string(zone.join("type"))
+                .map(|zone_type| zone_type.trim().to_string())
+                .unwrap_or_default(),
+            label: zone
+                .file_name()
+                .map(|zone| zone.to_string_lossy().to_string()),
+            path: zone.join("temp"),
+            scale: 1.0,
+        });
+    }
+    for supply in glob(&sysfs::path(POWER_SUPPLY_GLOB))
+        .unwrap()
+        .filter_map(Result::ok)
+    {
+        let path = supply.join("temp");
+        if path.exists() {
+            sources.push(Source {
+                driver: supply
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                label: None,
+                path,
+                scale: 100.0,
+            });
+        }
+    }
+    sources
+}
+
+/// Number in a sysfs name like `hwmon3` or `temp2_input`, for sorting them
+/// numerically
+fn hwmon_index(path: &Path, prefix: &str) -> u32 {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix(prefix))
+        .map(|rest| rest.split('_').next().unwrap_or_default())
+        .and_then(|index| index.parse().ok())
+        .unwrap_or(u32::MAX)
+}
+
+/// Range of temperatures a sensor can really report, anything outside is a
+/// bad read
+const PLAUSIBLE_TEMPS: std::ops::RangeInclusive<f64> = -40.0..=150.0;
+
+/// Converts a raw reading to degrees Celsius, `scale` being the factor
+/// that turns it into millidegrees: 1 for hwmon and thermal zones, 100 for
+/// power supplies, which report tenths of a degree
+pub fn celsius_from_raw(raw: f64, scale: f64) -> f64 {
+    raw * scale / 1000.0
+}
+
+/// A temperature read from a sensor, in degrees Celsius
+#[derive(Clone)]
+pub struct Reading {
+    /// Name of the sensor in the config
+    pub name: String,
+    /// Name of the sensor for people, from `Sensor::description`
+    pub label: String,
+    /// `OTHER` for virtual sensors
+    pub kind: SensorKind,
+    pub temp: f64,
+}
+
+/// The sensors found by `discover_sensors`, with their inputs kept open so
+/// every read is a single `pread`
+pub struct SensorSet {
+    pub sensors: Vec<Sensor>,
+    /// Open inputs of each sensor, `None` where opening failed
+    files: Vec<Vec<Option<File>>>,
+    /// Last temperature read from each sensor before its offset, and when,
+    /// for sensors with a `poll_interval_ms` of their own
+    last_read: Vec<Option<(Instant, f64)>>,
+    /// Reads in a row each sensor failed
+    failures: Vec<u32>,
+    /// `sensors.virtual`, parsed once when the sensors are opened
+    virtuals: Vec<(String, Expression)>,
+}
+
+impl SensorSet {
+    pub fn open(config: &Config) -> SensorSet {
+        let sensors = discover_sensors(config);
+        let files = sensors
+            .iter()
+            .map(|sensor| {
+                sensor
+                    .paths
+                    .iter()
+                    .map(|path| File::open(path).ok())
+                    .collect()
+            })
+            .collect();
+        let last_read = vec![None; sensors.len()];
+        let failures = vec![0; sensors.len()];
+        // Already checked by `Config::validate`
+        let virtuals = config
+            .sensors
+            .virtual_sensors
+            .iter()
+            .filter_map(|(name, source)| Some((name.clone(), Expression::parse(source).ok()?)))
+            .collect();
+        SensorSet {
+            sensors,
+            files,
+            last_read,
+            failures,
+            virtuals,
+        }
+    }
+
+    /// Switches to the sensors in `found`, keeping the last reading and
+    /// failures of those still on the same inputs
+    pub fn replace(&mut self, mut found: SensorSet) {
+        for (i, sensor) in found.sensors.iter().enumerate() {
+            let old = self
+                .sensors
+                .iter()
+                .position(|old| old.name == sensor.name && old.paths == sensor.paths);
+            if let Some(old) = old {
+                found.last_read[i] = self.last_read[old];
+                found.failures[i] = self.failures[old];
+            }
+        }
+        *self = found;
+    }
+
+    /// Names of the sensors, to tell when the set changed
+    pub fn names(&self) -> Vec<&str> {
+        self.sensors
+            .iter()
+            .map(|sensor| sensor.name.as_str())
+            .collect()
+    }
+
+    /// Reads every sensor in degrees Celsius, with its offset applied.
+    /// Sensors with an impossible reading are left out. A sensor that fails
+    /// to read holds its last reading for `sensors.failed_reads` reads, and
+    /// is left out after that. Fails when no sensor can be read
+    pub fn read(&mut self, config: &Config) -> Result<Vec<Reading>, String> {
+        let mut readings = Vec::new();
+        let mut last_error = None;
+        let sensors = self
+            .sensors
+            .iter()
+            .zip(&self.files)
+            .zip(&mut self.last_read)
+            .zip(&mut self.failures);
+        for (((sensor, files), last_read), failures) in sensors {
+            let device = config.sensors.devices.get(&sensor.name);
+            let offset = device.map_or(0.0, |device| device.offset);
+            let interval = device.and_then(|device| device.poll_interval_ms);
+            if let (Some(interval), Some((at, temp))) = (interval, *last_read) {
+                if at.elapsed() < Duration::from_millis(interval) {
+                    readings.push(Reading {
+                        name: sensor.name.clone(),
+                        label: sensor.description(),
+                        kind: sensor.kind,
+                        temp: temp + offset,
+                    });
+                    continue;
+                }
+            }
+            let limit = config.sensors.failed_reads;
+            let temp = match read_temp(sensor, files, config) {
+                Ok(Some(temp)) => {
+                    if *failures > limit {
+                        info!(sensor = %sensor.name, "{} can be read again", sensor.name);
+                    }
+                    *failures = 0;
+                    *last_read = Some((Instant::now(), temp));
+                    temp
+                }
+                Ok(None) => continue,
+                Err(error) => {
+                    *failures += 1;
+                    match last_read {
+                        // A single failed read holds the last good one
+                        Some((_, temp)) if *failures <= limit => *temp,
+                        _ => {
+                            if *failures == limit + 1 {
+                                warn!(
+                                    sensor = %sensor.name,
+                                    "Leaving out {} until it can be read: {}",
+                                    sensor.name, error
+                                );
+                            }
+                            last_error = Some(error);
+                            continue;
+                        }
+                    }
+                }
+            };
+            readings.push(Reading {
+                name: sensor.name.clone(),
+                label: sensor.description(),
+                kind: sensor.kind,
+                temp: temp + offset,
+            });
+        }
+        // Over again while more can be worked out, for virtual sensors that
+        // use each other
+        let mut pending: Vec<&(String, Expression)> = self.virtuals.iter().collect();
+        loop {
+            let before = pending.len();
+            pending.retain(|(name, expression)| {
+                match virtual_reading(name, expression, &readings) {
+                    Some(reading) => {
+                        readings.push(reading);
+                        false
+                    }
+                    None => true,
+                }
+            });
+            if pending.len() == before {
+                break;
+            }
+        }
+        match last_error {
+            Some(error) if readings.is_empty() => Err(error),
+            _ if readings.is_empty() => Err(String::from(
+                "Failed to read any temperature sensor. Are you running as root?",
+            )),
+            _ => Ok(readings),
+        }
+    }
+}
+
+/// Reads every input of `sensor` in degrees Celsius and combines them by
+/// `sensors.core_aggregation`. `None` when no input has a plausible reading
+fn read_temp(
+    sensor: &Sensor,
+    files: &[Option<File>],
+    config: &Config,
+) -> Result<Option<f64>, String> {
+    let mut temps = Vec::new();
+    for (path, file) in sensor.paths.iter().zip(files) {
+        let temp = match sysfs::read_input(path, file.as_ref()) {
+            Ok(temp) => temp,
+            Err(error) => return Err(format!("Failed to read {}: {}", sensor.name, error)),
+        };
+        match temp.trim().parse::<f64>() {
+            // Sensors that are not ready yet report exactly zero
+            Ok(0.0) => {}
+            Ok(temp) => {
+                let temp = celsius_from_raw(temp, sensor.scale);
+                if PLAUSIBLE_TEMPS.contains(&temp) {
+                    temps.push(temp);
+                }
+            }
+            Err(..) => return Err(format!("{} returned \"{}\"", sensor.name, temp.trim())),
+        }
+    }
+    if temps.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(match config.sensors.core_aggregation {
+        Aggregation::AVG => temps.iter().sum::<f64>() / temps.len() as f64,
+        Aggregation::MAX | Aggregation::WEIGHTED => temps.into_iter().fold(f64::NAN, f64::max),
+    }))
+}
+
+/// Finds the sensors and reads them once, for commands that do not keep
+/// them open
+pub fn read_sensors(config: &Config) -> Result<Vec<Reading>, String> {
+    SensorSet::open(config).read(config)
+}
+
+/// Works out the virtual sensor `name` from `readings`. It is left out while
+/// a sensor it uses has no reading
+fn virtual_reading(name: &str, expression: &Expression, readings: &[Reading]) -> Option<Reading> {
+    let lookup = |variable: &str| {
+        readings
+            .iter()
+            .find(|reading| reading.name == variable)
+            .map(|reading| reading.temp)
+    };
+    if expression
+        .variables()
+        .iter()
+        .any(|variable| lookup(variable).is_none())
+    {
+        return None;
+    }
+    Some(Reading {
+        name: name.to_string(),
+        label: name.to_string(),
+        kind: SensorKind::OTHER,
+        temp: expression.eval(&lookup),
+    })
+}
+
+/// Sliding median of every sensor over `sensors.median_window` readings, so
+/// a single bogus sample does not reach the fans
+#[derive(Default)]
+pub struct MedianFilter {
+    windows: HashMap<String, VecDeque<f64>>,
+}
+
+impl MedianFilter {
+    /// Adds `readings` to their windows, returning the median of each
+    pub fn filter(&mut self, readings: &[Reading], config: &Config) -> Vec<Reading> {
+        let size = config.sensors.median_window.max(1);
+        self.windows
+            .retain(|name, _| readings.iter().any(|reading| reading.name == *name));
+        readings
+            .iter()
+            .map(|reading| {
+                let window = self.windows.entry(reading.name.clone()).or_default();
+                window.push_back(reading.temp);
+                while window.len() > size {
+                    window.pop_front();
+                }
+                let mut sorted: Vec<f64> = window.iter().copied().collect();
+                sorted.sort_by(f64::total_cmp);
+                let middle = sorted.len() / 2;
+                let median = match sorted.len() % 2 {
+                    0 => (sorted[middle - 1] + sorted[middle]) / 2.0,
+                    _ => sorted[middle],
+                };
+                Reading {
+                    temp: median,
+                    ..reading.clone()
+                }
+            })
+            .collect()
+    }
+}
+
+/// Exponential moving average of every sensor, to keep short spikes from
+/// surging the fans
+#[derive(Default)]
+pub struct Smoother {
+    averages: HashMap<String, f64>,
+}
+
+impl Smoother {
+    /// Smooths `readings` with `sensors.smoothing`, returning the averages.
+    /// Sensors seen for the first time start at their reading
+    pub fn smooth(&mut self, readings: &[Reading], config: &Config) -> Vec<Reading> {
+        let weight = config.sensors.smoothing;
+        self.averages
+            .retain(|name, _| readings.iter().any(|reading| reading.name == *name));
+        readings
+            .iter()
+            .map(|reading| {
+                let average = self
+                    .averages
+                    .entry(reading.name.clone())
+                    .or_insert(reading.temp);
+                *average = *average * weight + reading.temp * (1.0 - weight);
+                Reading {
+                    temp: *average,
+                    ..reading.clone()
+                }
+            })
+            .collect()
+    }
+}
+
+/// Reduces the sensor readings to the temperature fed into the curve, either
+/// `sensors.input` or the readings combined by `sensors.aggregation`.
+/// Weighted sensors fall back to the hottest sensor when no sensor has a
+/// weight
+pub fn combined_temp(readings: &[Reading], config: &Config) -> f64 {
+    let input = config
+        .sensors
+        .input
+        .as_ref()
+        .and_then(|input| readings.iter().find(|reading| reading.name == *input));
+    if let Some(input) = input {
+        return input.temp;
+    }
+    aggregate(readings, config)
+}
+
+/// The temperature of the fan whose `[[fans]]` entry lists `sensors`, by
+/// name or kind, combined by `sensors.aggregation`. `None` when no reading
+/// is listed
+pub fn group_temp(readings: &[Reading], sensors: &[String], config: &Config) -> Option<f64> {
+    let listed = |reading: &&Reading| {
+        sensors
+            .iter()
+            .any(|sensor| *sensor == reading.name || sensor == reading.kind.name())
+    };
+    let group: Vec<Reading> = readings.iter().filter(listed).cloned().collect();
+    (!group.is_empty()).then(|| aggregate(&group, config))
+}
+
+/// `readings` combined by `sensors.aggregation`
+fn aggregate(readings: &[Reading], config: &Config) -> f64 {
+    let hottest = readings
+        .iter()
+        .map(|reading| reading.temp)
+        .fold(f64::NAN, f64::max);
+    match config.sensors.aggregation {
+        Aggregation::MAX => hottest,
+        Aggregation::AVG if !readings.is_empty() => {
+            let sum: f64 = readings.iter().map(|reading| reading.temp).sum();
+            sum / readings.len() as f64
+        }
+        Aggregation::AVG => hottest,
+        Aggregation::WEIGHTED => {
+            let weighted: Vec<(f64, f64)> = readings
+                .iter()
+                .filter_map(|reading| {
+                    let weight = config.sensors.devices.get(&reading.name)?.weight?;
+                    Some((reading.temp, weight))
+                })
+                .collect();
+            let total_weight: f64 = weighted.iter().map(|(_, weight)| weight).sum();
+            if total_weight <= 0.0 {
+                return hottest;
+            }
+            let sum: f64 = weighted.iter().map(|(temp, weight)| temp * weight).sum();
+            sum / total_weight
+        }
+    }
+}
+
+/// Formats `readings` in the configured unit, like `CPU package: 92°C, GPU
+/// edge: 78°C`
+pub fn describe_readings(readings: &[Reading], config: &Config) -> String {
+    let unit = config.temperature_unit;
+    readings
+        .iter()
+        .map(|reading| {
+            format!(
+                "{}: {:.0}{}",
+                reading.label,
+                unit.celsius_to(reading.temp),
+                unit.symbol()
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+pub fn get_current_temp(config: &Config) -> Result<f64, io::Error> {
+    let sensors = read_sensors(config).map_err(io::Error::other)?;
+    Ok(combined_temp(&sensors, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SensorConfig;
+
+    /// A source with `scale` reading `raw` from a file of its own under the
+    /// temporary directory
+    fn source(test: &str, raw: &str, scale: f64) -> Source {
+        let dir = std::env::temp_dir().join(format!("t2macd-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("temp");
+        fs::write(&path, raw).unwrap();
+        Source {
+            driver: String::from(test),
+            label: None,
+            path,
+            scale,
+        }
+    }
+
+    /// `Source::read` of a source reading `raw`, removing its file after
+    fn read(test: &str, raw: &str, scale: f64) -> Option<f64> {
+        let source = source(test, raw, scale);
+        let temp = source.read();
+        fs::remove_dir_all(source.path.parent().unwrap()).unwrap();
+        temp
+    }
+
+    #[test]
+    fn hwmon_reports_millidegrees() {
+        assert_eq!(celsius_from_raw(45000.0, 1.0), 45.0);
+        assert_eq!(celsius_from_raw(500.0, 1.0), 0.5);
+        assert_eq!(celsius_from_raw(300.0, 1.0), 0.3);
+        assert_eq!(celsius_from_raw(-300.0, 1.0), -0.3);
+        assert_eq!(celsius_from_raw(-10500.0, 1.0), -10.5);
+    }
+
+    #[test]
+    fn power_supplies_report_tenths_of_a_degree() {
+        assert_eq!(celsius_from_raw(305.0, 100.0), 30.5);
+        assert_eq!(celsius_from_raw(3.0, 100.0), 0.3);
+        assert_eq!(celsius_from_raw(-3.0, 100.0), -0.3);
+        assert_eq!(celsius_from_raw(-105.0, 100.0), -10.5);
+    }
+
+    #[test]
+    fn readings_near_zero_are_kept() {
+        assert_eq!(read("near-zero-hwmon", "300\n", 1.0), Some(0.3));
+        assert_eq!(read("near-zero-hwmon-negative", "-300\n", 1.0), Some(-0.3));
+        assert_eq!(read("near-zero-battery", "3\n", 100.0), Some(0.3));
+    }
+
+    #[test]
+    fn implausible_and_unready_readings_are_dropped() {
+        assert_eq!(read("unready", "0\n", 1.0), None);
+        assert_eq!(read("too-hot", "200000\n", 1.0), None);
+        assert_eq!(read("too-cold", "-500\n", 100.0), None);
+    }
+
+    #[test]
+    fn virtual_sensors_can_use_each_other_in_any_order() {
+        let source = source("virtual-order", "50000\n", 1.0);
+        let mut sensors = SensorSet {
+            sensors: vec![Sensor {
+                name: String::from("coretemp"),
+                label: None,
+                kind: SensorKind::CPU,
+                paths: vec![source.path.clone()],
+                scale: 1.0,
+            }],
+            files: vec![vec![None]],
+            last_read: vec![None],
+            failures: vec![0],
+            virtuals: vec![
+                (String::from("a"), Expression::parse("b + 1").unwrap()),
+                (
+                    String::from("b"),
+                    Expression::parse("coretemp * 2").unwrap(),
+                ),
+                (String::from("c"), Expression::parse("missing").unwrap()),
+            ],
+        };
+        let readings = sensors.read(&Config::default()).unwrap();
+        fs::remove_dir_all(source.path.parent().unwrap()).unwrap();
+        let temps: Vec<(&str, f64)> = readings
+            .iter()
+            .map(|reading| (reading.name.as_str(), reading.temp))
+            .collect();
+        assert_eq!(temps, [("coretemp", 50.0), ("b", 100.0), ("a", 101.0)]);
+    }
+
+    fn reading(name: &str, temp: f64) -> Reading {
+        Reading {
+            name: name.to_string(),
+            label: name.to_string(),
+            kind: SensorKind::OTHER,
+            temp,
+        }
+    }
+
+    fn temps(readings: &[Reading]) -> Vec<f64> {
+        readings.iter().map(|reading| reading.temp).collect()
+    }
+
+    #[test]
+    fn smoothing_eases_into_a_step() {
+        let mut config = Config::default();
+        config.sensors.smoothing = 0.5;
+        let mut smoother = Smoother::default();
+        let smoothed: Vec<f64> = [40.0, 60.0, 60.0, 60.0]
+            .into_iter()
+            .flat_map(|temp| temps(&smoother.smooth(&[reading("cpu", temp)], &config)))
+            .collect();
+        assert_eq!(smoothed, [40.0, 50.0, 55.0, 57.5]);
+    }
+
+    #[test]
+    fn no_smoothing_passes_readings_through() {
+        let config = Config::default();
+        let mut smoother = Smoother::default();
+        smoother.smooth(&[reading("cpu", 40.0)], &config);
+        let smoothed = smoother.smooth(&[reading("cpu", 60.0)], &config);
+        assert_eq!(temps(&smoothed), [60.0]);
+    }
+
+    #[test]
+    fn smoothing_starts_over_for_a_sensor_that_comes_back() {
+        let mut config = Config::default();
+        config.sensors.smoothing = 0.5;
+        let mut smoother = Smoother::default();
+        smoother.smooth(&[reading("cpu", 40.0), reading("gpu", 40.0)], &config);
+        // The gpu is gone for a read, the cpu keeps its average
+        let smoothed = smoother.smooth(&[reading("cpu", 60.0)], &config);
+        assert_eq!(temps(&smoothed), [50.0]);
+        let smoothed = smoother.smooth(&[reading("cpu", 60.0), reading("gpu", 80.0)], &config);
+        assert_eq!(temps(&smoothed), [55.0, 80.0]);
+    }
+
+    /// The medians `MedianFilter` gives for `temps` of one sensor, through a
+    /// window of `size`
+    fn medians(size: usize, temps: &[f64]) -> Vec<f64> {
+        let mut config = Config::default();
+        config.sensors.median_window = size;
+        let mut filter = MedianFilter::default();
+        temps
+            .iter()
+            .map(|temp| filter.filter(&[reading("cpu", *temp)], &config)[0].temp)
+            .collect()
+    }
+
+    #[test]
+    fn an_odd_median_window_rejects_a_spike() {
+        assert_eq!(
+            medians(3, &[40.0, 41.0, 90.0, 42.0, 43.0]),
+            [40.0, 40.5, 41.0, 42.0, 43.0]
+        );
+    }
+
+    #[test]
+    fn an_even_median_window_rejects_a_spike() {
+        assert_eq!(
+            medians(4, &[40.0, 41.0, 42.0, 90.0, 43.0, 44.0]),
+            [40.0, 40.5, 41.0, 41.5, 42.5, 43.5]
+        );
+    }
+
+    #[test]
+    fn a_median_window_of_one_passes_readings_through() {
+        assert_eq!(medians(1, &[40.0, 90.0, 41.0]), [40.0, 90.0, 41.0]);
+    }
+
+    /// `config` with `sensors.aggregation` set to `aggregation` and the
+    /// sensors in `weights` weighted
+    fn aggregated(aggregation: Aggregation, weights: &[(&str, f64)]) -> Config {
+        let mut config = Config::default();
+        config.sensors.aggregation = aggregation;
+        for (name, weight) in weights {
+            config.sensors.devices.insert(
+                name.to_string(),
+                SensorConfig {
+                    weight: Some(*weight),
+                    ..SensorConfig::default()
+                },
+            );
+        }
+        config
+    }
+
+    fn readings() -> Vec<Reading> {
+        vec![
+            reading("cpu", 80.0),
+            reading("gpu", 60.0),
+            reading("nvme", 40.0),
+        ]
+    }
+
+    #[test]
+    fn max_takes_the_hottest_sensor() {
+        let config = aggregated(Aggregation::MAX, &[]);
+        assert_eq!(combined_temp(&readings(), &config), 80.0);
+    }
+
+    #[test]
+    fn avg_takes_the_mean() {
+        let config = aggregated(Aggregation::AVG, &[]);
+        assert_eq!(combined_temp(&readings(), &config), 60.0);
+    }
+
+    #[test]
+    fn weighted_only_counts_sensors_with_a_weight() {
+        let config = aggregated(Aggregation::WEIGHTED, &[("cpu", 1.0), ("nvme", 3.0)]);
+        assert_eq!(combined_temp(&readings(), &config), 50.0);
+        let config = aggregated(Aggregation::WEIGHTED, &[("cpu", 0.0), ("gpu", 1.0)]);
+        assert_eq!(combined_temp(&readings(), &config), 60.0);
+    }
+
+    #[test]
+    fn weighted_without_weights_takes_the_hottest_sensor() {
+        let config = aggregated(Aggregation::WEIGHTED, &[]);
+        assert_eq!(combined_temp(&readings(), &config), 80.0);
+        let config = aggregated(Aggregation::WEIGHTED, &[("cpu", 0.0), ("gpu", 0.0)]);
+        assert_eq!(combined_temp(&readings(), &config), 80.0);
+    }
+
+    #[test]
+    fn the_input_sensor_wins_over_aggregation() {
+        let mut config = aggregated(Aggregation::MAX, &[]);
+        config.sensors.input = Some(String::from("nvme"));
+        assert_eq!(combined_temp(&readings(), &config), 40.0);
+        config.sensors.input = Some(String::from("missing"));
+        assert_eq!(combined_temp(&readings(), &config), 80.0);
+    }
+}
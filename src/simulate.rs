@@ -0,0 +1,261 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Fake hardware for `--simulate`, so the control logic can be run without a
+//! T2 Mac. A scenario file lists the sensors and fans to create. Sensors
+//! either replay a list of temperatures or follow a simple thermal model that
+//! cools with the fan speed. Everything is written to a generated sysfs tree
+//! in a temporary directory.
+
+use crate::sysfs;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How often the simulated temperatures are updated
+const STEP: Duration = Duration::from_millis(200);
+
+/// Where the fans are created, the platform device of the simulated
+/// applesmc hwmon device
+const FAN_DIR: &str = "sys/devices/platform/simulated/0/0/APP0001:00";
+
+/// Where the fans are created with `classic_smc`, as on Macs without a T2
+const CLASSIC_FAN_DIR: &str = "sys/devices/platform/applesmc.768";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Scenario {
+    /// DMI product name the model is detected from, like `MacBookPro16,1`
+    #[serde(default)]
+    product: Option<String>,
+    #[serde(default)]
+    sensor: Vec<SimulatedSensor>,
+    #[serde(default)]
+    fan: Vec<SimulatedFan>,
+    /// Create the fans like the SMC of a Mac without a T2 chip
+    #[serde(default)]
+    classic_smc: bool,
+}
+
+impl Scenario {
+    fn fan_dir(&self) -> &'static str {
+        if self.classic_smc {
+            CLASSIC_FAN_DIR
+        } else {
+            FAN_DIR
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SimulatedSensor {
+    /// hwmon device name, like `coretemp`
+    name: String,
+    #[serde(default)]
+    label: Option<String>,
+    /// Temperatures to replay as `[seconds, degrees]`, interpolated in
+    /// between and held after the last one
+    #[serde(default)]
+    replay: Vec<(f64, f64)>,
+    #[serde(default)]
+    model: Option<ThermalModel>,
+}
+
+/// A heat source cooled by the fans. The temperature moves towards
+/// `ambient + load * (1 - cooling * fan speed)` with `time_constant`
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct ThermalModel {
+    ambient: f64,
+    /// Degrees above ambient with the fans stopped, as `[seconds, degrees]`
+    load: Vec<(f64, f64)>,
+    /// Share of the load removed with every fan at full speed
+    cooling: f64,
+    /// Seconds to get most of the way to a new temperature
+    time_constant: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SimulatedFan {
+    min_speed: u32,
+    max_speed: u32,
+    #[serde(default)]
+    label: Option<String>,
+    /// Speed reported whatever the fan is told, like 0 for a dead fan
+    #[serde(default)]
+    stuck_at: Option<u32>,
+}
+
+/// Value of `points` at `time`, interpolated linearly and held past either end
+fn interpolate(points: &[(f64, f64)], time: f64) -> f64 {
+    let after = points.iter().position(|(at, _)| *at > time);
+    match after {
+        Some(0) => points[0].1,
+        Some(i) => {
+            let ((t0, v0), (t1, v1)) = (points[i - 1], points[i]);
+            v0 + (v1 - v0) * (time - t0) / (t1 - t0)
+        }
+        None => points.last().map_or(0.0, |(_, value)| *value),
+    }
+}
+
+/// Average speed of the simulated fans as a share of their range
+fn fan_speed(root: &Path, scenario: &Scenario) -> f64 {
+    let fans = &scenario.fan;
+    if fans.is_empty() {
+        return 0.0;
+    }
+    let total: f64 = fans
+        .iter()
+        .enumerate()
+        .map(|(i, fan)| {
+            let output = root
+                .join(scenario.fan_dir())
+                .join(format!("fan{}_output", i + 1));
+            let speed: f64 = fs::read_to_string(output)
+                .ok()
+                .and_then(|speed| speed.lines().next()?.trim().parse().ok())
+                .unwrap_or(0.0);
+            let range = fan.max_speed.saturating_sub(fan.min_speed).max(1) as f64;
+            ((speed - fan.min_speed as f64) / range).clamp(0.0, 1.0)
+        })
+        .sum();
+    total / fans.len() as f64
+}
+
+/// Builds the sysfs tree for the scenario at `path` in a temporary directory,
+/// points `sysfs` at it and keeps it updated from a background thread.
+/// Returns the directory
+pub fn start(path: &Path) -> Result<PathBuf, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|error| format!("Failed to read {}: {}", path.display(), error))?;
+    let scenario: Scenario = toml::from_str(&text)
+        .map_err(|error| format!("Invalid scenario {}: {}", path.display(), error))?;
+    for sensor in &scenario.sensor {
+        match &sensor.model {
+            Some(model) if model.load.is_empty() => {
+                return Err(format!("Sensor {} has a model without load", sensor.name))
+            }
+            None if sensor.replay.is_empty() => {
+                return Err(format!("Sensor {} needs replay or model", sensor.name))
+            }
+            _ => {}
+        }
+    }
+    let root = std::env::temp_dir().join(format!("t2macd-simulate-{}", std::process::id()));
+    create_tree(&root, &scenario)
+        .map_err(|error| format!("Failed to create {}: {}", root.display(), error))?;
+    sysfs::set_root(root.clone());
+    let thread_root = root.clone();
+    std::thread::spawn(move || simulate(&thread_root, &scenario));
+    Ok(root)
+}
+
+fn create_tree(root: &Path, scenario: &Scenario) -> std::io::Result<()> {
+    if let Some(product) = &scenario.product {
+        let dmi = root.join("sys/class/dmi/id");
+        fs::create_dir_all(&dmi)?;
+        fs::write(dmi.join("product_name"), format!("{}\n", product))?;
+    }
+    for (i, sensor) in scenario.sensor.iter().enumerate() {
+        let device = root.join(format!("sys/class/hwmon/hwmon{}", i));
+        fs::create_dir_all(&device)?;
+        fs::write(device.join("name"), format!("{}\n", sensor.name))?;
+        if let Some(label) = &sensor.label {
+            fs::write(device.join("temp1_label"), format!("{}\n", label))?;
+        }
+        let start = match &sensor.model {
+            Some(model) => model.ambient,
+            None => interpolate(&sensor.replay, 0.0),
+        };
+        write_temp(&device, start)?;
+    }
+    let fans = root.join(scenario.fan_dir());
+    fs::create_dir_all(&fans)?;
+    let smc = root.join(format!("sys/class/hwmon/hwmon{}", scenario.sensor.len()));
+    fs::create_dir_all(&smc)?;
+    fs::write(smc.join("name"), "applesmc\n")?;
+    std::os::unix::fs::symlink(&fans, smc.join("device"))?;
+    for (i, fan) in scenario.fan.iter().enumerate() {
+        let fan_file = |suffix: &str| fans.join(format!("fan{}_{}", i + 1, suffix));
+        fs::write(fan_file("min"), format!("{}\n", fan.min_speed))?;
+        fs::write(fan_file("max"), format!("{}\n", fan.max_speed))?;
+        fs::write(fan_file("input"), format!("{}\n", fan.min_speed))?;
+        fs::write(fan_file("output"), format!("{}\n", fan.min_speed))?;
+        fs::write(fan_file("manual"), "0\n")?;
+        if let Some(label) = &fan.label {
+            fs::write(fan_file("label"), format!("{}\n", label))?;
+        }
+    }
+    Ok(())
+}
+
+fn write_temp(device: &Path, temp: f64) -> std::io::Result<()> {
+    // In millidegrees, like real hwmon devices
+    fs::write(
+        device.join("temp1_input"),
+        format!("{}\n", (temp * 1000.0).round() as i64),
+    )
+}
+
+/// Updates the temperatures and fan speeds of the tree at `root` forever
+fn simulate(root: &Path, scenario: &Scenario) {
+    let started = Instant::now();
+    let mut temps: Vec<f64> = scenario
+        .sensor
+        .iter()
+        .map(|sensor| match &sensor.model {
+            Some(model) => model.ambient,
+            None => interpolate(&sensor.replay, 0.0),
+        })
+        .collect();
+    loop {
+        std::thread::sleep(STEP);
+        let time = started.elapsed().as_secs_f64();
+        let fan_speed = fan_speed(root, scenario);
+        for (i, sensor) in scenario.sensor.iter().enumerate() {
+            temps[i] = match &sensor.model {
+                Some(model) => {
+                    let load = interpolate(&model.load, time);
+                    let target = model.ambient + load * (1.0 - model.cooling * fan_speed);
+                    let step = (STEP.as_secs_f64() / model.time_constant.max(0.001)).min(1.0);
+                    temps[i] + (target - temps[i]) * step
+                }
+                None => interpolate(&sensor.replay, time),
+            };
+            let device = root.join(format!("sys/class/hwmon/hwmon{}", i));
+            let _ = write_temp(&device, temps[i]);
+        }
+        // The fans spin at whatever they were last told
+        for (i, fan) in scenario.fan.iter().enumerate() {
+            let fan_file = |suffix: &str| {
+                root.join(scenario.fan_dir())
+                    .join(format!("fan{}_{}", i + 1, suffix))
+            };
+            if let Some(speed) = fan.stuck_at {
+                let _ = fs::write(fan_file("input"), format!("{}\n", speed));
+            } else if let Ok(output) = fs::read_to_string(fan_file("output")) {
+                let speed = output.lines().next().unwrap_or_default();
+                let _ = fs::write(fan_file("input"), format!("{}\n", speed.trim()));
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A thread watching the control loop, so a tick stuck on a sysfs read or a
+//! lock does not leave the fans at their last speed. The daemon aborts once
+//! it hands them back, for systemd to restart it
+
+use crate::fan;
+use std::process;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use tracing::error;
+
+/// How often the thread checks whether the loop is late
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long handing the fans back may take, as the fans can be stuck the
+/// same way the loop is
+const HAND_BACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Written by the loop on every tick, read by the watching thread
+pub struct Heartbeat {
+    started: Instant,
+    /// Milliseconds after `started` the next beat is due by, `u64::MAX` for
+    /// never
+    deadline: Arc<AtomicU64>,
+}
+
+impl Heartbeat {
+    /// Starts the watching thread, which waits for the first beat
+    pub fn start() -> Heartbeat {
+        let started = Instant::now();
+        let deadline = Arc::new(AtomicU64::new(u64::MAX));
+        let watched = deadline.clone();
+        thread::spawn(move || loop {
+            thread::sleep(CHECK_INTERVAL);
+            let due = watched.load(Ordering::Relaxed);
+            if due != u64::MAX && started.elapsed().as_millis() > due as u128 {
+                stalled();
+            }
+        });
+        Heartbeat { started, deadline }
+    }
+
+    /// Expects the next beat by `deadline`, or not at all with `None`
+    pub fn beat(&self, deadline: Option<Instant>) {
+        let due = deadline.map_or(u64::MAX, |deadline| {
+            deadline.saturating_duration_since(self.started).as_millis() as u64
+        });
+        self.deadline.store(due, Ordering::Relaxed);
+    }
+}
+
+fn stalled() -> ! {
+    error!("An update took longer than stall_timeout, handing the fans back and aborting");
+    let (done, handed_back) = mpsc::channel();
+    thread::spawn(move || {
+        fan::hand_back();
+        let _ = done.send(());
+    });
+    let _ = handed_back.recv_timeout(HAND_BACK_TIMEOUT);
+    process::abort();
+}
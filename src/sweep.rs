@@ -0,0 +1,115 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! `t2macd test-fans` steps every fan through its range and checks that the
+//! speed it reports follows, to find worn or dead fans.
+
+use crate::fan::Fan;
+use crate::fan::RESPONSE_TIME;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use tracing::error;
+
+/// Fan speeds that are tested, as a percentage of the hardware range
+const STEPS: [u32; 5] = [0, 25, 50, 75, 100];
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Waits up to `RESPONSE_TIME` for `fan` to reach `target`, returning the
+/// last speed read and whether it got there. `None` when `stop` was set
+fn settle(fan: &Fan, target: u32, stop: &AtomicBool) -> Option<Result<(u32, bool), String>> {
+    let start = Instant::now();
+    loop {
+        thread::sleep(SAMPLE_INTERVAL);
+        if stop.load(Ordering::Relaxed) {
+            return None;
+        }
+        let speed = match fan.read_speed() {
+            Ok(speed) => speed,
+            Err(error) => return Some(Err(error.to_string())),
+        };
+        let reached = fan.tracks(target, speed);
+        if reached || start.elapsed() >= RESPONSE_TIME {
+            return Some(Ok((speed, reached)));
+        }
+    }
+}
+
+/// Runs each fan through `STEPS` in turn, printing the speed it reports at
+/// each, and hands them all back to the firmware. Returns whether every fan
+/// followed, stopping early when `stop` is set
+pub fn test_fans(fans: &[Fan], stop: &AtomicBool) -> bool {
+    println!(
+        "Testing {} fans, this takes up to {} seconds each",
+        fans.len(),
+        STEPS.len() as u64 * RESPONSE_TIME.as_secs()
+    );
+    let mut passed = true;
+    'fans: for fan in fans {
+        let limits = fan.hardware_limits();
+        println!(
+            "{}: {}-{} RPM",
+            fan.name(),
+            limits.min_speed,
+            limits.max_speed
+        );
+        let mut failed_steps = 0;
+        for percent in STEPS {
+            let target = limits.speed_at(percent as f64 / 100.0);
+            if let Err(error) = fan.set_speed(target) {
+                println!("  failed to set {} RPM: {}", target, error);
+                failed_steps += 1;
+                continue;
+            }
+            match settle(fan, target, stop) {
+                None => break 'fans,
+                Some(Ok((speed, true))) => {
+                    println!("  {:>3}%: {:>5} RPM for {:>5}", percent, speed, target)
+                }
+                Some(Ok((speed, false))) => {
+                    println!(
+                        "  {:>3}%: {:>5} RPM for {:>5}, not following",
+                        percent, speed, target
+                    );
+                    failed_steps += 1;
+                }
+                Some(Err(error)) => {
+                    println!("  {:>3}%: failed to read the speed: {}", percent, error);
+                    failed_steps += 1;
+                }
+            }
+        }
+        match failed_steps {
+            0 => println!("  OK"),
+            count => {
+                println!("  FAILED at {} of {} steps", count, STEPS.len());
+                passed = false;
+            }
+        }
+    }
+    for fan in fans {
+        if let Err(error) = fan.release_control() {
+            error!("Failed to hand {} back: {}", fan.name(), error);
+        }
+    }
+    if stop.load(Ordering::Relaxed) {
+        println!("Stopped, handed the fans back");
+        return false;
+    }
+    passed
+}
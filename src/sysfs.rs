@@ -0,0 +1,55 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Where sysfs is read from. This is `/` except when `--simulate` points it
+//! at a generated tree, which then also holds `/run/t2macd` so a simulation
+//! never meets a running daemon
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Reads sysfs under `root` from now on. Only the first call has an effect
+pub fn set_root(root: PathBuf) {
+    let _ = ROOT.set(root);
+}
+
+/// `path`, an absolute sysfs path or glob, under the current root
+pub fn path(path: &str) -> String {
+    match ROOT.get() {
+        Some(root) => format!("{}{}", root.display(), path),
+        None => path.to_string(),
+    }
+}
+
+/// Reads a sysfs attribute through `file` from the start, falling back to
+/// opening `path` when there is no open file
+pub fn read_input(path: &Path, file: Option<&File>) -> io::Result<String> {
+    let file = match file {
+        Some(file) => file,
+        None => return fs::read_to_string(path),
+    };
+    // sysfs attributes are a single short line, read in one go
+    let mut buffer = [0; 64];
+    let length = file.read_at(&mut buffer, 0)?;
+    Ok(String::from_utf8_lossy(&buffer[..length]).into_owned())
+}
@@ -0,0 +1,84 @@
+// Copyright (C) 2023 t2macd contributors
+// SPDX-License-Identifier: GPL-3.0-or-later
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use inotify::Inotify;
+use inotify::WatchMask;
+use std::io;
+use std::path::Path;
+use tracing::error;
+
+/// Extensions of the files that are read as config layers
+const CONFIG_EXTENSIONS: [&str; 4] = ["toml", "json", "yaml", "yml"];
+
+/// Watches the directories holding the config for files being written, moved
+/// or removed
+pub struct ConfigWatcher {
+    inotify: Inotify,
+    buffer: Vec<u8>,
+}
+
+impl ConfigWatcher {
+    /// Starts watching `dirs`, skipping any that do not exist
+    pub fn new<'a>(dirs: impl IntoIterator<Item = &'a Path>) -> Result<ConfigWatcher, io::Error> {
+        let inotify = Inotify::init()?;
+        for dir in dirs {
+            if dir.is_dir() {
+                inotify.watches().add(
+                    dir,
+                    WatchMask::CLOSE_WRITE
+                        | WatchMask::MOVED_TO
+                        | WatchMask::MOVED_FROM
+                        | WatchMask::DELETE,
+                )?;
+            }
+        }
+        Ok(ConfigWatcher {
+            inotify,
+            buffer: vec![0; 4096],
+        })
+    }
+
+    /// Returns whether a config file changed since the last call, without
+    /// blocking
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        loop {
+            let events = match self.inotify.read_events(&mut self.buffer) {
+                Ok(events) => events,
+                Err(error) => {
+                    if error.kind() != io::ErrorKind::WouldBlock {
+                        error!("Failed to watch config: {}", error);
+                    }
+                    return changed;
+                }
+            };
+            let mut any = false;
+            for event in events {
+                any = true;
+                let extension = event
+                    .name
+                    .and_then(|name| Path::new(name).extension())
+                    .and_then(|extension| extension.to_str());
+                if extension.is_some_and(|extension| CONFIG_EXTENSIONS.contains(&extension)) {
+                    changed = true;
+                }
+            }
+            if !any {
+                return changed;
+            }
+        }
+    }
+}